@@ -0,0 +1,349 @@
+use std::time::Duration;
+
+use winit::{
+    event::*,
+    event_loop::{ControlFlow, EventLoop},
+    window::{Window, WindowBuilder},
+};
+
+use crate::gamepad::{self, Deadzones, GamepadBackend, GamepadEvent, Gamepads};
+use crate::gpu::{GpuContext, GpuSettings};
+use crate::replay::{RecordMode, RecordedEvent, Recorder, Recording};
+use crate::timing::{Clock, FrameTime, LoopMode};
+
+// the format frames are drawn in by `replay_headless`
+pub const HEADLESS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+// what `run_app` sets up before calling `App::init`
+#[derive(Clone, Debug)]
+pub struct AppConfig {
+    pub title: String,
+    pub gpu: GpuSettings,
+    pub loop_mode: LoopMode,
+    pub gamepad_deadzones: Deadzones,
+    pub record_mode: RecordMode,
+    // what `AppContext::seed` returns, random if None. a replay has the
+    // recorded seed instead
+    pub seed: Option<u64>,
+    // the same input draws the same frames: every frame gets the same delta
+    // instead of the wall clock time, the seed defaults to 0 and the surface
+    // format doesn't depend on the display
+    pub deterministic: bool,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            title: "learn-wgpu".to_string(),
+            gpu: GpuSettings::default(),
+            loop_mode: LoopMode::default(),
+            gamepad_deadzones: Deadzones::default(),
+            record_mode: RecordMode::Off,
+            seed: None,
+            deterministic: false,
+        }
+    }
+}
+
+// the window and gpu context owned by the crate, handed to every `App` call
+pub struct AppContext {
+    // owns the window, if there is one
+    pub gpu: GpuContext,
+    // every connected pad, updated before `App::gamepad_input` sees an event
+    pub gamepads: Gamepads,
+    seed: u64,
+    exit_requested: bool,
+}
+
+impl AppContext {
+    // None for `replay_headless`
+    pub fn window(&self) -> Option<&Window> {
+        self.gpu.window()
+    }
+
+    // ends the event loop once the current event is handled
+    pub fn exit(&mut self) {
+        self.exit_requested = true;
+    }
+
+    pub fn exit_requested(&self) -> bool {
+        self.exit_requested
+    }
+
+    // for the app's random numbers, so a replay gets the same ones
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+// user logic driven by `run_app`, which owns the window, the event loop and
+// the gpu context
+pub trait App: Sized {
+    fn config() -> AppConfig {
+        AppConfig::default()
+    }
+
+    fn init(ctx: &AppContext) -> anyhow::Result<Self>;
+
+    // true if the event was used, otherwise the crate handles closing and
+    // resizing the window. `AppContext::exit` ends the app from any call
+    fn input(&mut self, _ctx: &mut AppContext, _event: &WindowEvent) -> bool {
+        false
+    }
+
+    // where gamepad events come from, asked once after `init`. the devices
+    // connected to the machine by default, `gamepad::VirtualGamepads` for
+    // scripted input
+    fn gamepad_backend(&self) -> Option<Box<dyn GamepadBackend>> {
+        gamepad::default_backend()
+    }
+
+    // true if the event was used. events are polled at the start of every
+    // frame, before the updates
+    fn gamepad_input(&mut self, _ctx: &mut AppContext, _event: &GamepadEvent) -> bool {
+        false
+    }
+
+    // advances the simulation, once per fixed step or once per frame
+    // depending on `AppConfig::loop_mode`
+    fn update(&mut self, _ctx: &mut AppContext, _time: &FrameTime) {}
+
+    // draws into `view`, the surface texture, which is presented afterwards.
+    // `alpha` is how far the frame is from the previous update to the latest
+    fn render(&mut self, ctx: &mut AppContext, view: &wgpu::TextureView, time: &FrameTime, alpha: f32);
+
+    // after the surface has been reconfigured to the new size
+    fn resize(&mut self, _ctx: &mut AppContext, _new_size: winit::dpi::PhysicalSize<u32>) {}
+}
+
+fn resize<A: App>(ctx: &mut AppContext, app: &mut A, new_size: winit::dpi::PhysicalSize<u32>) {
+    if ctx.gpu.resize(new_size) {
+        app.resize(ctx, new_size);
+    }
+}
+
+fn gamepad_input<A: App>(ctx: &mut AppContext, app: &mut A, event: &GamepadEvent) {
+    ctx.gamepads.handle_event(event);
+    app.gamepad_input(ctx, event);
+}
+
+// a recorded event as if it had just happened. recorded resizes only
+// resize a headless target, a window keeps its actual size
+fn replay_event<A: App>(ctx: &mut AppContext, app: &mut A, event: &RecordedEvent) {
+    match event {
+        RecordedEvent::Gamepad(event) => gamepad_input(ctx, app, event),
+        event => {
+            if let Some(event) = event.to_window_event() {
+                if !app.input(ctx, &event) {
+                    if let (WindowEvent::Resized(new_size), true) = (&event, ctx.gpu.is_headless()) {
+                        resize(ctx, app, *new_size);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// the delta of every frame in deterministic mode, one update's worth
+fn deterministic_delta(loop_mode: &LoopMode) -> Duration {
+    match loop_mode {
+        LoopMode::Variable => Duration::from_secs(1) / 60,
+        LoopMode::Fixed(fixed) => Duration::from_secs_f32(fixed.step),
+    }
+}
+
+// what `AppContext::seed` returns when there's no recording to take it from
+fn seed(app_config: &AppConfig) -> u64 {
+    match app_config.seed {
+        Some(seed) => seed,
+        None if app_config.deterministic => 0,
+        None => rand::random(),
+    }
+}
+
+// runs the updates due at `time` and draws and presents a frame
+fn frame<A: App>(ctx: &mut AppContext, app: &mut A, loop_mode: &mut LoopMode, time: &FrameTime) -> Result<(), wgpu::SurfaceError> {
+    let alpha = match loop_mode {
+        LoopMode::Variable => {
+            app.update(ctx, time);
+            1.0
+        }
+        LoopMode::Fixed(fixed) => {
+            for _ in 0..fixed.advance(time) {
+                app.update(ctx, &fixed.next_update());
+            }
+            fixed.alpha()
+        }
+    };
+    let frame = ctx.gpu.acquire_frame()?;
+    app.render(ctx, &frame.view, time, alpha);
+    frame.present();
+    Ok(())
+}
+
+pub async fn run_app<A: App + 'static>() {
+    run_app_with::<A>(A::config()).await;
+}
+
+// like `run_app`, with `app_config` instead of `App::config`
+pub async fn run_app_with<A: App + 'static>(mut app_config: AppConfig) {
+    if app_config.deterministic {
+        app_config.gpu.prefer_hdr_output = false;
+    }
+    let mut recording = None;
+    if let RecordMode::Replay(path) = &app_config.record_mode {
+        match Recording::load(path) {
+            Ok(loaded) => recording = Some(loaded),
+            Err(e) => {
+                eprintln!("{:?}", e);
+                return;
+            }
+        }
+    }
+    let event_loop = EventLoop::new();
+    let mut window_builder = WindowBuilder::new().with_title(&app_config.title);
+    if let Some(recording) = &recording {
+        window_builder = window_builder.with_inner_size(recording.size);
+    }
+    let window = window_builder.build(&event_loop).unwrap();
+    let window_id = window.id();
+
+    let gpu = match GpuContext::new(window, &app_config.gpu).await {
+        Ok(gpu) => gpu,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            return;
+        }
+    };
+    let seed = recording.as_ref().map_or_else(|| seed(&app_config), |recording| recording.seed);
+    let mut recorder = match &app_config.record_mode {
+        RecordMode::Record(path) => Some((path.clone(), Recorder::new(seed, gpu.size()))),
+        _ => None,
+    };
+    let mut replay_frames = recording.map(|recording| recording.frames.into_iter().peekable());
+    let mut ctx = AppContext {
+        gpu,
+        gamepads: Gamepads::new(app_config.gamepad_deadzones),
+        seed,
+        exit_requested: false,
+    };
+    let mut app = match A::init(&ctx) {
+        Ok(app) => app,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            return;
+        }
+    };
+    let mut clock = Clock::new();
+    let fixed_delta = app_config.deterministic.then(|| deterministic_delta(&app_config.loop_mode));
+    let mut loop_mode = app_config.loop_mode;
+    let mut gamepad_backend = app.gamepad_backend();
+    let mut gamepad_events = Vec::new();
+
+    event_loop.run(move | event, _, control_flow | {
+        // live input is ignored while a replay lasts
+        let replaying = replay_frames.as_mut().is_some_and(|frames| frames.peek().is_some());
+        match event {
+            Event::WindowEvent {ref event, window_id: id} if id == window_id => {
+                if let Some((_, recorder)) = &mut recorder {
+                    recorder.record_window_event(event);
+                }
+                if replaying || !app.input(&mut ctx, event) {
+                    match event {
+                        WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                        WindowEvent::Resized(physical_size) => {
+                            resize(&mut ctx, &mut app, *physical_size);
+                        }
+                        WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                            resize(&mut ctx, &mut app, **new_inner_size);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Event::RedrawRequested(id) if id == window_id => {
+                if let Some(backend) = &mut gamepad_backend {
+                    backend.poll(&mut gamepad_events);
+                }
+                let delta = match replay_frames.as_mut().and_then(Iterator::next) {
+                    Some(recorded) => {
+                        gamepad_events.clear();
+                        for event in &recorded.events {
+                            replay_event(&mut ctx, &mut app, event);
+                        }
+                        recorded.delta
+                    }
+                    None => {
+                        for event in gamepad_events.drain(..) {
+                            if let Some((_, recorder)) = &mut recorder {
+                                recorder.record_gamepad_event(&event);
+                            }
+                            gamepad_input(&mut ctx, &mut app, &event);
+                        }
+                        fixed_delta.unwrap_or_else(|| clock.measure())
+                    }
+                };
+                if let Some((_, recorder)) = &mut recorder {
+                    recorder.end_frame(delta);
+                }
+                let time = clock.advance(delta);
+                match frame(&mut ctx, &mut app, &mut loop_mode, &time) {
+                    Ok(()) => {}
+                    Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
+                    Err(e) => eprintln!("{:?}", e),
+                }
+            }
+            Event::MainEventsCleared => {
+                // RedrawRequested will only trigger once
+                // unless we request it
+                if let Some(window) = ctx.window() {
+                    window.request_redraw();
+                }
+            }
+            Event::LoopDestroyed => {
+                if let Some((path, recorder)) = &recorder {
+                    if let Err(e) = recorder.recording().save(path) {
+                        eprintln!("{:?}", e);
+                    }
+                }
+            }
+            _ => {}
+        }
+        if ctx.exit_requested {
+            *control_flow = ControlFlow::Exit;
+        }
+    });
+}
+
+// runs the app on `recording` without a window or an event loop, drawing
+// into a `HEADLESS_FORMAT` target of the recorded size. `after_frame` runs
+// once each frame is submitted, e.g. to read it back with
+// `GpuContext::read_frame`
+pub async fn replay_headless<A: App>(
+    app_config: AppConfig,
+    recording: &Recording,
+    mut after_frame: impl FnMut(&mut A, &mut AppContext),
+) -> anyhow::Result<A> {
+    let gpu = GpuContext::headless(recording.size, HEADLESS_FORMAT, &app_config.gpu).await?;
+    let mut ctx = AppContext {
+        gpu,
+        gamepads: Gamepads::new(app_config.gamepad_deadzones),
+        seed: recording.seed,
+        exit_requested: false,
+    };
+    let mut app = A::init(&ctx)?;
+    let mut clock = Clock::new();
+    let mut loop_mode = app_config.loop_mode;
+    for recorded in &recording.frames {
+        for event in &recorded.events {
+            replay_event(&mut ctx, &mut app, event);
+        }
+        let time = clock.advance(recorded.delta);
+        frame(&mut ctx, &mut app, &mut loop_mode, &time)?;
+        after_frame(&mut app, &mut ctx);
+        if ctx.exit_requested {
+            break;
+        }
+    }
+    Ok(app)
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use image::{GenericImageView, RgbaImage};
+use serde::{Deserialize, Serialize};
+use anyhow::*;
+
+use crate::texture;
+
+// normalized rectangle inside an atlas page
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UvRect {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+impl UvRect {
+    pub const FULL: UvRect = UvRect { min: [0.0, 0.0], max: [1.0, 1.0] };
+
+    // map tex coords in 0..1 of the source image into this rect
+    pub fn remap(&self, tex_coords: [f32; 2]) -> [f32; 2] {
+        [
+            self.min[0] + tex_coords[0] * (self.max[0] - self.min[0]),
+            self.min[1] + tex_coords[1] * (self.max[1] - self.min[1]),
+        ]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AtlasEntry {
+    pub page: usize,
+    // pixel rect of the image, excluding padding
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub uv: UvRect,
+}
+
+// everything needed to rebuild an atlas from its page images,
+// so packing can happen offline and only the result is shipped
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AtlasLayout {
+    pub page_width: u32,
+    pub page_height: u32,
+    pub padding: u32,
+    pub page_count: usize,
+    pub entries: BTreeMap<String, AtlasEntry>,
+}
+
+impl AtlasLayout {
+    pub fn get(&self, name: &str) -> Option<&AtlasEntry> {
+        self.entries.get(name)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+}
+
+// a shelf is a horizontal strip of a page, as tall as its first (tallest) image
+struct Shelf {
+    y: u32,
+    height: u32,
+    cursor_x: u32,
+}
+
+struct Page {
+    shelves: Vec<Shelf>,
+    used_height: u32,
+}
+
+// packs many images into as few pages as possible with the shelf algorithm
+pub struct AtlasPacker {
+    max_size: u32,
+    padding: u32,
+    images: Vec<(String, RgbaImage)>,
+}
+
+impl AtlasPacker {
+    pub fn new(max_size: u32, padding: u32) -> Self {
+        Self {
+            max_size,
+            padding,
+            images: Vec::new(),
+        }
+    }
+
+    pub fn add_image(&mut self, name: &str, img: &image::DynamicImage) {
+        self.images.push((name.to_string(), img.to_rgba8()));
+    }
+
+    pub fn add_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        let img = image::load_from_memory(bytes)?;
+        self.add_image(name, &img);
+        Ok(())
+    }
+
+    // packs on the CPU only; the pages can be written out as pngs
+    // next to the layout and loaded later with `TextureAtlas::from_pages`
+    pub fn pack(&self) -> Result<(AtlasLayout, Vec<RgbaImage>)> {
+        // the layout looks entries up by name
+        let mut names = std::collections::BTreeSet::new();
+        if let Some((name, _)) = self.images.iter().find(|(name, _)| !names.insert(name)) {
+            bail!("atlas has more than one image named {}", name);
+        }
+        let padded = |img: &RgbaImage| (img.width() + 2 * self.padding, img.height() + 2 * self.padding);
+        // tallest first keeps shelves tight
+        let mut order: Vec<usize> = (0..self.images.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.images[i].1.height()));
+
+        let mut pages: Vec<Page> = Vec::new();
+        let mut placements = vec![(0usize, 0u32, 0u32); self.images.len()];
+        for &i in order.iter() {
+            let (name, img) = &self.images[i];
+            // nothing to extrude into the padding
+            if img.width() == 0 || img.height() == 0 {
+                bail!("image {} is empty ({}x{})", name, img.width(), img.height());
+            }
+            let (w, h) = padded(img);
+            if w > self.max_size || h > self.max_size {
+                bail!("image {} ({}x{}) does not fit in a {}px atlas page",
+                    name, img.width(), img.height(), self.max_size);
+            }
+            let placed = pages.iter_mut()
+                .enumerate()
+                .find_map(|(page_idx, page)| {
+                    Self::place(page, w, h, self.max_size).map(|(x, y)| (page_idx, x, y))
+                });
+            placements[i] = match placed {
+                Some(p) => p,
+                None => {
+                    pages.push(Page { shelves: Vec::new(), used_height: 0 });
+                    let page_idx = pages.len() - 1;
+                    let (x, y) = Self::place(&mut pages[page_idx], w, h, self.max_size).unwrap();
+                    (page_idx, x, y)
+                }
+            };
+        }
+
+        // shrink pages to the smallest power of two that holds everything,
+        // or max_size when that isn't a power of two and is smaller
+        let mut page_width = 1;
+        let mut page_height = 1;
+        for page in pages.iter() {
+            let width = page.shelves.iter().map(|s| s.cursor_x).max().unwrap_or(0);
+            page_width = page_width.max(width.next_power_of_two().min(self.max_size));
+            page_height = page_height.max(page.used_height.next_power_of_two().min(self.max_size));
+        }
+
+        let mut page_images = vec![RgbaImage::new(page_width, page_height); pages.len()];
+        let mut entries = BTreeMap::new();
+        for (i, (name, img)) in self.images.iter().enumerate() {
+            let (page, px, py) = placements[i];
+            let x = px + self.padding;
+            let y = py + self.padding;
+            Self::blit_extruded(&mut page_images[page], img, x, y, self.padding);
+            let uv = UvRect {
+                min: [x as f32 / page_width as f32, y as f32 / page_height as f32],
+                max: [
+                    (x + img.width()) as f32 / page_width as f32,
+                    (y + img.height()) as f32 / page_height as f32,
+                ],
+            };
+            entries.insert(name.clone(), AtlasEntry {
+                page,
+                x,
+                y,
+                width: img.width(),
+                height: img.height(),
+                uv,
+            });
+        }
+        let layout = AtlasLayout {
+            page_width,
+            page_height,
+            padding: self.padding,
+            page_count: pages.len(),
+            entries,
+        };
+        Ok((layout, page_images))
+    }
+
+    pub fn build(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<TextureAtlas> {
+        let (layout, pages) = self.pack()?;
+        TextureAtlas::from_pages(device, queue, layout, pages)
+    }
+
+    fn place(page: &mut Page, w: u32, h: u32, max_size: u32) -> Option<(u32, u32)> {
+        for shelf in page.shelves.iter_mut() {
+            if h <= shelf.height && shelf.cursor_x + w <= max_size {
+                let x = shelf.cursor_x;
+                shelf.cursor_x += w;
+                return Some((x, shelf.y));
+            }
+        }
+        // open a new shelf below the last one
+        if page.used_height + h > max_size {
+            return None;
+        }
+        let y = page.used_height;
+        page.shelves.push(Shelf { y, height: h, cursor_x: w });
+        page.used_height += h;
+        Some((0, y))
+    }
+
+    // copy the image and repeat its border pixels into the padding,
+    // so linear filtering at the edges never picks up a neighbour
+    fn blit_extruded(dst: &mut RgbaImage, src: &RgbaImage, x: u32, y: u32, padding: u32) {
+        let (w, h) = src.dimensions();
+        let p = padding as i64;
+        for dy in -p..(h as i64 + p) {
+            for dx in -p..(w as i64 + p) {
+                let sx = dx.clamp(0, w as i64 - 1) as u32;
+                let sy = dy.clamp(0, h as i64 - 1) as u32;
+                let tx = (x as i64 + dx) as u32;
+                let ty = (y as i64 + dy) as u32;
+                dst.put_pixel(tx, ty, *src.get_pixel(sx, sy));
+            }
+        }
+    }
+}
+
+pub struct TextureAtlas {
+    pub layout: AtlasLayout,
+    pub pages: Vec<texture::Texture>,
+}
+
+impl TextureAtlas {
+    pub fn from_pages(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: AtlasLayout,
+        pages: Vec<RgbaImage>,
+    ) -> Result<Self> {
+        if pages.len() != layout.page_count {
+            bail!("atlas layout expects {} pages, got {}", layout.page_count, pages.len());
+        }
+        let pages = pages.into_iter()
+            .enumerate()
+            .map(|(i, page)| {
+                let img = image::DynamicImage::ImageRgba8(page);
+                texture::Texture::from_image(device, queue, &img, Some(&format!("atlas page {}", i)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { layout, pages })
+    }
+
+    // load a prebuilt atlas from its layout and encoded page images
+    pub fn from_prebuilt(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: AtlasLayout,
+        page_bytes: &[&[u8]],
+    ) -> Result<Self> {
+        let pages = page_bytes.iter()
+            .map(|bytes| {
+                let img = image::load_from_memory(bytes)?;
+                if img.dimensions() != (layout.page_width, layout.page_height) {
+                    bail!("atlas page is {:?}, layout expects {}x{}",
+                        img.dimensions(), layout.page_width, layout.page_height);
+                }
+                Ok(img.to_rgba8())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::from_pages(device, queue, layout, pages)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AtlasEntry> {
+        self.layout.get(name)
+    }
+
+    pub fn page(&self, entry: &AtlasEntry) -> &texture::Texture {
+        &self.pages[entry.page]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32) -> image::DynamicImage {
+        image::DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, image::Rgba([255; 4])))
+    }
+
+    #[test]
+    fn empty_images_are_rejected() {
+        for (width, height) in [(0, 4), (4, 0)] {
+            let mut packer = AtlasPacker::new(64, 1);
+            packer.add_image("empty", &image(width, height));
+            assert!(packer.pack().is_err());
+        }
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let mut packer = AtlasPacker::new(64, 1);
+        packer.add_image("a", &image(4, 4));
+        packer.add_image("b", &image(4, 4));
+        packer.add_image("a", &image(8, 8));
+        let error = packer.pack().unwrap_err().to_string();
+        assert!(error.contains("more than one image named a"), "{}", error);
+    }
+
+    #[test]
+    fn pages_stay_within_max_size() {
+        let mut packer = AtlasPacker::new(100, 1);
+        packer.add_image("a", &image(70, 70));
+        let (layout, pages) = packer.pack().unwrap();
+        assert_eq!((layout.page_width, layout.page_height), (100, 100));
+        assert_eq!(pages[0].dimensions(), (100, 100));
+        let entry = layout.get("a").unwrap();
+        assert_eq!((entry.x, entry.y), (1, 1));
+        assert!(entry.uv.max[0] <= 1.0 && entry.uv.max[1] <= 1.0);
+    }
+}
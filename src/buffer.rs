@@ -0,0 +1,346 @@
+use wgpu::util::DeviceExt;
+
+// round up to the next multiple of `alignment`, which must be a power of two
+fn align_to(value: wgpu::BufferAddress, alignment: wgpu::BufferAddress) -> wgpu::BufferAddress {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+// a gpu buffer whose contents can be replaced every frame,
+// reallocating when the new data no longer fits
+pub struct DynamicBuffer {
+    buffer: wgpu::Buffer,
+    label: String,
+    usage: wgpu::BufferUsages,
+    // bytes
+    capacity: wgpu::BufferAddress,
+    len: wgpu::BufferAddress,
+}
+
+impl DynamicBuffer {
+    pub fn new(
+        device: &wgpu::Device,
+        label: &str,
+        usage: wgpu::BufferUsages,
+        capacity: wgpu::BufferAddress,
+    ) -> Self {
+        let usage = usage | wgpu::BufferUsages::COPY_DST;
+        let capacity = align_to(capacity.max(wgpu::COPY_BUFFER_ALIGNMENT), wgpu::COPY_BUFFER_ALIGNMENT);
+        let buffer = Self::allocate(device, label, usage, capacity);
+        Self {
+            buffer,
+            label: label.to_string(),
+            usage,
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub fn with_contents(
+        device: &wgpu::Device,
+        label: &str,
+        usage: wgpu::BufferUsages,
+        contents: &[u8],
+    ) -> Self {
+        let usage = usage | wgpu::BufferUsages::COPY_DST;
+        let size = contents.len() as wgpu::BufferAddress;
+        // never empty, like `new`, so `slice` has something to return
+        let capacity = align_to(size.max(wgpu::COPY_BUFFER_ALIGNMENT), wgpu::COPY_BUFFER_ALIGNMENT);
+        let mut padded = Vec::new();
+        let contents = if capacity == size {
+            contents
+        } else {
+            padded.extend_from_slice(contents);
+            padded.resize(capacity as usize, 0);
+            &padded
+        };
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents,
+            usage,
+        });
+        Self {
+            buffer,
+            label: label.to_string(),
+            usage,
+            capacity,
+            len: size,
+        }
+    }
+
+    // replace the contents, returns true if the buffer had to be reallocated
+    // (any bind groups referencing it must then be recreated)
+    pub fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, data: &[u8]) -> bool {
+        let size = data.len() as wgpu::BufferAddress;
+        let grown = size > self.capacity;
+        if grown {
+            // grow geometrically so streaming data doesn't reallocate every frame
+            self.capacity = align_to(size.next_power_of_two(), wgpu::COPY_BUFFER_ALIGNMENT);
+            self.buffer = Self::allocate(device, &self.label, self.usage, self.capacity);
+        }
+        // write_buffer needs a size that is a multiple of 4
+        let aligned = align_to(size, wgpu::COPY_BUFFER_ALIGNMENT);
+        if aligned == size {
+            queue.write_buffer(&self.buffer, 0, data);
+        } else {
+            let mut padded = data.to_vec();
+            padded.resize(aligned as usize, 0);
+            queue.write_buffer(&self.buffer, 0, &padded);
+        }
+        self.len = size;
+        grown
+    }
+
+    pub fn write_slice<T: bytemuck::Pod>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &[T],
+    ) -> bool {
+        self.write(device, queue, bytemuck::cast_slice(data))
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    // only the part written by the last `write`
+    pub fn slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..self.len.max(1).min(self.capacity))
+    }
+
+    pub fn len(&self) -> wgpu::BufferAddress {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> wgpu::BufferAddress {
+        self.capacity
+    }
+
+    fn allocate(
+        device: &wgpu::Device,
+        label: &str,
+        usage: wgpu::BufferUsages,
+        size: wgpu::BufferAddress,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+}
+
+// a sub-allocation handed out by `RingBuffer::alloc`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RingAllocation {
+    chunk: usize,
+    pub offset: wgpu::BufferAddress,
+    pub size: wgpu::BufferAddress,
+}
+
+struct Chunk {
+    buffer: wgpu::Buffer,
+    size: wgpu::BufferAddress,
+    cursor: wgpu::BufferAddress,
+    // frame that last allocated from this chunk
+    frame: u64,
+}
+
+// hands out many small ranges per frame from a few large buffers.
+// chunks used in a frame are only reused `frames_in_flight` frames later,
+// so writes never land in memory the gpu may still be reading
+pub struct RingBuffer {
+    chunks: Vec<Chunk>,
+    // chunks allocated from in the current frame
+    active: Vec<usize>,
+    label: String,
+    usage: wgpu::BufferUsages,
+    chunk_size: wgpu::BufferAddress,
+    alignment: wgpu::BufferAddress,
+    frames_in_flight: u64,
+    frame: u64,
+}
+
+impl RingBuffer {
+    // `alignment` is the offset alignment of each allocation, e.g.
+    // `min_uniform_buffer_offset_alignment` for dynamic uniform offsets
+    pub fn new(
+        label: &str,
+        usage: wgpu::BufferUsages,
+        chunk_size: wgpu::BufferAddress,
+        alignment: wgpu::BufferAddress,
+        frames_in_flight: u64,
+    ) -> Self {
+        Self {
+            chunks: Vec::new(),
+            active: Vec::new(),
+            label: label.to_string(),
+            usage: usage | wgpu::BufferUsages::COPY_DST,
+            chunk_size: align_to(chunk_size, wgpu::COPY_BUFFER_ALIGNMENT),
+            alignment: alignment.max(wgpu::COPY_BUFFER_ALIGNMENT),
+            frames_in_flight: frames_in_flight.max(1),
+            frame: 0,
+        }
+    }
+
+    // call once per frame before any `alloc`
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+        self.active.clear();
+    }
+
+    pub fn alloc(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &[u8],
+    ) -> RingAllocation {
+        // empty data still reserves some space, like `DynamicBuffer`, so
+        // `slice` has something to return
+        let size = align_to((data.len() as wgpu::BufferAddress).max(1), wgpu::COPY_BUFFER_ALIGNMENT);
+        let chunk = self.find_chunk(device, size);
+        let offset = self.chunks[chunk].cursor;
+        self.chunks[chunk].cursor = align_to(offset + size, self.alignment);
+        if size == data.len() as wgpu::BufferAddress {
+            queue.write_buffer(&self.chunks[chunk].buffer, offset, data);
+        } else {
+            let mut padded = data.to_vec();
+            padded.resize(size as usize, 0);
+            queue.write_buffer(&self.chunks[chunk].buffer, offset, &padded);
+        }
+        RingAllocation {
+            chunk,
+            offset,
+            size: data.len() as wgpu::BufferAddress,
+        }
+    }
+
+    pub fn alloc_slice<T: bytemuck::Pod>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &[T],
+    ) -> RingAllocation {
+        self.alloc(device, queue, bytemuck::cast_slice(data))
+    }
+
+    pub fn buffer(&self, allocation: &RingAllocation) -> &wgpu::Buffer {
+        &self.chunks[allocation.chunk].buffer
+    }
+
+    pub fn slice(&self, allocation: &RingAllocation) -> wgpu::BufferSlice<'_> {
+        self.buffer(allocation)
+            .slice(allocation.offset..allocation.offset + allocation.size.max(1))
+    }
+
+    fn find_chunk(&mut self, device: &wgpu::Device, size: wgpu::BufferAddress) -> usize {
+        // keep filling a chunk already used this frame
+        for &i in self.active.iter() {
+            if self.chunks[i].cursor + size <= self.chunks[i].size {
+                return i;
+            }
+        }
+        // otherwise recycle one the gpu is done with
+        let frame = self.frame;
+        let frames_in_flight = self.frames_in_flight;
+        let free = self.chunks.iter().position(|c| {
+            frame - c.frame >= frames_in_flight && c.size >= size
+        });
+        let i = match free {
+            Some(i) => i,
+            None => {
+                let chunk_size = self.chunk_size.max(size.next_power_of_two());
+                let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some(&self.label),
+                    size: chunk_size,
+                    usage: self.usage,
+                    mapped_at_creation: false,
+                });
+                self.chunks.push(Chunk {
+                    buffer,
+                    size: chunk_size,
+                    cursor: 0,
+                    frame,
+                });
+                self.chunks.len() - 1
+            }
+        };
+        self.chunks[i].cursor = 0;
+        self.chunks[i].frame = frame;
+        self.active.push(i);
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu() -> Option<(wgpu::Device, wgpu::Queue)> {
+        match futures::executor::block_on(crate::compute::request_headless_device()) {
+            Ok((_, device, queue)) => Some((device, queue)),
+            Err(e) => {
+                eprintln!("skipping, no adapter: {:?}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn ring_chunks_are_reused_after_frames_in_flight() {
+        let (device, queue) = match gpu() {
+            Some(gpu) => gpu,
+            None => return,
+        };
+        let mut ring = RingBuffer::new("Ring", wgpu::BufferUsages::UNIFORM, 512, 256, 2);
+        let mut chunks = Vec::new();
+        for _ in 0..4 {
+            ring.begin_frame();
+            let first = ring.alloc(&device, &queue, &[1; 16]);
+            let second = ring.alloc(&device, &queue, &[2; 16]);
+            // both fit in one chunk, at aligned offsets
+            assert_eq!(first.chunk, second.chunk);
+            assert_eq!((first.offset, second.offset), (0, 256));
+            chunks.push(first.chunk);
+        }
+        // the first frame's chunk is still in flight during the second
+        assert_eq!(chunks, [0, 1, 0, 1]);
+        assert_eq!(ring.chunks.len(), 2);
+    }
+
+    #[test]
+    fn empty_ring_allocations_reserve_space() {
+        let (device, queue) = match gpu() {
+            Some(gpu) => gpu,
+            None => return,
+        };
+        let mut ring = RingBuffer::new("Ring", wgpu::BufferUsages::VERTEX, 64, 4, 2);
+        ring.begin_frame();
+        let empty = ring.alloc(&device, &queue, &[]);
+        let next = ring.alloc(&device, &queue, &[1, 2, 3]);
+        assert_eq!(empty.size, 0);
+        assert_eq!(next.offset, wgpu::COPY_BUFFER_ALIGNMENT);
+        let _ = ring.slice(&empty);
+    }
+
+    #[test]
+    fn with_contents_is_aligned() {
+        let (device, _) = match gpu() {
+            Some(gpu) => gpu,
+            None => return,
+        };
+        let usage = wgpu::BufferUsages::VERTEX;
+        let empty = DynamicBuffer::with_contents(&device, "Empty", usage, &[]);
+        assert_eq!((empty.len(), empty.capacity()), (0, wgpu::COPY_BUFFER_ALIGNMENT));
+        assert_eq!(empty.buffer().size(), wgpu::COPY_BUFFER_ALIGNMENT);
+        let _ = empty.slice();
+        let odd = DynamicBuffer::with_contents(&device, "Odd", usage, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!((odd.len(), odd.capacity()), (6, 8));
+        assert_eq!(odd.buffer().size(), 8);
+    }
+}
@@ -0,0 +1,119 @@
+use cgmath::{Matrix4, Point3, SquareMatrix, Vector3};
+use wgpu::util::DeviceExt;
+
+// cgmath is built for opengl's -1..1 clip depth, wgpu uses 0..1
+#[rustfmt::skip]
+pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+#[derive(Copy, Clone, Debug)]
+pub struct Camera {
+    pub eye: Point3<f32>,
+    pub target: Point3<f32>,
+    pub up: Vector3<f32>,
+    pub aspect: f32,
+    // degrees
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+impl Camera {
+    // looking down -z at the origin from where the z = 0 plane fills
+    // clip space vertically, like the untransformed demo shapes
+    pub fn new(aspect: f32) -> Self {
+        Self {
+            eye: Point3::new(0.0, 0.0, 2.0),
+            target: Point3::new(0.0, 0.0, 0.0),
+            up: Vector3::unit_y(),
+            aspect,
+            fovy: 2.0 * 0.5f32.atan().to_degrees(),
+            znear: 0.1,
+            zfar: 100.0,
+        }
+    }
+
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        Matrix4::look_at_rh(self.eye, self.target, self.up)
+    }
+
+    pub fn projection_matrix(&self) -> Matrix4<f32> {
+        OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar)
+    }
+
+    pub fn view_projection_matrix(&self) -> Matrix4<f32> {
+        self.projection_matrix() * self.view_matrix()
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CameraUniform {
+    pub view_proj: [[f32; 4]; 4],
+    // w is unused, vec3 would be padded to 16 bytes anyway
+    pub view_position: [f32; 4],
+    // for shaders working back from clip space, like the skybox
+    pub inverse_view_proj: [[f32; 4]; 4],
+}
+
+impl CameraUniform {
+    pub fn from_camera(camera: &Camera) -> Self {
+        let view_proj = camera.view_projection_matrix();
+        Self {
+            view_proj: view_proj.into(),
+            view_position: camera.eye.to_homogeneous().into(),
+            inverse_view_proj: view_proj.invert().unwrap_or_else(Matrix4::identity).into(),
+        }
+    }
+}
+
+// the camera uniform and its bind group, visible to both shader stages
+pub struct CameraBuffer {
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+    buffer: wgpu::Buffer,
+}
+
+impl CameraBuffer {
+    pub fn new(device: &wgpu::Device, camera: &Camera) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("camera_bind_group_layout"),
+        });
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Camera Buffer"),
+            contents: bytemuck::cast_slice(&[CameraUniform::from_camera(camera)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("camera_bind_group"),
+        });
+        Self {
+            bind_group_layout,
+            bind_group,
+            buffer,
+        }
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, camera: &Camera) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[CameraUniform::from_camera(camera)]));
+    }
+}
@@ -0,0 +1,403 @@
+use std::marker::PhantomData;
+
+use anyhow::*;
+use futures::channel::oneshot;
+use wgpu::util::DeviceExt;
+
+use crate::gpu;
+
+// a device without a surface, for compute work and tests. the backends can
+// be picked with WGPU_BACKEND, and a software adapter is used when there
+// is no hardware one
+pub async fn request_headless_device() -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+    let instance = gpu::create_instance();
+    let adapter = gpu::request_adapter(&instance, None).await?;
+    let desc = wgpu::DeviceDescriptor {
+        label: Some("Headless Device"),
+        features: wgpu::Features::empty(),
+        limits: wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits()),
+    };
+    let (device, queue) = adapter.request_device(&desc, None).await?;
+    Ok((adapter, device, queue))
+}
+
+// workgroups needed to cover `size` invocations, per dimension
+pub fn workgroup_count(size: [u32; 3], workgroup_size: [u32; 3]) -> [u32; 3] {
+    [0, 1, 2].map(|i| size[i].div_ceil(workgroup_size[i].max(1)))
+}
+
+// the @workgroup_size of `entry_point`. only literal sizes are understood
+fn parse_workgroup_size(source: &str, entry_point: &str) -> Result<[u32; 3]> {
+    let function = source.match_indices("fn ")
+        .map(|(i, _)| i)
+        .find(|&i| {
+            let rest = source[i + 3..].trim_start();
+            rest.strip_prefix(entry_point)
+                .is_some_and(|after| after.trim_start().starts_with('('))
+        })
+        .ok_or_else(|| anyhow!("no function {} in the shader", entry_point))?;
+    let attribute = source[..function].rfind("@workgroup_size")
+        .ok_or_else(|| anyhow!("{} has no @workgroup_size", entry_point))?;
+    let arguments = &source[attribute + "@workgroup_size".len()..function];
+    let arguments = arguments.trim_start()
+        .strip_prefix('(')
+        .and_then(|a| a.split(')').next())
+        .ok_or_else(|| anyhow!("malformed @workgroup_size of {}", entry_point))?;
+    let mut size = [1; 3];
+    for (i, argument) in arguments.split(',').map(str::trim).filter(|a| !a.is_empty()).enumerate() {
+        if i >= 3 {
+            bail!("@workgroup_size of {} has more than 3 dimensions", entry_point);
+        }
+        size[i] = argument.trim_end_matches('u').parse()
+            .with_context(|| format!("@workgroup_size({}) of {} isn't a literal", arguments, entry_point))?;
+    }
+    Ok(size)
+}
+
+// a compute pipeline for one entry point of a wgsl module, with the bind
+// group layouts derived from what the entry point uses
+pub struct ComputeKernel {
+    pub pipeline: wgpu::ComputePipeline,
+    pub workgroup_size: [u32; 3],
+}
+
+impl ComputeKernel {
+    pub fn new(device: &wgpu::Device, label: &str, source: &str, entry_point: &str) -> Result<Self> {
+        let workgroup_size = parse_workgroup_size(source, entry_point)?;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(label),
+            layout: None,
+            module: &module,
+            entry_point,
+        });
+        Ok(Self { pipeline, workgroup_size })
+    }
+
+    // `resources` are (binding, resource) pairs of `group`. the derived layout
+    // only has the bindings the entry point actually uses
+    pub fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        group: u32,
+        resources: &[(u32, wgpu::BindingResource)],
+    ) -> wgpu::BindGroup {
+        let entries: Vec<_> = resources.iter()
+            .map(|(binding, resource)| wgpu::BindGroupEntry {
+                binding: *binding,
+                resource: resource.clone(),
+            })
+            .collect();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("compute_bind_group"),
+            layout: &self.pipeline.get_bind_group_layout(group),
+            entries: &entries,
+        })
+    }
+
+    // runs the kernel for at least `size` invocations, bind groups in group order.
+    // kernels have to skip the invocations past the end of their data
+    pub fn dispatch(&self, encoder: &mut wgpu::CommandEncoder, bind_groups: &[&wgpu::BindGroup], size: [u32; 3]) {
+        let [x, y, z] = workgroup_count(size, self.workgroup_size);
+        self.dispatch_workgroups(encoder, bind_groups, [x, y, z]);
+    }
+
+    pub fn dispatch_workgroups(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        bind_groups: &[&wgpu::BindGroup],
+        workgroups: [u32; 3],
+    ) {
+        if workgroups.contains(&0) {
+            return;
+        }
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Compute Pass"),
+        });
+        pass.set_pipeline(&self.pipeline);
+        for (i, bind_group) in bind_groups.iter().enumerate() {
+            pass.set_bind_group(i as u32, bind_group, &[]);
+        }
+        pass.dispatch_workgroups(workgroups[0], workgroups[1], workgroups[2]);
+    }
+}
+
+// a typed read-write storage buffer
+pub struct StorageBuffer<T: bytemuck::Pod> {
+    buffer: wgpu::Buffer,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> StorageBuffer<T> {
+    // `usage` is added to STORAGE | COPY_SRC | COPY_DST, e.g. VERTEX to draw from it
+    pub fn new(device: &wgpu::Device, label: &str, data: &[T], usage: wgpu::BufferUsages) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents: bytemuck::cast_slice(data),
+            usage: usage | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+        });
+        Self { buffer, len: data.len(), _marker: PhantomData }
+    }
+
+    pub fn zeroed(device: &wgpu::Device, label: &str, len: usize, usage: wgpu::BufferUsages) -> Self {
+        Self::new(device, label, &vec![T::zeroed(); len], usage)
+    }
+
+    // `data` has to fit, storage buffers don't grow
+    pub fn write(&self, queue: &wgpu::Queue, offset: usize, data: &[T]) {
+        assert!(offset + data.len() <= self.len, "write past the end of a storage buffer");
+        let offset = (offset * std::mem::size_of::<T>()) as wgpu::BufferAddress;
+        queue.write_buffer(&self.buffer, offset, bytemuck::cast_slice(data));
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // copies the buffer into a staging one as part of the work already
+    // submitted to `queue`. empty buffers are done right away
+    pub fn read(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Readback<T> {
+        if self.is_empty() {
+            return Readback::empty();
+        }
+        let size = (self.len * std::mem::size_of::<T>()) as wgpu::BufferAddress;
+        let staging = create_staging_buffer(device, size);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Readback Encoder"),
+        });
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &staging, 0, size);
+        queue.submit(std::iter::once(encoder.finish()));
+        Readback::new(staging, None)
+    }
+}
+
+// a texture compute shaders write to, also sampleable and copyable
+pub struct StorageTexture {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub format: wgpu::TextureFormat,
+    pub size: (u32, u32),
+}
+
+impl StorageTexture {
+    // `format` has to support storage use, like Rgba8Unorm, Rgba16Float or R32Float
+    pub fn new(device: &wgpu::Device, label: &str, width: u32, height: u32, format: wgpu::TextureFormat) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        Self { texture, view, format, size: (width, height) }
+    }
+
+    pub fn binding(&self) -> wgpu::BindingResource<'_> {
+        wgpu::BindingResource::TextureView(&self.view)
+    }
+
+    // the texels, rows tightly packed
+    pub fn read(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Readback<u8> {
+        read_texture(device, queue, &self.texture, self.format, self.size)
+    }
+}
+
+// the texels of the first mip of a 2d texture with COPY_SRC usage, rows
+// tightly packed, as of the work already submitted to `queue`
+pub fn read_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    format: wgpu::TextureFormat,
+    (width, height): (u32, u32),
+) -> Readback<u8> {
+    let unpadded = width * format.describe().block_size as u32;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padded = unpadded.div_ceil(align) * align;
+    let staging = create_staging_buffer(device, padded as u64 * height as u64);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &staging,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(padded),
+                rows_per_image: std::num::NonZeroU32::new(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+    Readback::new(staging, Some((padded as usize, unpadded as usize)))
+}
+
+fn create_staging_buffer(device: &wgpu::Device, size: wgpu::BufferAddress) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback Buffer"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+// gpu data on its way back to the cpu. like all mapping, it only makes
+// progress while the device is polled (or by itself on the web)
+pub struct Readback<T: bytemuck::Pod> {
+    // none when there is nothing to read
+    staging: Option<wgpu::Buffer>,
+    receiver: oneshot::Receiver<Result<(), wgpu::BufferAsyncError>>,
+    // padded and unpadded bytes per row of texture copies
+    rows: Option<(usize, usize)>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> Readback<T> {
+    fn new(staging: wgpu::Buffer, rows: Option<(usize, usize)>) -> Self {
+        let (sender, receiver) = oneshot::channel();
+        staging.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        Self { staging: Some(staging), receiver, rows, _marker: PhantomData }
+    }
+
+    // already arrived, with no data
+    fn empty() -> Self {
+        let (sender, receiver) = oneshot::channel();
+        let _ = sender.send(Result::Ok(()));
+        Self { staging: None, receiver, rows: None, _marker: PhantomData }
+    }
+
+    // without blocking, the data once it has arrived. keep calling
+    // `device.poll(wgpu::Maintain::Poll)`, e.g. once a frame
+    pub fn try_take(&mut self) -> Option<Result<Vec<T>>> {
+        match self.receiver.try_recv() {
+            Result::Ok(Some(result)) => Some(self.finish(result)),
+            Result::Ok(None) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+
+    // waits for the data, blocking on the device outside the web
+    pub async fn wait(mut self, device: &wgpu::Device) -> Result<Vec<T>> {
+        if !cfg!(target_arch = "wasm32") {
+            device.poll(wgpu::Maintain::Wait);
+        }
+        let result = (&mut self.receiver).await?;
+        self.finish(result)
+    }
+
+    fn finish(&self, result: Result<(), wgpu::BufferAsyncError>) -> Result<Vec<T>> {
+        result?;
+        let Some(staging) = &self.staging else {
+            return Ok(Vec::new());
+        };
+        let data = {
+            let mapped = staging.slice(..).get_mapped_range();
+            match self.rows {
+                Some((padded, unpadded)) => {
+                    let mut bytes = Vec::with_capacity(mapped.len() / padded * unpadded);
+                    for row in mapped.chunks(padded) {
+                        bytes.extend_from_slice(&row[..unpadded]);
+                    }
+                    bytemuck::cast_slice(&bytes).to_vec()
+                }
+                None => bytemuck::cast_slice(&mapped).to_vec(),
+            }
+        };
+        staging.unmap();
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOUBLE: &str = "
+@group(0) @binding(0)
+var<storage, read_write> values: array<u32>;
+
+@compute @workgroup_size(64)
+fn cs_double(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x < arrayLength(&values) {
+        values[id.x] = values[id.x] * 2u;
+    }
+}
+";
+
+    // none when the adapter can't run compute shaders
+    fn device() -> Option<(wgpu::Device, wgpu::Queue)> {
+        let (adapter, device, queue) = match futures::executor::block_on(request_headless_device()) {
+            Result::Ok(gpu) => gpu,
+            Err(e) => {
+                eprintln!("skipping, no adapter: {:?}", e);
+                return None;
+            }
+        };
+        if !adapter.get_downlevel_capabilities().flags.contains(wgpu::DownlevelFlags::COMPUTE_SHADERS) {
+            eprintln!("skipping, no compute shaders");
+            return None;
+        }
+        Some((device, queue))
+    }
+
+    #[test]
+    fn dispatch_and_read_back() {
+        let Some((device, queue)) = device() else { return };
+        let kernel = ComputeKernel::new(&device, "Double", DOUBLE, "cs_double").unwrap();
+        assert_eq!(kernel.workgroup_size, [64, 1, 1]);
+        // more than one workgroup, with a partial last one
+        let data: Vec<u32> = (0..100).collect();
+        let values = StorageBuffer::new(&device, "Values", &data, wgpu::BufferUsages::empty());
+        let bind_group = kernel.create_bind_group(&device, 0, &[(0, values.binding())]);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Test Encoder"),
+        });
+        kernel.dispatch(&mut encoder, &[&bind_group], [values.len() as u32, 1, 1]);
+        queue.submit(std::iter::once(encoder.finish()));
+        let result = futures::executor::block_on(values.read(&device, &queue).wait(&device)).unwrap();
+        assert_eq!(result, data.iter().map(|v| v * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn read_empty_buffer() {
+        let Some((device, queue)) = device() else { return };
+        let values = StorageBuffer::<u32>::new(&device, "Empty", &[], wgpu::BufferUsages::empty());
+        let mut readback = values.read(&device, &queue);
+        assert_eq!(readback.try_take().unwrap().unwrap(), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,292 @@
+use cgmath::{InnerSpace, Matrix4, Point3, SquareMatrix, Vector3, Vector4};
+use wgpu::util::DeviceExt;
+
+use crate::buffer::DynamicBuffer;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DebugVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 4],
+}
+
+impl DebugVertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] =
+        wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x4];
+
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<DebugVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+pub const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+pub const GREEN: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
+pub const BLUE: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+pub const YELLOW: [f32; 4] = [1.0, 1.0, 0.0, 1.0];
+pub const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+// immediate mode line drawing: primitives are collected during update,
+// uploaded in `prepare` and forgotten after the frame is drawn
+pub struct DebugDraw {
+    // only created when the pass has a depth attachment
+    depth_pipeline: Option<wgpu::RenderPipeline>,
+    overlay_pipeline: wgpu::RenderPipeline,
+    camera_buffer: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+    depth_test: bool,
+    // line list vertices, two per line
+    depth_lines: Vec<DebugVertex>,
+    overlay_lines: Vec<DebugVertex>,
+    vertex_buffer: DynamicBuffer,
+    // vertex counts uploaded by the last `prepare`
+    depth_count: u32,
+    overlay_count: u32,
+}
+
+impl DebugDraw {
+    const CIRCLE_SEGMENTS: usize = 32;
+
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        depth_format: Option<wgpu::TextureFormat>,
+        sample_count: u32,
+    ) -> Self {
+        let camera_bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("debug_camera_bind_group_layout"),
+            }
+        );
+        let identity: [[f32; 4]; 4] = Matrix4::identity().into();
+        let camera_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Debug Camera Buffer"),
+                contents: bytemuck::cast_slice(&identity),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &camera_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+            label: Some("debug_camera_bind_group"),
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Debug Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("debug.wgsl").into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Debug Pipeline Layout"),
+            bind_group_layouts: &[&camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |depth_compare: wgpu::CompareFunction| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Debug Pipeline"),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[DebugVertex::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: color_format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    ..Default::default()
+                },
+                // debug lines read depth but never occlude the scene
+                depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
+                    format,
+                    depth_write_enabled: false,
+                    depth_compare,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    ..Default::default()
+                },
+                multiview: None,
+            })
+        };
+        let depth_pipeline = depth_format.map(|_| create_pipeline(wgpu::CompareFunction::LessEqual));
+        let overlay_pipeline = create_pipeline(wgpu::CompareFunction::Always);
+        let vertex_buffer = DynamicBuffer::new(
+            device,
+            "Debug Vertex Buffer",
+            wgpu::BufferUsages::VERTEX,
+            1024 * std::mem::size_of::<DebugVertex>() as wgpu::BufferAddress,
+        );
+        Self {
+            depth_pipeline,
+            overlay_pipeline,
+            camera_buffer,
+            camera_bind_group,
+            depth_test: false,
+            depth_lines: Vec::new(),
+            overlay_lines: Vec::new(),
+            vertex_buffer,
+            depth_count: 0,
+            overlay_count: 0,
+        }
+    }
+
+    pub fn set_view_proj(&mut self, queue: &wgpu::Queue, view_proj: Matrix4<f32>) {
+        let view_proj: [[f32; 4]; 4] = view_proj.into();
+        queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&view_proj));
+    }
+
+    // whether following primitives are hidden behind scene geometry;
+    // ignored when there is no depth attachment
+    pub fn set_depth_test(&mut self, enabled: bool) {
+        self.depth_test = enabled;
+    }
+
+    pub fn line<P: Into<Point3<f32>>>(&mut self, a: P, b: P, color: [f32; 4]) {
+        let lines = if self.depth_test && self.depth_pipeline.is_some() {
+            &mut self.depth_lines
+        } else {
+            &mut self.overlay_lines
+        };
+        lines.push(DebugVertex { position: a.into().into(), color });
+        lines.push(DebugVertex { position: b.into().into(), color });
+    }
+
+    pub fn aabb<P: Into<Point3<f32>>>(&mut self, min: P, max: P, color: [f32; 4]) {
+        let (min, max) = (min.into(), max.into());
+        let corner = |i: usize| Point3::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        );
+        self.box_edges(&std::array::from_fn::<_, 8, _>(corner), color);
+    }
+
+    // three great circles, one per axis plane
+    pub fn sphere<P: Into<Point3<f32>>>(&mut self, center: P, radius: f32, color: [f32; 4]) {
+        let center = center.into();
+        self.circle(center, Vector3::unit_x() * radius, Vector3::unit_y() * radius, color);
+        self.circle(center, Vector3::unit_y() * radius, Vector3::unit_z() * radius, color);
+        self.circle(center, Vector3::unit_z() * radius, Vector3::unit_x() * radius, color);
+    }
+
+    // square grid on the xz plane with `divisions` cells per side
+    pub fn grid<P: Into<Point3<f32>>>(&mut self, center: P, size: f32, divisions: u32, color: [f32; 4]) {
+        let center = center.into();
+        let half = size / 2.0;
+        let divisions = divisions.max(1);
+        for i in 0..=divisions {
+            let t = -half + size * i as f32 / divisions as f32;
+            self.line(center + Vector3::new(t, 0.0, -half), center + Vector3::new(t, 0.0, half), color);
+            self.line(center + Vector3::new(-half, 0.0, t), center + Vector3::new(half, 0.0, t), color);
+        }
+    }
+
+    // x, y and z axes of `transform` in red, green and blue
+    pub fn axes(&mut self, transform: Matrix4<f32>, size: f32) {
+        let origin = Point3::from_homogeneous(transform * Vector4::unit_w());
+        for (axis, color) in [(Vector4::unit_x(), RED), (Vector4::unit_y(), GREEN), (Vector4::unit_z(), BLUE)] {
+            let dir = (transform * axis).truncate();
+            if dir.magnitude2() > 0.0 {
+                self.line(origin, origin + dir.normalize() * size, color);
+            }
+        }
+    }
+
+    // the volume seen by a camera, from the inverse of its view projection
+    pub fn frustum(&mut self, view_proj: Matrix4<f32>, color: [f32; 4]) {
+        let inverse = match view_proj.invert() {
+            Some(inverse) => inverse,
+            None => return,
+        };
+        // wgpu clip space has depth in 0..1
+        let corner = |i: usize| {
+            let ndc = Vector4::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { 0.0 } else { 1.0 },
+                1.0,
+            );
+            Point3::from_homogeneous(inverse * ndc)
+        };
+        self.box_edges(&std::array::from_fn::<_, 8, _>(corner), color);
+    }
+
+    // upload this frame's primitives and start collecting the next frame
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.depth_count = self.depth_lines.len() as u32;
+        self.overlay_count = self.overlay_lines.len() as u32;
+        if self.depth_count + self.overlay_count > 0 {
+            let mut vertices = std::mem::take(&mut self.depth_lines);
+            vertices.append(&mut self.overlay_lines);
+            self.vertex_buffer.write_slice(device, queue, &vertices);
+            // keep the allocation around for the next frame
+            vertices.clear();
+            self.depth_lines = vertices;
+        }
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if self.depth_count + self.overlay_count == 0 {
+            return;
+        }
+        render_pass.set_bind_group(0, &self.camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice());
+        if let Some(pipeline) = &self.depth_pipeline {
+            if self.depth_count > 0 {
+                render_pass.set_pipeline(pipeline);
+                render_pass.draw(0..self.depth_count, 0..1);
+            }
+        }
+        if self.overlay_count > 0 {
+            render_pass.set_pipeline(&self.overlay_pipeline);
+            render_pass.draw(self.depth_count..self.depth_count + self.overlay_count, 0..1);
+        }
+    }
+
+    fn circle(&mut self, center: Point3<f32>, u: Vector3<f32>, v: Vector3<f32>, color: [f32; 4]) {
+        let point = |i: usize| {
+            let angle = i as f32 / Self::CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+            center + u * angle.cos() + v * angle.sin()
+        };
+        for i in 0..Self::CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    // corners indexed by bit: 1 = +x, 2 = +y, 4 = +z
+    fn box_edges(&mut self, corners: &[Point3<f32>; 8], color: [f32; 4]) {
+        const EDGES: [(usize, usize); 12] = [
+            (0, 1), (2, 3), (4, 5), (6, 7),
+            (0, 2), (1, 3), (4, 6), (5, 7),
+            (0, 4), (1, 5), (2, 6), (3, 7),
+        ];
+        for (a, b) in EDGES {
+            self.line(corners[a], corners[b], color);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// alternative ways of shading the scene, for inspecting meshes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    Shaded,
+    Wireframe,
+    // texture coordinates as red and green
+    Uvs,
+    // a distinct flat color per vertex index
+    VertexIndex,
+}
+
+impl DebugView {
+    pub fn next(self) -> Self {
+        match self {
+            DebugView::Shaded => DebugView::Wireframe,
+            DebugView::Wireframe => DebugView::Uvs,
+            DebugView::Uvs => DebugView::VertexIndex,
+            DebugView::VertexIndex => DebugView::Shaded,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DebugViewSettings {
+    pub view: DebugView,
+    pub cull_mode: Option<wgpu::Face>,
+    // whether the device was created with `POLYGON_MODE_LINE`
+    pub line_mode_supported: bool,
+}
+
+impl DebugViewSettings {
+    pub fn new(line_mode_supported: bool) -> Self {
+        Self {
+            view: DebugView::Shaded,
+            cull_mode: Some(wgpu::Face::Back),
+            line_mode_supported,
+        }
+    }
+
+    pub fn next_cull_mode(&self) -> Option<wgpu::Face> {
+        match self.cull_mode {
+            Some(wgpu::Face::Back) => Some(wgpu::Face::Front),
+            Some(wgpu::Face::Front) => None,
+            None => Some(wgpu::Face::Back),
+        }
+    }
+
+    // without line polygon mode the wireframe is drawn by the fragment shader
+    // from barycentric coordinates, which needs un-indexed triangles
+    pub fn uses_barycentric_wireframe(&self) -> bool {
+        self.view == DebugView::Wireframe && !self.line_mode_supported
+    }
+
+    pub fn primitive_state(&self) -> wgpu::PrimitiveState {
+        let polygon_mode = if self.view == DebugView::Wireframe && self.line_mode_supported {
+            wgpu::PolygonMode::Line
+        } else {
+            wgpu::PolygonMode::Fill
+        };
+        wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: self.cull_mode,
+            polygon_mode,
+            unclipped_depth: false,
+            conservative: false,
+        }
+    }
+
+    pub fn vertex_entry_point(&self) -> &'static str {
+        if self.uses_barycentric_wireframe() {
+            "vs_barycentric"
+        } else {
+            "vs_main"
+        }
+    }
+
+    // `shaded` is the entry point used when no debug view is active
+    pub fn fragment_entry_point(&self, shaded: &'static str) -> &'static str {
+        match self.view {
+            DebugView::Shaded => shaded,
+            DebugView::Wireframe if self.line_mode_supported => "fs_wireframe",
+            DebugView::Wireframe => "fs_wireframe_barycentric",
+            DebugView::Uvs => "fs_uvs",
+            DebugView::VertexIndex => "fs_vertex_index",
+        }
+    }
+}
+
+// one vertex per index, so every triangle owns its three corners
+pub fn expand_triangles<V: Copy>(vertices: &[V], indices: &[u16]) -> Vec<V> {
+    indices.iter().map(|&i| vertices[i as usize]).collect()
+}
@@ -0,0 +1,407 @@
+use std::path::Path;
+
+use anyhow::*;
+use cgmath::{InnerSpace, Vector3};
+use wgpu::util::DeviceExt;
+
+// the environment cubemaps are hdr, like the scene they light
+pub const ENVIRONMENT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+pub const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EnvironmentSettings {
+    // per face size of the environment cubemap, which gets a full mip chain
+    pub size: u32,
+    pub irradiance_size: u32,
+    // the prefiltered map's first mip is for roughness 0, its last for 1
+    pub prefiltered_size: u32,
+    pub prefiltered_mip_count: u32,
+    // the irradiance integral takes steps² samples per texel
+    pub irradiance_steps: u32,
+    pub prefilter_sample_count: u32,
+    pub brdf_lut_size: u32,
+    pub brdf_lut_sample_count: u32,
+}
+
+impl Default for EnvironmentSettings {
+    fn default() -> Self {
+        Self {
+            size: 512,
+            irradiance_size: 32,
+            prefiltered_size: 128,
+            prefiltered_mip_count: 5,
+            irradiance_steps: 64,
+            prefilter_sample_count: 256,
+            brdf_lut_size: 256,
+            brdf_lut_sample_count: 512,
+        }
+    }
+}
+
+// must match the Params struct in environment.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct Params {
+    face: u32,
+    sample_count: u32,
+    roughness: f32,
+    source_size: f32,
+    sun: [f32; 4],
+}
+
+// what a preprocessing pass reads
+enum Source<'a> {
+    None,
+    Cube(&'a wgpu::TextureView),
+    Equirect(&'a wgpu::TextureView),
+}
+
+// an environment cubemap for the skybox and the maps derived from it for
+// image based lighting: diffuse irradiance, ggx prefiltered radiance and the
+// brdf lut of the split sum approximation
+pub struct Environment {
+    pub cube_view: wgpu::TextureView,
+    pub irradiance_view: wgpu::TextureView,
+    pub prefiltered_view: wgpu::TextureView,
+    pub brdf_lut_view: wgpu::TextureView,
+    pub prefiltered_mip_count: u32,
+    // kept alive for the views
+    _textures: Vec<wgpu::Texture>,
+}
+
+impl Environment {
+    // black everywhere, lights nothing. doesn't need any rendering
+    pub fn empty(device: &wgpu::Device) -> Self {
+        let cube = create_cube(device, "Empty Environment", 1, 1);
+        let brdf_lut = create_brdf_lut_texture(device, 1);
+        Self {
+            cube_view: cube_view(&cube, 0, 1),
+            irradiance_view: cube_view(&cube, 0, 1),
+            prefiltered_view: cube_view(&cube, 0, 1),
+            brdf_lut_view: brdf_lut.create_view(&wgpu::TextureViewDescriptor::default()),
+            prefiltered_mip_count: 1,
+            _textures: vec![cube, brdf_lut],
+        }
+    }
+
+    // a daylight gradient sky with a sun towards `sun_direction`
+    pub fn procedural_sky(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sun_direction: Vector3<f32>,
+        sun_intensity: f32,
+        settings: &EnvironmentSettings,
+    ) -> Self {
+        let baker = Baker::new(device);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+        let cube = create_cube(device, "Environment", settings.size, mip_count(settings.size));
+        let pipeline = baker.pipeline(device, "fs_sky", ENVIRONMENT_FORMAT);
+        let sun = sun_direction.normalize().extend(sun_intensity);
+        for face in 0..6 {
+            let params = Params { face, sun: sun.into(), ..Default::default() };
+            baker.draw(device, &mut encoder, &pipeline, &face_view(&cube, face, 0), params, Source::None);
+        }
+        Self::bake(device, queue, &baker, encoder, cube, settings)
+    }
+
+    // from a latitude-longitude panorama
+    pub fn from_equirectangular(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &image::Rgba32FImage,
+        settings: &EnvironmentSettings,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: image.width(),
+            height: image.height(),
+            depth_or_array_layers: 1,
+        };
+        let equirect = device.create_texture_with_data(queue, &wgpu::TextureDescriptor {
+            label: Some("Equirectangular Environment"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        }, bytemuck::cast_slice(image.as_raw()));
+        let equirect_view = equirect.create_view(&wgpu::TextureViewDescriptor::default());
+        let baker = Baker::new(device);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+        let cube = create_cube(device, "Environment", settings.size, mip_count(settings.size));
+        let pipeline = baker.pipeline(device, "fs_equirect_to_cube", ENVIRONMENT_FORMAT);
+        for face in 0..6 {
+            let params = Params { face, ..Default::default() };
+            baker.draw(device, &mut encoder, &pipeline, &face_view(&cube, face, 0), params,
+                Source::Equirect(&equirect_view));
+        }
+        Self::bake(device, queue, &baker, encoder, cube, settings)
+    }
+
+    // a .hdr (radiance) or any other image the image crate reads, as a panorama
+    pub fn load<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+        settings: &EnvironmentSettings,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .with_context(|| format!("loading environment {}", path.display()))?
+            .to_rgba32f();
+        Ok(Self::from_equirectangular(device, queue, &image, settings))
+    }
+
+    // fills the environment's mips and derives the lighting maps from it
+    fn bake(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        baker: &Baker,
+        mut encoder: wgpu::CommandEncoder,
+        cube: wgpu::Texture,
+        settings: &EnvironmentSettings,
+    ) -> Self {
+        let cube_mips = mip_count(settings.size);
+        let downsample = baker.pipeline(device, "fs_downsample", ENVIRONMENT_FORMAT);
+        for mip in 1..cube_mips {
+            let source = cube_view(&cube, mip - 1, 1);
+            for face in 0..6 {
+                let params = Params { face, ..Default::default() };
+                baker.draw(device, &mut encoder, &downsample, &face_view(&cube, face, mip), params,
+                    Source::Cube(&source));
+            }
+        }
+        let full_cube = cube_view(&cube, 0, cube_mips);
+
+        let irradiance = create_cube(device, "Irradiance", settings.irradiance_size, 1);
+        let pipeline = baker.pipeline(device, "fs_irradiance", ENVIRONMENT_FORMAT);
+        for face in 0..6 {
+            let params = Params {
+                face,
+                sample_count: settings.irradiance_steps,
+                source_size: settings.size as f32,
+                ..Default::default()
+            };
+            baker.draw(device, &mut encoder, &pipeline, &face_view(&irradiance, face, 0), params,
+                Source::Cube(&full_cube));
+        }
+
+        let prefiltered_mip_count = settings.prefiltered_mip_count
+            .clamp(1, mip_count(settings.prefiltered_size));
+        let prefiltered = create_cube(device, "Prefiltered Environment", settings.prefiltered_size, prefiltered_mip_count);
+        let pipeline = baker.pipeline(device, "fs_prefilter", ENVIRONMENT_FORMAT);
+        for mip in 0..prefiltered_mip_count {
+            let roughness = if prefiltered_mip_count > 1 {
+                mip as f32 / (prefiltered_mip_count - 1) as f32
+            } else {
+                0.0
+            };
+            for face in 0..6 {
+                let params = Params {
+                    face,
+                    sample_count: settings.prefilter_sample_count,
+                    roughness,
+                    source_size: settings.size as f32,
+                    ..Default::default()
+                };
+                baker.draw(device, &mut encoder, &pipeline, &face_view(&prefiltered, face, mip), params,
+                    Source::Cube(&full_cube));
+            }
+        }
+
+        let brdf_lut = create_brdf_lut_texture(device, settings.brdf_lut_size);
+        let brdf_lut_view = brdf_lut.create_view(&wgpu::TextureViewDescriptor::default());
+        let pipeline = baker.pipeline(device, "fs_brdf_lut", BRDF_LUT_FORMAT);
+        let params = Params { sample_count: settings.brdf_lut_sample_count, ..Default::default() };
+        baker.draw(device, &mut encoder, &pipeline, &brdf_lut_view, params, Source::None);
+
+        queue.submit(std::iter::once(encoder.finish()));
+        Self {
+            cube_view: full_cube,
+            irradiance_view: cube_view(&irradiance, 0, 1),
+            prefiltered_view: cube_view(&prefiltered, 0, prefiltered_mip_count),
+            brdf_lut_view,
+            prefiltered_mip_count,
+            _textures: vec![cube, irradiance, prefiltered, brdf_lut],
+        }
+    }
+}
+
+// the preprocessing shader and the sampler its passes use
+struct Baker {
+    shader: wgpu::ShaderModule,
+    sampler: wgpu::Sampler,
+}
+
+impl Baker {
+    fn new(device: &wgpu::Device) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Environment Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("environment.wgsl").into()),
+        });
+        Self {
+            shader,
+            sampler: create_sampler(device),
+        }
+    }
+
+    // the layout is derived from what the entry point uses
+    fn pipeline(&self, device: &wgpu::Device, entry_point: &str, format: wgpu::TextureFormat) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(entry_point),
+            layout: None,
+            vertex: wgpu::VertexState {
+                module: &self.shader,
+                entry_point: "vs_fullscreen",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &self.shader,
+                entry_point,
+                targets: &[Some(format.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    fn draw(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        target: &wgpu::TextureView,
+        params: Params,
+        source: Source,
+    ) {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Params"),
+            contents: bytemuck::bytes_of(&params),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: buffer.as_entire_binding(),
+        }];
+        match source {
+            Source::None => {}
+            Source::Cube(view) => {
+                entries.push(wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(view),
+                });
+                entries.push(wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                });
+            }
+            Source::Equirect(view) => {
+                entries.push(wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(view),
+                });
+            }
+        }
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+            label: Some("environment_bind_group"),
+        });
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Environment Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+// trilinear and clamped, for sampling any of the environment maps
+pub fn create_sampler(device: &wgpu::Device) -> wgpu::Sampler {
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("Environment Sampler"),
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    })
+}
+
+fn mip_count(size: u32) -> u32 {
+    32 - size.max(1).leading_zeros()
+}
+
+fn create_cube(device: &wgpu::Device, label: &str, size: u32, mip_level_count: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: ENVIRONMENT_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    })
+}
+
+fn create_brdf_lut_texture(device: &wgpu::Device, size: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("BRDF LUT"),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: BRDF_LUT_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    })
+}
+
+fn cube_view(texture: &wgpu::Texture, base_mip_level: u32, mip_level_count: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        base_mip_level,
+        mip_level_count: std::num::NonZeroU32::new(mip_level_count),
+        array_layer_count: std::num::NonZeroU32::new(6),
+        ..Default::default()
+    })
+}
+
+// a single face and mip to render into
+fn face_view(texture: &wgpu::Texture, face: u32, mip: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_mip_level: mip,
+        mip_level_count: std::num::NonZeroU32::new(1),
+        base_array_layer: face,
+        array_layer_count: std::num::NonZeroU32::new(1),
+        ..Default::default()
+    })
+}
@@ -0,0 +1,207 @@
+// Environment map preprocessing, every pass draws a fullscreen triangle
+// into one face of a cubemap (or into the brdf lut)
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // 0,0 at the top left like texel rows
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let x = f32(i32(index & 1u) * 4 - 1);
+    let y = f32(i32(index >> 1u) * 4 - 1);
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.uv = vec2<f32>(x * 0.5 + 0.5, 0.5 - y * 0.5);
+    return out;
+}
+
+struct Params {
+    face: u32,
+    sample_count: u32,
+    roughness: f32,
+    // per face size of the source cubemap's first mip
+    source_size: f32,
+    // procedural sky only, direction towards the sun and its intensity
+    sun: vec4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var t_cube: texture_cube<f32>;
+@group(0) @binding(2)
+var s_cube: sampler;
+// rgba32float can't be filtered, it is read with textureLoad
+@group(0) @binding(3)
+var t_equirect: texture_2d<f32>;
+
+const PI: f32 = 3.14159265359;
+
+// the direction through a texel of a cube face, following the webgpu
+// (and d3d/vulkan) face orientation
+fn cube_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+    let u = uv.x * 2.0 - 1.0;
+    let v = uv.y * 2.0 - 1.0;
+    var dir: vec3<f32>;
+    switch face {
+        case 0u: { dir = vec3<f32>(1.0, -v, -u); }
+        case 1u: { dir = vec3<f32>(-1.0, -v, u); }
+        case 2u: { dir = vec3<f32>(u, 1.0, v); }
+        case 3u: { dir = vec3<f32>(u, -1.0, -v); }
+        case 4u: { dir = vec3<f32>(u, -v, 1.0); }
+        default: { dir = vec3<f32>(-u, -v, -1.0); }
+    }
+    return normalize(dir);
+}
+
+// an orthonormal basis around `n`, for turning hemisphere samples
+fn tangent_frame(n: vec3<f32>) -> mat3x3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(n.y) > 0.999 {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    return mat3x3<f32>(tangent, bitangent, n);
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// a half vector around +z distributed like ggx with roughness^2 = alpha
+fn importance_sample_ggx(xi: vec2<f32>, alpha: f32) -> vec3<f32> {
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (alpha * alpha - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+fn distribution_ggx(n_dot_h: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+@fragment
+fn fs_equirect_to_cube(in: VertexOutput) -> @location(0) vec4<f32> {
+    let dir = cube_direction(params.face, in.uv);
+    let size = vec2<f32>(textureDimensions(t_equirect));
+    let uv = vec2<f32>(atan2(dir.z, dir.x) / (2.0 * PI) + 0.5, acos(clamp(dir.y, -1.0, 1.0)) / PI);
+    let texel = clamp(vec2<i32>(uv * size), vec2<i32>(0), vec2<i32>(size) - 1);
+    return vec4<f32>(textureLoad(t_equirect, texel, 0).rgb, 1.0);
+}
+
+// a simple daylight sky, bright towards the horizon, with a sun disk
+@fragment
+fn fs_sky(in: VertexOutput) -> @location(0) vec4<f32> {
+    let dir = cube_direction(params.face, in.uv);
+    let zenith = vec3<f32>(0.15, 0.3, 0.7);
+    let horizon = vec3<f32>(0.7, 0.8, 0.95);
+    let ground = vec3<f32>(0.25, 0.22, 0.2);
+    var color: vec3<f32>;
+    if dir.y >= 0.0 {
+        color = mix(horizon, zenith, pow(dir.y, 0.5));
+    } else {
+        color = mix(horizon, ground, pow(-dir.y, 0.3));
+    }
+    let sun = normalize(params.sun.xyz);
+    let sun_amount = smoothstep(0.9995, 0.9998, dot(dir, sun));
+    let glow = pow(max(dot(dir, sun), 0.0), 64.0) * 0.5;
+    color += (sun_amount + glow) * params.sun.w;
+    return vec4<f32>(color, 1.0);
+}
+
+// one mip from the previous one, t_cube is a view of just that mip
+@fragment
+fn fs_downsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    let dir = cube_direction(params.face, in.uv);
+    return textureSampleLevel(t_cube, s_cube, dir, 0.0);
+}
+
+// cosine weighted hemisphere integral for diffuse lighting
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n = cube_direction(params.face, in.uv);
+    let frame = tangent_frame(n);
+    // a coarse mip is plenty for such a blurry result
+    let lod = max(log2(params.source_size / 32.0), 0.0);
+    var irradiance = vec3<f32>(0.0);
+    let steps = params.sample_count;
+    for (var i = 0u; i < steps; i += 1u) {
+        for (var j = 0u; j < steps; j += 1u) {
+            let phi = 2.0 * PI * (f32(i) + 0.5) / f32(steps);
+            let theta = 0.5 * PI * (f32(j) + 0.5) / f32(steps);
+            let local = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let color = textureSampleLevel(t_cube, s_cube, frame * local, lod).rgb;
+            irradiance += color * cos(theta) * sin(theta);
+        }
+    }
+    irradiance = PI * irradiance / f32(steps * steps);
+    return vec4<f32>(irradiance, 1.0);
+}
+
+// ggx prefiltered radiance for the split sum approximation, assuming the
+// view direction equals the normal. samples come from lower mips where the
+// pdf is low to avoid fireflies
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n = cube_direction(params.face, in.uv);
+    if params.roughness <= 0.0 {
+        return textureSampleLevel(t_cube, s_cube, n, 0.0);
+    }
+    let frame = tangent_frame(n);
+    let alpha = params.roughness * params.roughness;
+    let texel_solid_angle = 4.0 * PI / (6.0 * params.source_size * params.source_size);
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < params.sample_count; i += 1u) {
+        let h = frame * importance_sample_ggx(hammersley(i, params.sample_count), alpha);
+        let l = normalize(2.0 * dot(n, h) * h - n);
+        let n_dot_l = dot(n, l);
+        if n_dot_l > 0.0 {
+            let n_dot_h = max(dot(n, h), 0.0);
+            // with v = n the pdf of l is d / 4
+            let pdf = distribution_ggx(n_dot_h, alpha) / 4.0 + 0.0001;
+            let sample_solid_angle = 1.0 / (f32(params.sample_count) * pdf);
+            let lod = max(0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0, 0.0);
+            color += textureSampleLevel(t_cube, s_cube, l, lod).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+    return vec4<f32>(color / max(weight, 0.0001), 1.0);
+}
+
+fn geometry_schlick_ggx_ibl(n_dot_x: f32, roughness: f32) -> f32 {
+    let k = roughness * roughness / 2.0;
+    return n_dot_x / (n_dot_x * (1.0 - k) + k);
+}
+
+// scale and bias to f0 of the specular brdf integral, by n.v (x) and
+// roughness (y)
+@fragment
+fn fs_brdf_lut(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n_dot_v = max(in.uv.x, 0.001);
+    let roughness = in.uv.y;
+    let alpha = roughness * roughness;
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < params.sample_count; i += 1u) {
+        let h = importance_sample_ggx(hammersley(i, params.sample_count), alpha);
+        let l = normalize(2.0 * dot(v, h) * h - v);
+        let n_dot_l = max(l.z, 0.0);
+        let n_dot_h = max(h.z, 0.0);
+        let v_dot_h = max(dot(v, h), 0.0);
+        if n_dot_l > 0.0 {
+            let g = geometry_schlick_ggx_ibl(n_dot_v, roughness) * geometry_schlick_ggx_ibl(n_dot_l, roughness);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fc = pow(1.0 - v_dot_h, 5.0);
+            scale += (1.0 - fc) * g_vis;
+            bias += fc * g_vis;
+        }
+    }
+    let count = f32(params.sample_count);
+    return vec4<f32>(scale / count, bias / count, 0.0, 1.0);
+}
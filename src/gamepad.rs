@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use cgmath::{InnerSpace, Vector2};
+use serde::{Deserialize, Serialize};
+
+// buttons by position on an xbox style layout, south is A and east is B
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    // the triggers pulled past their actuation point, the analog value is
+    // the axis of the same name
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+// sticks are -1..1 with +y up, triggers 0..1
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    RightStickX,
+    RightStickY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Stick {
+    Left,
+    Right,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+// stays the same while the pad is connected
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct GamepadId(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadEventKind {
+    Connected,
+    Disconnected,
+    ButtonPressed(GamepadButton),
+    ButtonReleased(GamepadButton),
+    // the raw value, before deadzones
+    AxisChanged(GamepadAxis, f32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GamepadEvent {
+    pub id: GamepadId,
+    pub kind: GamepadEventKind,
+}
+
+// values closer to rest than these read as rest, and the rest of the range
+// is stretched so movement still starts from 0. they are clamped to
+// `MAX_DEADZONE`, so there is always some range left to stretch
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Deadzones {
+    // of the distance from the stick's center
+    pub stick: f32,
+    pub trigger: f32,
+}
+
+impl Default for Deadzones {
+    fn default() -> Self {
+        Self {
+            stick: 0.15,
+            trigger: 0.05,
+        }
+    }
+}
+
+pub const MAX_DEADZONE: f32 = 0.99;
+
+impl Deadzones {
+    // radial, so small diagonal movements aren't snapped to an axis
+    pub fn apply_stick(&self, value: Vector2<f32>) -> Vector2<f32> {
+        let deadzone = self.stick.clamp(0.0, MAX_DEADZONE);
+        let length = value.magnitude();
+        if length <= deadzone {
+            return Vector2::new(0.0, 0.0);
+        }
+        let scaled = ((length - deadzone) / (1.0 - deadzone)).min(1.0);
+        value * (scaled / length)
+    }
+
+    pub fn apply_trigger(&self, value: f32) -> f32 {
+        let deadzone = self.trigger.clamp(0.0, MAX_DEADZONE);
+        if value <= deadzone {
+            return 0.0;
+        }
+        ((value - deadzone) / (1.0 - deadzone)).min(1.0)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct PadState {
+    buttons: HashSet<GamepadButton>,
+    axes: HashMap<GamepadAxis, f32>,
+}
+
+// the current state of every connected pad, kept up to date from events
+#[derive(Clone, Debug, Default)]
+pub struct Gamepads {
+    pub deadzones: Deadzones,
+    pads: BTreeMap<GamepadId, PadState>,
+}
+
+impl Gamepads {
+    pub fn new(deadzones: Deadzones) -> Self {
+        Self {
+            deadzones,
+            pads: BTreeMap::new(),
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &GamepadEvent) {
+        match event.kind {
+            GamepadEventKind::Connected => {
+                self.pads.entry(event.id).or_default();
+            }
+            GamepadEventKind::Disconnected => {
+                self.pads.remove(&event.id);
+            }
+            GamepadEventKind::ButtonPressed(button) => {
+                self.pads.entry(event.id).or_default().buttons.insert(button);
+            }
+            GamepadEventKind::ButtonReleased(button) => {
+                if let Some(pad) = self.pads.get_mut(&event.id) {
+                    pad.buttons.remove(&button);
+                }
+            }
+            GamepadEventKind::AxisChanged(axis, value) => {
+                self.pads.entry(event.id).or_default().axes.insert(axis, value);
+            }
+        }
+    }
+
+    // ordered by id
+    pub fn connected(&self) -> impl Iterator<Item = GamepadId> + '_ {
+        self.pads.keys().copied()
+    }
+
+    pub fn is_connected(&self, id: GamepadId) -> bool {
+        self.pads.contains_key(&id)
+    }
+
+    pub fn button_held(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.pads.get(&id).is_some_and(|pad| pad.buttons.contains(&button))
+    }
+
+    // the raw value, 0 for unknown pads
+    pub fn axis(&self, id: GamepadId, axis: GamepadAxis) -> f32 {
+        self.pads.get(&id).and_then(|pad| pad.axes.get(&axis)).copied().unwrap_or(0.0)
+    }
+
+    // with the stick deadzone applied
+    pub fn stick(&self, id: GamepadId, stick: Stick) -> Vector2<f32> {
+        let (x, y) = match stick {
+            Stick::Left => (GamepadAxis::LeftStickX, GamepadAxis::LeftStickY),
+            Stick::Right => (GamepadAxis::RightStickX, GamepadAxis::RightStickY),
+        };
+        self.deadzones.apply_stick(Vector2::new(self.axis(id, x), self.axis(id, y)))
+    }
+
+    // with the trigger deadzone applied
+    pub fn trigger(&self, id: GamepadId, side: Side) -> f32 {
+        let axis = match side {
+            Side::Left => GamepadAxis::LeftTrigger,
+            Side::Right => GamepadAxis::RightTrigger,
+        };
+        self.deadzones.apply_trigger(self.axis(id, axis))
+    }
+}
+
+// where gamepad events come from, polled once per frame
+pub trait GamepadBackend {
+    // appends the events since the previous poll
+    fn poll(&mut self, events: &mut Vec<GamepadEvent>);
+}
+
+#[derive(Debug, Default)]
+struct VirtualQueue {
+    events: Vec<GamepadEvent>,
+    next_id: usize,
+}
+
+// a backend fed by code instead of devices, for tests and scripted input.
+// clones share the same queue, so one can be handed to `run_app` while the
+// others keep connecting pads
+#[derive(Clone, Debug, Default)]
+pub struct VirtualGamepads {
+    queue: Arc<Mutex<VirtualQueue>>,
+}
+
+impl VirtualGamepads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect(&self) -> VirtualGamepad {
+        let mut queue = self.queue.lock().unwrap();
+        let id = GamepadId(queue.next_id);
+        queue.next_id += 1;
+        queue.events.push(GamepadEvent { id, kind: GamepadEventKind::Connected });
+        VirtualGamepad { id, queue: self.queue.clone() }
+    }
+}
+
+impl GamepadBackend for VirtualGamepads {
+    fn poll(&mut self, events: &mut Vec<GamepadEvent>) {
+        events.append(&mut self.queue.lock().unwrap().events);
+    }
+}
+
+// one simulated pad, its changes show up at the next poll
+#[derive(Clone, Debug)]
+pub struct VirtualGamepad {
+    id: GamepadId,
+    queue: Arc<Mutex<VirtualQueue>>,
+}
+
+impl VirtualGamepad {
+    pub fn id(&self) -> GamepadId {
+        self.id
+    }
+
+    pub fn press(&self, button: GamepadButton) {
+        self.send(GamepadEventKind::ButtonPressed(button));
+    }
+
+    pub fn release(&self, button: GamepadButton) {
+        self.send(GamepadEventKind::ButtonReleased(button));
+    }
+
+    pub fn set_axis(&self, axis: GamepadAxis, value: f32) {
+        self.send(GamepadEventKind::AxisChanged(axis, value));
+    }
+
+    pub fn disconnect(self) {
+        self.send(GamepadEventKind::Disconnected);
+    }
+
+    fn send(&self, kind: GamepadEventKind) {
+        self.queue.lock().unwrap().events.push(GamepadEvent { id: self.id, kind });
+    }
+}
+
+// the devices connected to the machine, through gilrs when the `gamepad`
+// feature is enabled. none otherwise or if they can't be opened
+pub fn default_backend() -> Option<Box<dyn GamepadBackend>> {
+    #[cfg(feature = "gamepad")]
+    match GilrsBackend::new() {
+        Ok(backend) => return Some(Box::new(backend)),
+        Err(e) => eprintln!("{:?}", e),
+    }
+    None
+}
+
+#[cfg(feature = "gamepad")]
+pub use gilrs_backend::GilrsBackend;
+
+#[cfg(feature = "gamepad")]
+mod gilrs_backend {
+    use anyhow::*;
+    use gilrs::{Axis, Button, EventType, Gilrs};
+
+    use super::{GamepadAxis, GamepadBackend, GamepadButton, GamepadEvent, GamepadEventKind, GamepadId};
+
+    pub struct GilrsBackend {
+        gilrs: Gilrs,
+        // pads that were connected before the first poll
+        pending: Vec<GamepadEvent>,
+    }
+
+    impl GilrsBackend {
+        pub fn new() -> Result<Self> {
+            let gilrs = Gilrs::new().map_err(|e| anyhow!("can't open gamepads: {}", e))?;
+            let pending = gilrs.gamepads()
+                .map(|(id, _)| GamepadEvent { id: GamepadId(id.into()), kind: GamepadEventKind::Connected })
+                .collect();
+            Ok(Self { gilrs, pending })
+        }
+    }
+
+    impl GamepadBackend for GilrsBackend {
+        fn poll(&mut self, events: &mut Vec<GamepadEvent>) {
+            events.append(&mut self.pending);
+            while let Some(event) = self.gilrs.next_event() {
+                let kind = match event.event {
+                    EventType::Connected => Some(GamepadEventKind::Connected),
+                    EventType::Disconnected => Some(GamepadEventKind::Disconnected),
+                    EventType::ButtonPressed(button, _) => map_button(button).map(GamepadEventKind::ButtonPressed),
+                    EventType::ButtonReleased(button, _) => map_button(button).map(GamepadEventKind::ButtonReleased),
+                    // gilrs reports analog triggers as buttons with a value
+                    EventType::ButtonChanged(Button::LeftTrigger2, value, _) => {
+                        Some(GamepadEventKind::AxisChanged(GamepadAxis::LeftTrigger, value))
+                    }
+                    EventType::ButtonChanged(Button::RightTrigger2, value, _) => {
+                        Some(GamepadEventKind::AxisChanged(GamepadAxis::RightTrigger, value))
+                    }
+                    EventType::AxisChanged(axis, value, _) => {
+                        map_axis(axis).map(|axis| GamepadEventKind::AxisChanged(axis, value))
+                    }
+                    _ => None,
+                };
+                if let Some(kind) = kind {
+                    events.push(GamepadEvent { id: GamepadId(event.id.into()), kind });
+                }
+            }
+        }
+    }
+
+    fn map_button(button: Button) -> Option<GamepadButton> {
+        Some(match button {
+            Button::South => GamepadButton::South,
+            Button::East => GamepadButton::East,
+            Button::North => GamepadButton::North,
+            Button::West => GamepadButton::West,
+            Button::LeftTrigger => GamepadButton::LeftBumper,
+            Button::RightTrigger => GamepadButton::RightBumper,
+            Button::LeftTrigger2 => GamepadButton::LeftTrigger,
+            Button::RightTrigger2 => GamepadButton::RightTrigger,
+            Button::Select => GamepadButton::Select,
+            Button::Start => GamepadButton::Start,
+            Button::Mode => GamepadButton::Mode,
+            Button::LeftThumb => GamepadButton::LeftThumb,
+            Button::RightThumb => GamepadButton::RightThumb,
+            Button::DPadUp => GamepadButton::DPadUp,
+            Button::DPadDown => GamepadButton::DPadDown,
+            Button::DPadLeft => GamepadButton::DPadLeft,
+            Button::DPadRight => GamepadButton::DPadRight,
+            _ => return None,
+        })
+    }
+
+    // the d-pad also reports buttons, and z axes are triggers on some
+    // pads that report them as buttons too
+    fn map_axis(axis: Axis) -> Option<GamepadAxis> {
+        match axis {
+            Axis::LeftStickX => Some(GamepadAxis::LeftStickX),
+            Axis::LeftStickY => Some(GamepadAxis::LeftStickY),
+            Axis::RightStickX => Some(GamepadAxis::RightStickX),
+            Axis::RightStickY => Some(GamepadAxis::RightStickY),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stick_deadzone_edges() {
+        let deadzones = Deadzones { stick: 0.2, trigger: 0.0 };
+        assert_eq!(deadzones.apply_stick(Vector2::new(0.0, 0.0)), Vector2::new(0.0, 0.0));
+        assert_eq!(deadzones.apply_stick(Vector2::new(0.0, -0.2)), Vector2::new(0.0, 0.0));
+        let past = deadzones.apply_stick(Vector2::new(0.0, -0.21));
+        assert!(past.y < 0.0 && past.y > -0.05 && past.x == 0.0);
+        let full = deadzones.apply_stick(Vector2::new(1.0, 0.0));
+        assert!((full.x - 1.0).abs() < 1e-6 && full.y == 0.0);
+        // pads report corners past the unit circle, the direction is kept
+        let corner = deadzones.apply_stick(Vector2::new(1.0, 1.0));
+        assert!((corner.magnitude() - 1.0).abs() < 1e-6);
+        assert!((corner.x - corner.y).abs() < 1e-6);
+    }
+
+    #[test]
+    fn trigger_deadzone_edges() {
+        let deadzones = Deadzones { stick: 0.0, trigger: 0.1 };
+        assert_eq!(deadzones.apply_trigger(0.0), 0.0);
+        assert_eq!(deadzones.apply_trigger(0.1), 0.0);
+        assert!((deadzones.apply_trigger(0.55) - 0.5).abs() < 1e-6);
+        assert_eq!(deadzones.apply_trigger(1.0), 1.0);
+        assert_eq!(deadzones.apply_trigger(1.5), 1.0);
+    }
+
+    #[test]
+    fn full_deadzones_stay_finite() {
+        let deadzones = Deadzones { stick: 1.0, trigger: 1.0 };
+        assert_eq!(deadzones.apply_trigger(1.0), 1.0);
+        assert_eq!(deadzones.apply_trigger(0.5), 0.0);
+        let full = deadzones.apply_stick(Vector2::new(0.0, 1.0));
+        assert!((full.y - 1.0).abs() < 1e-6);
+        assert_eq!(deadzones.apply_stick(Vector2::new(0.5, 0.0)), Vector2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn disconnecting_forgets_the_pad() {
+        let mut gamepads = Gamepads::default();
+        let id = GamepadId(0);
+        gamepads.handle_event(&GamepadEvent { id, kind: GamepadEventKind::ButtonPressed(GamepadButton::South) });
+        gamepads.handle_event(&GamepadEvent { id, kind: GamepadEventKind::AxisChanged(GamepadAxis::LeftTrigger, 1.0) });
+        assert!(gamepads.button_held(id, GamepadButton::South));
+        assert_eq!(gamepads.trigger(id, Side::Left), 1.0);
+        gamepads.handle_event(&GamepadEvent { id, kind: GamepadEventKind::Disconnected });
+        assert!(!gamepads.is_connected(id));
+        assert!(!gamepads.button_held(id, GamepadButton::South));
+        assert_eq!(gamepads.trigger(id, Side::Left), 0.0);
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::*;
+use winit::{dpi::PhysicalSize, window::Window};
+
+use crate::compute::{self, Readback};
+use crate::tonemap;
+
+// what the device and surface are created with
+#[derive(Clone, Debug)]
+pub struct GpuSettings {
+    // enabled where the adapter supports them
+    pub optional_features: wgpu::Features,
+    // use an extended range surface format where the display has one
+    pub prefer_hdr_output: bool,
+}
+
+impl Default for GpuSettings {
+    fn default() -> Self {
+        Self {
+            optional_features: wgpu::Features::empty(),
+            prefer_hdr_output: false,
+        }
+    }
+}
+
+// a texture to draw a frame into, shown once it is `present`ed
+pub struct Frame {
+    pub view: wgpu::TextureView,
+    surface_texture: Option<wgpu::SurfaceTexture>,
+}
+
+impl Frame {
+    pub fn present(self) {
+        if let Some(surface_texture) = self.surface_texture {
+            surface_texture.present();
+        }
+    }
+}
+
+// where frames go, a window's surface or an offscreen texture. the surface
+// is dropped before the window it was created from
+enum Target {
+    Surface { surface: wgpu::Surface, window: Window },
+    Headless(wgpu::Texture),
+}
+
+// the device and queue plus what frames are drawn into. pipelines created
+// against `device` can be shared by everything using the context
+pub struct GpuContext {
+    pub instance: wgpu::Instance,
+    pub adapter: wgpu::Adapter,
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    target: Target,
+    config: wgpu::SurfaceConfiguration,
+    hdr_output: bool,
+}
+
+impl GpuContext {
+    // presenting to `window`, which the context keeps until it is dropped
+    pub async fn new(window: Window, settings: &GpuSettings) -> Result<Self> {
+        let size = window.inner_size();
+        // the instance is a handle to the GPU
+        let instance = create_instance();
+        // create the surface to present to. safe as the window is moved into
+        // the context and outlives the surface
+        let surface = unsafe { instance.create_surface(&window) }?;
+        let adapter = request_adapter(&instance, Some(&surface)).await?;
+        let mut limits = wgpu::Limits::default();
+        if cfg!(target_arch = "wasm32") {
+            limits = wgpu::Limits::downlevel_webgl2_defaults();
+        }
+        let (device, queue) = request_device(&adapter, settings, limits).await?;
+        // configure the surface
+        let surface_caps = surface.get_capabilities(&adapter);
+        if surface_caps.formats.is_empty() {
+            bail!("the surface isn't supported by the adapter");
+        }
+        let (surface_format, hdr_output) = tonemap::select_surface_format(
+            &surface_caps.formats, settings.prefer_hdr_output);
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: surface_format,
+            width: size.width.max(1),
+            height: size.height.max(1),
+            present_mode: surface_caps.present_modes[0],
+            alpha_mode: surface_caps.alpha_modes[0],
+            view_formats: vec![],
+        };
+        surface.configure(&device, &config);
+        Ok(Self {
+            instance,
+            adapter,
+            device,
+            queue,
+            target: Target::Surface { surface, window },
+            config,
+            hdr_output,
+        })
+    }
+
+    // drawing into a texture of `format` instead of a window, for tools and
+    // tests. falls back to a software adapter, like `compute::request_headless_device`
+    pub async fn headless(size: PhysicalSize<u32>, format: wgpu::TextureFormat, settings: &GpuSettings) -> Result<Self> {
+        let instance = create_instance();
+        let adapter = request_adapter(&instance, None).await?;
+        let limits = wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits());
+        let (device, queue) = request_device(&adapter, settings, limits).await?;
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format,
+            width: size.width.max(1),
+            height: size.height.max(1),
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+        };
+        let target = Target::Headless(create_headless_texture(&device, &config));
+        Ok(Self {
+            instance,
+            adapter,
+            device,
+            queue,
+            target,
+            config,
+            hdr_output: false,
+        })
+    }
+
+    pub fn surface_format(&self) -> wgpu::TextureFormat {
+        self.config.format
+    }
+
+    pub fn size(&self) -> PhysicalSize<u32> {
+        PhysicalSize::new(self.config.width, self.config.height)
+    }
+
+    // the surface configuration, also describing the headless target
+    pub fn config(&self) -> &wgpu::SurfaceConfiguration {
+        &self.config
+    }
+
+    // whether the surface format takes extended range colors
+    pub fn hdr_output(&self) -> bool {
+        self.hdr_output
+    }
+
+    // None for headless contexts
+    pub fn window(&self) -> Option<&Window> {
+        match &self.target {
+            Target::Surface { window, .. } => Some(window),
+            Target::Headless(_) => None,
+        }
+    }
+
+    pub fn is_headless(&self) -> bool {
+        matches!(self.target, Target::Headless(_))
+    }
+
+    // false, changing nothing, for empty sizes like those of minimized windows
+    pub fn resize(&mut self, new_size: PhysicalSize<u32>) -> bool {
+        if new_size.width == 0 || new_size.height == 0 {
+            return false;
+        }
+        self.config.width = new_size.width;
+        self.config.height = new_size.height;
+        match &mut self.target {
+            Target::Surface { surface, .. } => surface.configure(&self.device, &self.config),
+            Target::Headless(texture) => *texture = create_headless_texture(&self.device, &self.config),
+        }
+        true
+    }
+
+    // the texture for the next frame. a lost or outdated surface is
+    // reconfigured and tried once more
+    pub fn acquire_frame(&mut self) -> Result<Frame, wgpu::SurfaceError> {
+        let surface = match &self.target {
+            Target::Surface { surface, .. } => surface,
+            Target::Headless(texture) => {
+                return Result::Ok(Frame {
+                    view: texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                    surface_texture: None,
+                });
+            }
+        };
+        let surface_texture = match surface.get_current_texture() {
+            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                surface.configure(&self.device, &self.config);
+                surface.get_current_texture()
+            }
+            result => result,
+        }?;
+        Result::Ok(Frame {
+            view: surface_texture.texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            surface_texture: Some(surface_texture),
+        })
+    }
+
+    // what was last drawn into the headless target, rows tightly packed
+    pub fn read_frame(&self) -> Option<Readback<u8>> {
+        match &self.target {
+            Target::Headless(texture) => Some(compute::read_texture(
+                &self.device, &self.queue, texture, self.config.format, (self.config.width, self.config.height))),
+            Target::Surface { .. } => None,
+        }
+    }
+}
+
+pub(crate) fn create_instance() -> wgpu::Instance {
+    wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: wgpu::util::backend_bits_from_env().unwrap_or_else(wgpu::Backends::all),
+        dx12_shader_compiler: Default::default(),
+    })
+}
+
+// a software adapter is used when there is no hardware one
+pub(crate) async fn request_adapter(instance: &wgpu::Instance, surface: Option<&wgpu::Surface>) -> Result<wgpu::Adapter> {
+    for force_fallback_adapter in [false, true] {
+        let options = wgpu::RequestAdapterOptions {
+            power_preference: wgpu::util::power_preference_from_env().unwrap_or_default(),
+            compatible_surface: surface,
+            force_fallback_adapter,
+        };
+        if let Some(adapter) = instance.request_adapter(&options).await {
+            return Ok(adapter);
+        }
+    }
+    bail!("no suitable adapter, hardware or software")
+}
+
+async fn request_device(
+    adapter: &wgpu::Adapter,
+    settings: &GpuSettings,
+    limits: wgpu::Limits,
+) -> Result<(wgpu::Device, wgpu::Queue)> {
+    let desc = wgpu::DeviceDescriptor {
+        features: adapter.features() & settings.optional_features,
+        limits,
+        label: None,
+    };
+    Ok(adapter.request_device(&desc, None).await?)
+}
+
+fn create_headless_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Headless Target"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
@@ -0,0 +1,417 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use winit::event::{ElementState, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode, WindowEvent};
+
+use crate::gamepad::{GamepadAxis, GamepadButton, GamepadEvent, GamepadEventKind, GamepadId};
+
+// how far an axis has to move to hold an action bound to it
+pub const AXIS_THRESHOLD: f32 = 0.5;
+
+// a physical input an action can be bound to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    // the key with this meaning in the current layout
+    Key(VirtualKeyCode),
+    // the key at this position, whatever the layout
+    Scancode(u32),
+    Mouse(MouseButton),
+    // on any pad
+    Gamepad(GamepadButton),
+    // an axis past `AXIS_THRESHOLD` in one direction
+    #[serde(rename = "gamepad_axis")]
+    GamepadAxis { axis: GamepadAxis, positive: bool },
+}
+
+impl Trigger {
+    // gamepad triggers ignore keyboard modifiers
+    fn is_gamepad(&self) -> bool {
+        matches!(self, Trigger::Gamepad(_) | Trigger::GamepadAxis { .. })
+    }
+
+    fn is_modifier(&self) -> bool {
+        use VirtualKeyCode::*;
+        matches!(self, Trigger::Key(LShift | RShift | LControl | RControl | LAlt | RAlt | LWin | RWin))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub logo: bool,
+}
+
+impl Modifiers {
+    fn is_none(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl From<ModifiersState> for Modifiers {
+    fn from(state: ModifiersState) -> Self {
+        Self {
+            shift: state.shift(),
+            ctrl: state.ctrl(),
+            alt: state.alt(),
+            logo: state.logo(),
+        }
+    }
+}
+
+// a trigger together with the modifiers that have to be held, no more and
+// no less. in json e.g. { "key": "S", "modifiers": { "ctrl": true } }
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Binding {
+    #[serde(flatten)]
+    pub trigger: Trigger,
+    #[serde(default, skip_serializing_if = "Modifiers::is_none")]
+    pub modifiers: Modifiers,
+}
+
+impl Binding {
+    pub fn key(key: VirtualKeyCode) -> Self {
+        Trigger::Key(key).into()
+    }
+
+    pub fn scancode(scancode: u32) -> Self {
+        Trigger::Scancode(scancode).into()
+    }
+
+    pub fn mouse(button: MouseButton) -> Self {
+        Trigger::Mouse(button).into()
+    }
+
+    pub fn gamepad(button: GamepadButton) -> Self {
+        Trigger::Gamepad(button).into()
+    }
+
+    pub fn gamepad_axis(axis: GamepadAxis, positive: bool) -> Self {
+        Trigger::GamepadAxis { axis, positive }.into()
+    }
+
+    pub fn with_modifiers(self, modifiers: Modifiers) -> Self {
+        Self { modifiers, ..self }
+    }
+}
+
+impl From<Trigger> for Binding {
+    fn from(trigger: Trigger) -> Self {
+        Self { trigger, modifiers: Modifiers::default() }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct ActionState {
+    // the triggers keeping the action held, and the pads they're on
+    held_by: Vec<(Trigger, Option<GamepadId>)>,
+    pressed: bool,
+    released: bool,
+}
+
+// named actions bound to keys, mouse buttons and gamepads. window events go
+// through `handle_event` and gamepad events through `handle_gamepad_event`,
+// and the edges seen by `pressed` and `released` last until
+// `end_frame`
+#[derive(Clone, Debug, Default)]
+pub struct InputMap {
+    bindings: BTreeMap<String, Vec<Binding>>,
+    states: HashMap<String, ActionState>,
+    modifiers: Modifiers,
+    rebinding: Option<String>,
+}
+
+impl InputMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // an object of action names to lists of bindings
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(Self {
+            bindings: serde_json::from_str(json)?,
+            ..Self::default()
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self.bindings)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read bindings {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("invalid bindings {}", path.display()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    pub fn actions(&self) -> impl Iterator<Item = &str> {
+        self.bindings.keys().map(String::as_str)
+    }
+
+    pub fn bindings(&self, action: &str) -> &[Binding] {
+        self.bindings.get(action).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn bind(&mut self, action: &str, binding: Binding) {
+        let bindings = self.bindings.entry(action.to_string()).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    pub fn set_bindings(&mut self, action: &str, bindings: Vec<Binding>) {
+        self.release(action);
+        self.bindings.insert(action.to_string(), bindings);
+    }
+
+    pub fn unbind(&mut self, action: &str, binding: &Binding) {
+        if let Some(bindings) = self.bindings.get_mut(action) {
+            bindings.retain(|b| b != binding);
+        }
+    }
+
+    // the next key or button pressed, with the modifiers held, replaces
+    // the bindings of `action` instead of triggering anything
+    pub fn rebind_next(&mut self, action: &str) {
+        self.rebinding = Some(action.to_string());
+    }
+
+    // the action waiting for `rebind_next` input
+    pub fn rebinding(&self) -> Option<&str> {
+        self.rebinding.as_deref()
+    }
+
+    pub fn cancel_rebind(&mut self) {
+        self.rebinding = None;
+    }
+
+    // since the last `end_frame`
+    pub fn pressed(&self, action: &str) -> bool {
+        self.states.get(action).is_some_and(|state| state.pressed)
+    }
+
+    pub fn held(&self, action: &str) -> bool {
+        self.states.get(action).is_some_and(|state| !state.held_by.is_empty())
+    }
+
+    // since the last `end_frame`
+    pub fn released(&self, action: &str) -> bool {
+        self.states.get(action).is_some_and(|state| state.released)
+    }
+
+    pub fn end_frame(&mut self) {
+        for state in self.states.values_mut() {
+            state.pressed = false;
+            state.released = false;
+        }
+    }
+
+    // true if the event was bound to an action or used for rebinding
+    pub fn handle_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::ModifiersChanged(state) => {
+                self.modifiers = (*state).into();
+                false
+            }
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput { scancode, virtual_keycode, state, .. },
+                ..
+            } => {
+                let key = virtual_keycode.map(Trigger::Key);
+                let triggers: Vec<_> = key.into_iter().chain([Trigger::Scancode(*scancode)]).collect();
+                self.handle_triggers(&triggers, *state == ElementState::Pressed, None)
+            }
+            WindowEvent::MouseInput { button, state, .. } => {
+                self.handle_triggers(&[Trigger::Mouse(*button)], *state == ElementState::Pressed, None)
+            }
+            // keys released while unfocused never report it
+            WindowEvent::Focused(false) => {
+                let held: Vec<_> = self.states.iter()
+                    .filter(|(_, state)| !state.held_by.is_empty())
+                    .map(|(action, _)| action.clone())
+                    .collect();
+                for action in held {
+                    self.release(&action);
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    // true if the event was bound to an action or used for rebinding
+    pub fn handle_gamepad_event(&mut self, event: &GamepadEvent) -> bool {
+        let pad = Some(event.id);
+        match event.kind {
+            GamepadEventKind::ButtonPressed(button) => self.handle_triggers(&[Trigger::Gamepad(button)], true, pad),
+            GamepadEventKind::ButtonReleased(button) => self.handle_triggers(&[Trigger::Gamepad(button)], false, pad),
+            GamepadEventKind::AxisChanged(axis, value) => {
+                let positive = Trigger::GamepadAxis { axis, positive: true };
+                let negative = Trigger::GamepadAxis { axis, positive: false };
+                // crossing zero releases one direction before pressing the other
+                let (release, press) = if value >= 0.0 { (negative, positive) } else { (positive, negative) };
+                let released = self.handle_triggers(&[release], false, pad);
+                let pressed = self.handle_triggers(&[press], value.abs() >= AXIS_THRESHOLD, pad);
+                released || pressed
+            }
+            GamepadEventKind::Disconnected => {
+                for state in self.states.values_mut() {
+                    let held = !state.held_by.is_empty();
+                    state.held_by.retain(|(_, p)| *p != pad);
+                    state.released |= held && state.held_by.is_empty();
+                }
+                false
+            }
+            GamepadEventKind::Connected => false,
+        }
+    }
+
+    // the first trigger is the preferred one to rebind to
+    fn handle_triggers(&mut self, triggers: &[Trigger], pressed: bool, pad: Option<GamepadId>) -> bool {
+        if pressed {
+            if let Some(action) = self.rebinding.take() {
+                // modifiers wait for the key they're held with
+                if triggers[0].is_modifier() {
+                    self.rebinding = Some(action);
+                    return true;
+                }
+                let mut binding = Binding::from(triggers[0]);
+                if !binding.trigger.is_gamepad() {
+                    binding.modifiers = self.modifiers;
+                }
+                self.set_bindings(&action, vec![binding]);
+                return true;
+            }
+        }
+        let mut used = false;
+        for (action, bindings) in &self.bindings {
+            for binding in bindings.iter().filter(|b| triggers.contains(&b.trigger)) {
+                let state = self.states.entry(action.clone()).or_default();
+                let held = (binding.trigger, pad);
+                if pressed {
+                    if !binding.trigger.is_gamepad() && binding.modifiers != self.modifiers {
+                        continue;
+                    }
+                    // key repeats and axis movements don't press again
+                    if !state.held_by.contains(&held) {
+                        state.pressed |= state.held_by.is_empty();
+                        state.held_by.push(held);
+                    }
+                    used = true;
+                } else if let Some(i) = state.held_by.iter().position(|h| *h == held) {
+                    state.held_by.swap_remove(i);
+                    state.released |= state.held_by.is_empty();
+                    used = true;
+                }
+            }
+        }
+        used
+    }
+
+    fn release(&mut self, action: &str) {
+        if let Some(state) = self.states.get_mut(action) {
+            if !state.held_by.is_empty() {
+                state.held_by.clear();
+                state.released = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn axis(map: &mut InputMap, id: usize, axis: GamepadAxis, value: f32) -> bool {
+        map.handle_gamepad_event(&GamepadEvent { id: GamepadId(id), kind: GamepadEventKind::AxisChanged(axis, value) })
+    }
+
+    fn button(map: &mut InputMap, id: usize, button: GamepadButton, pressed: bool) {
+        let kind = if pressed {
+            GamepadEventKind::ButtonPressed(button)
+        } else {
+            GamepadEventKind::ButtonReleased(button)
+        };
+        map.handle_gamepad_event(&GamepadEvent { id: GamepadId(id), kind });
+    }
+
+    fn stick_map() -> InputMap {
+        let mut map = InputMap::new();
+        map.bind("left", Binding::gamepad_axis(GamepadAxis::LeftStickX, false));
+        map.bind("right", Binding::gamepad_axis(GamepadAxis::LeftStickX, true));
+        map
+    }
+
+    #[test]
+    fn rebinding_skips_modifiers() {
+        let mut map = InputMap::new();
+        map.bind("save", Binding::key(VirtualKeyCode::S));
+        map.rebind_next("save");
+        map.modifiers = Modifiers { ctrl: true, ..Modifiers::default() };
+        assert!(map.handle_triggers(&[Trigger::Key(VirtualKeyCode::LControl), Trigger::Scancode(29)], true, None));
+        assert_eq!(map.rebinding(), Some("save"));
+        assert!(map.handle_triggers(&[Trigger::Key(VirtualKeyCode::P), Trigger::Scancode(25)], true, None));
+        assert_eq!(map.rebinding(), None);
+        assert_eq!(map.bindings("save"), &[Binding {
+            trigger: Trigger::Key(VirtualKeyCode::P),
+            modifiers: Modifiers { ctrl: true, ..Modifiers::default() },
+        }]);
+    }
+
+    #[test]
+    fn axis_below_threshold_does_nothing() {
+        let mut map = stick_map();
+        assert!(!axis(&mut map, 0, GamepadAxis::LeftStickX, AXIS_THRESHOLD * 0.5));
+        assert!(!map.held("right") && !map.pressed("right"));
+    }
+
+    #[test]
+    fn axis_crossing_zero() {
+        let mut map = stick_map();
+        assert!(axis(&mut map, 0, GamepadAxis::LeftStickX, 0.8));
+        assert!(map.pressed("right") && map.held("right") && !map.held("left"));
+        map.end_frame();
+
+        // moving further doesn't press again
+        axis(&mut map, 0, GamepadAxis::LeftStickX, 1.0);
+        assert!(!map.pressed("right") && map.held("right"));
+
+        axis(&mut map, 0, GamepadAxis::LeftStickX, -0.8);
+        assert!(map.released("right") && !map.held("right"));
+        assert!(map.pressed("left") && map.held("left"));
+        map.end_frame();
+
+        axis(&mut map, 0, GamepadAxis::LeftStickX, 0.0);
+        assert!(map.released("left") && !map.held("left") && !map.held("right"));
+    }
+
+    #[test]
+    fn disconnect_releases_held_actions() {
+        let mut map = InputMap::new();
+        map.bind("jump", Binding::gamepad(GamepadButton::South));
+        map.bind("right", Binding::gamepad_axis(GamepadAxis::LeftStickX, true));
+        button(&mut map, 0, GamepadButton::South, true);
+        button(&mut map, 1, GamepadButton::South, true);
+        axis(&mut map, 0, GamepadAxis::LeftStickX, 1.0);
+        map.end_frame();
+
+        map.handle_gamepad_event(&GamepadEvent { id: GamepadId(0), kind: GamepadEventKind::Disconnected });
+        assert!(map.released("right") && !map.held("right"));
+        // the other pad still holds it
+        assert!(!map.released("jump") && map.held("jump"));
+
+        map.handle_gamepad_event(&GamepadEvent { id: GamepadId(1), kind: GamepadEventKind::Disconnected });
+        assert!(map.released("jump") && !map.held("jump"));
+    }
+}
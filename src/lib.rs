@@ -1,24 +1,46 @@
-use wgpu::{PrimitiveState};
-use wgpu::util::DeviceExt;
-use winit::{
-    event::*,
-    event_loop::{ControlFlow, EventLoop},
-    window::{Window, WindowBuilder},
-};
-use rand::Rng;
-use bytemuck;
-
-mod texture;
+use winit::event::*;
+use rand::{Rng, SeedableRng};
+use cgmath::{Rotation3, SquareMatrix};
+
+pub mod app;
+pub mod atlas;
+pub mod buffer;
+pub mod camera;
+pub mod compute;
+pub mod debug_draw;
+pub mod debug_view;
+pub mod environment;
+pub mod gamepad;
+pub mod gpu;
+pub mod input;
+pub mod lighting;
+pub mod material;
+pub mod msaa;
+pub mod particles;
+pub mod post;
+pub mod render_graph;
+pub mod replay;
+pub mod scene;
+pub mod shadow;
+pub mod skybox;
+pub mod sprite;
+pub mod tangent;
+pub mod texture;
+pub mod timing;
+pub mod tonemap;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-struct Vertex {
-    position: [f32; 3],
-    tex_coords: [f32; 2],
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub tex_coords: [f32; 2],
+    pub normal: [f32; 3],
+    // w is the bitangent's handedness
+    pub tangent: [f32; 4],
 }
 
 impl Vertex {
-    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         let attributes = &[
             wgpu::VertexAttribute { // position
                 offset: 0,
@@ -30,177 +52,255 @@ impl Vertex {
                 shader_location: 1,
                 format: wgpu::VertexFormat::Float32x2,
             },
+            wgpu::VertexAttribute { // normal
+                offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                shader_location: 2,
+                format: wgpu::VertexFormat::Float32x3,
+            },
+            wgpu::VertexAttribute { // tangent
+                offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                shader_location: 3,
+                format: wgpu::VertexFormat::Float32x4,
+            },
         ];
-        let vertex_buffer_layout = wgpu::VertexBufferLayout {
+        wgpu::VertexBufferLayout {
             array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
             step_mode: wgpu::VertexStepMode::Vertex,
-            attributes: attributes,
-        };
-        return vertex_buffer_layout;
+            attributes,
+        }
     }
 }
 
-const VERTICES: &[Vertex] = &[
-    Vertex { position: [-0.50, -0.75, 0.0], tex_coords: [0.4131759, 0.99240386], }, 
-    Vertex { position: [0.50, -0.75, 0.0],  tex_coords: [0.0048659444, 0.56958647], }, 
-    Vertex { position: [0.75, 0.50, 0.0],   tex_coords: [0.28081453, 0.05060294], }, 
-    Vertex { position: [0.00, 1.00, 0.0],   tex_coords: [0.85967, 0.1526709], },
-    Vertex { position: [-0.75, 0.50, 0.0],  tex_coords: [0.9414737, 0.7347359], },
-    Vertex { position: [-0.30, 0.00, 0.0],  tex_coords: [0.4131759, 0.99240386], },
-    Vertex { position: [0.00, -0.30, 0.0],  tex_coords: [0.0048659444, 0.56958647], },
-    Vertex { position: [0.30, 0.00, 0.0],   tex_coords: [0.28081453, 0.05060294], },
-    Vertex { position: [0.25, 0.45, 0.0],   tex_coords: [0.85967, 0.1526709], },
-    Vertex { position: [-0.25, 0.45, 0.0],  tex_coords: [0.9414737, 0.7347359], },
-];
-
-// triangles have their vertices arranged in counter-clockwise order
-const INDICES_PENTAGON: &[u16] = &[
-    0, 1, 2,
-    0, 2, 3,
-    0, 3, 4,
-];
-const INDICES_CHALLENGE: &[u16] = &[
-    0, 7, 9,
-    5, 1, 8,
-    6, 2, 9,
-    5, 7, 3,
-    4, 6, 8,
-];
+// radians per second with the stick all the way to the side
+const ORBIT_SPEED: f32 = 1.5;
+
+// the fragment entry points of shader.wgsl a material can be drawn with
+// unlit, the first one unless its `unlit_shader` names another
+const UNLIT_SHADERS: &[&str] = &["fs_main", "fs_main2"];
 
+// the scene loaded at startup, unless LEARN_WGPU_SCENE names a file. it is
+// built in with its textures, so it loads without a filesystem
+const DEFAULT_SCENE: &str = include_str!("scenes/demo.json");
+
+// the textures of `DEFAULT_SCENE`, by their paths in it
+fn default_scene_texture(path: &str) -> anyhow::Result<Vec<u8>> {
+    let bytes: &[u8] = match path {
+        "../happy-tree.png" => include_bytes!("happy-tree.png"),
+        "../hmm.png" => include_bytes!("hmm.png"),
+        "../bumps-normal.png" => include_bytes!("bumps-normal.png"),
+        _ => anyhow::bail!("{} isn't built in", path),
+    };
+    Ok(bytes.to_vec())
+}
+
+// the meshes of the scene hide each other by depth, whatever order they're drawn in
+fn scene_depth_stencil() -> wgpu::DepthStencilState {
+    wgpu::DepthStencilState {
+        format: texture::DEPTH_FORMAT,
+        depth_write_enabled: true,
+        depth_compare: wgpu::CompareFunction::LessEqual,
+        stencil: wgpu::StencilState::default(),
+        bias: wgpu::DepthBiasState::default(),
+    }
+}
+
+// one pipeline per fragment entry point, built for the current debug view
+fn create_render_pipelines(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    multisample_state: wgpu::MultisampleState,
+    debug_view: &debug_view::DebugViewSettings,
+    fs_entry_points: &[&'static str],
+) -> Vec<wgpu::RenderPipeline> {
+    let blend = if debug_view.uses_barycentric_wireframe() {
+        wgpu::BlendState::ALPHA_BLENDING
+    } else {
+        wgpu::BlendState::REPLACE
+    };
+    fs_entry_points.iter().map(|&fs_entry_point| {
+        let vertex_state = wgpu::VertexState {
+            module: shader,
+            entry_point: debug_view.vertex_entry_point(),
+            buffers: &[Vertex::desc(), scene::InstanceRaw::desc()],
+        };
+        let fragment_state = wgpu::FragmentState {
+            module: shader,
+            entry_point: debug_view.fragment_entry_point(fs_entry_point),
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(blend),
+                write_mask: wgpu::ColorWrites::ALL,
+            })]
+        };
+        let render_pipeline_desc = wgpu::RenderPipelineDescriptor{
+            label: Some("Render Pipeline"),
+            layout: Some(layout),
+            vertex: vertex_state,
+            fragment: Some(fragment_state),
+            primitive: debug_view.primitive_state(),
+            depth_stencil: Some(scene_depth_stencil()),
+            multisample: multisample_state,
+            multiview: None,
+        };
+        device.create_render_pipeline(&render_pipeline_desc)
+    }).collect()
+}
+
+// the blinn-phong and pbr pipelines, drawn instead of the demo ones when lighting is on
+fn create_lit_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    multisample_state: wgpu::MultisampleState,
+    cull_mode: Option<wgpu::Face>,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Lit Render Pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[Vertex::desc(), scene::InstanceRaw::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            cull_mode,
+            ..Default::default()
+        },
+        depth_stencil: Some(scene_depth_stencil()),
+        multisample: multisample_state,
+        multiview: None,
+    })
+}
 
 
 struct State {
-    surface: wgpu::Surface,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
-    config: wgpu::SurfaceConfiguration,
-    size: winit::dpi::PhysicalSize<u32>,
-    window: Window,
     color: wgpu::Color,
+    shader: wgpu::ShaderModule,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    // one per material, with its entry of `UNLIT_SHADERS`
     render_pipelines: Vec<wgpu::RenderPipeline>,
-    render_pipeline_idx: usize,
-    vertex_buffer: wgpu::Buffer,
-    index_buffers: Vec<wgpu::Buffer>,
-    index_buffer_idx: usize,
-    num_indices: u32,
-    bind_group_buffer: Vec<wgpu::BindGroup>,
-    bind_group_buffer_idx: usize,
+    unlit_shaders: Vec<&'static str>,
+    // where the scene was loaded from, saved next to it with ctrl+S. the
+    // built in one is saved to the working directory
+    scene_path: Option<std::path::PathBuf>,
+    // drawn by the mesh nodes of `scene`
+    meshes: Vec<scene::Mesh>,
+    scene: scene::SceneGraph,
+    // the nodes the demo moves, found by name. the shape's mesh and
+    // material are swapped with space
+    shape: Option<scene::NodeId>,
+    // rotated to circle the point light around the shape
+    light_pivot: Option<scene::NodeId>,
+    spot_light: Option<scene::NodeId>,
+    // rotated to orbit the camera around the shape
+    camera_pivot: Option<scene::NodeId>,
+    // the instance data of every draw, rewritten each frame
+    instance_buffer: buffer::DynamicBuffer,
+    draws: Vec<scene::DrawItem>,
+    debug_draw: debug_draw::DebugDraw,
+    show_debug: bool,
+    debug_view: debug_view::DebugViewSettings,
+    msaa: msaa::Msaa,
+    // matches the sample count of `msaa` and the size of the surface
+    depth_texture: texture::Texture,
+    supported_sample_counts: Vec<u32>,
+    transient_pool: render_graph::TransientPool,
+    post: post::PostChain,
+    tone_mapping: tonemap::ToneMapping,
+    camera: camera::Camera,
+    camera_buffer: camera::CameraBuffer,
+    lighting: lighting::Lighting,
+    shadows: shadow::ShadowMaps,
+    skybox: skybox::Skybox,
+    // draw the environment instead of the clear color, toggled with B
+    show_skybox: bool,
+    lit_shader: wgpu::ShaderModule,
+    lit_pipeline_layout: wgpu::PipelineLayout,
+    lit_pipeline: wgpu::RenderPipeline,
+    pbr_shader: wgpu::ShaderModule,
+    pbr_pipeline_layout: wgpu::PipelineLayout,
+    pbr_pipeline: wgpu::RenderPipeline,
+    // one per material of the scene, bound at group 0 by every pipeline
+    materials: Vec<material::Material>,
+    // how the mesh is lit by the scene lights, cycled with L
+    shading: lighting::Shading,
+    particles: Option<particles::ParticleSystem>,
+    show_particles: bool,
+    // what keys and buttons do, from bindings.json
+    input_map: input::InputMap,
+    // seconds of simulated time before and after the latest update, frames
+    // are drawn in between
+    previous_animation_time: f32,
+    animation_time: f32,
+    // seeded from the app context, so replays pick the same colors
+    rng: rand::rngs::StdRng,
 }
 
 impl State {
-    // Creating some of the wgpu types requires async code
-    async fn new(window: Window) -> State {
-        let size = window.inner_size();
-        // the instance is a handle to the GPU
-        let instance = wgpu::Instance::new(
-            wgpu::InstanceDescriptor { 
-                backends: wgpu::Backends::all(), 
-                dx12_shader_compiler: Default::default(),
+    fn new(ctx: &app::AppContext) -> anyhow::Result<State> {
+        let gpu = &ctx.gpu;
+        let (adapter, device, queue, config) = (&gpu.adapter, &gpu.device, &gpu.queue, gpu.config());
+        let size = gpu.size();
+        let hdr_output = gpu.hdr_output();
+        // use 4x msaa when available, it is guaranteed by webgpu for most formats
+        // the scene is drawn into an hdr target and post processed into the surface
+        let scene_format = post::HDR_FORMAT;
+        let supported_sample_counts = msaa::supported_sample_counts(
+            adapter, device.features(), scene_format);
+        let sample_count = if supported_sample_counts.contains(&4) { 4 } else { 1 };
+        let msaa = msaa::Msaa::new(device, config, scene_format, sample_count);
+        let depth_texture = texture::Texture::create_depth_texture(device, config, sample_count, "Depth Texture");
+        let mut post = post::PostChain::with_default_effects(device, queue, config.format);
+        let tone_mapping = tonemap::ToneMapping::new(hdr_output);
+        post.set_tone_mapping(&tone_mapping);
+
+        // the meshes, materials, lights and camera, from a scene file
+        let scene_path = std::env::var_os("LEARN_WGPU_SCENE").map(std::path::PathBuf::from);
+        let (scene_desc, loaded_materials) = match &scene_path {
+            Some(path) => {
+                let desc = scene::SceneDesc::load(path)?;
+                // saves of the default scene keep its texture paths, they
+                // load wherever the file is moved
+                let dir = path.parent().unwrap_or(std::path::Path::new(""));
+                let materials = desc.load_materials_with(|texture| {
+                    std::fs::read(dir.join(texture))
+                        .or_else(|e| default_scene_texture(texture).map_err(|_| e.into()))
+                })?;
+                (desc, materials)
+            }
+            None => {
+                let desc = scene::SceneDesc::from_json(DEFAULT_SCENE)?;
+                let materials = desc.load_materials_with(default_scene_texture)?;
+                (desc, materials)
             }
-        );
-        // create the surface to present to
-        let surface = unsafe { instance.create_surface(&window) }.unwrap();
-        let options = wgpu::RequestAdapterOptions{
-            power_preference: wgpu::PowerPreference::default(),
-            compatible_surface: Some(&surface),
-            force_fallback_adapter: false,
-        };
-        let adapter = instance.request_adapter(&options).await.unwrap();
-        let mut limits = wgpu::Limits::default();
-        if cfg!(target_arch = "wasm32") {
-            limits = wgpu::Limits::downlevel_webgl2_defaults();
-        }
-        let desc = wgpu::DeviceDescriptor {
-            features: wgpu::Features::empty(),
-            limits: limits,
-            label: None,
-        };
-        let (device, queue) = adapter.request_device(&desc, None).await.unwrap();
-        // configure the surface
-        let surface_caps = surface.get_capabilities(&adapter);
-        let surface_format = surface_caps.formats.iter()
-            .copied()
-            .filter(|f| f.describe().srgb)
-            .next()
-            .unwrap_or(surface_caps.formats[0]);
-        let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: surface_format,
-            width: size.width,
-            height: size.height,
-            present_mode: surface_caps.present_modes[0],
-            alpha_mode: surface_caps.alpha_modes[0],
-            view_formats: vec![],
-        };
-        surface.configure(&device, &config);
-
-        // create bind group to describe how textures can be accessed by shader
-        let sampled_texture = wgpu::BindGroupLayoutEntry {
-            binding: 0,
-            visibility: wgpu::ShaderStages::FRAGMENT, // only visible to fragment shader
-            ty: wgpu::BindingType::Texture {
-                multisampled: false,
-                view_dimension: wgpu::TextureViewDimension::D2,
-                sample_type: wgpu::TextureSampleType::Float { filterable: true },
-            },
-            count: None,
-        };
-        let sampler = wgpu::BindGroupLayoutEntry {
-            binding: 1,
-            visibility: wgpu::ShaderStages::FRAGMENT, 
-            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-            count: None,
-        };
-        let bind_group_layout_desc = wgpu::BindGroupLayoutDescriptor {
-            entries: &[sampled_texture, sampler],
-            label: Some("texture_bind_group_layout"),
-        };
-        let texture_bind_group_layout = device.create_bind_group_layout(&bind_group_layout_desc);
-        // texture 1 bind group
-        let texture1 = texture::Texture::from_bytes(
-                &device, &queue, include_bytes!("happy-tree.png"), 
-                "happy-tree.png").unwrap();
-        let bind_group1_entry1 = wgpu::BindGroupEntry {
-            binding: 0,
-            resource: wgpu::BindingResource::TextureView(&texture1.view),
-        };
-        let bind_group1_entry2 = wgpu::BindGroupEntry {
-            binding: 1,
-            resource: wgpu::BindingResource::Sampler(&texture1.sampler),
-        };
-        let bind_group1_desc = wgpu::BindGroupDescriptor {
-            layout: &texture_bind_group_layout,
-            entries: &[bind_group1_entry1, bind_group1_entry2],
-            label: Some("diffuse_bind_group"),
-        };
-        let bind_group1 = device.create_bind_group(&bind_group1_desc);
-        // texture 2 bind group
-        let texture2 = texture::Texture::from_bytes(
-                &device, &queue, include_bytes!("hmm.png"), 
-                "hmm.png").unwrap();
-        let bind_group2_entry1 = wgpu::BindGroupEntry {
-            binding: 0,
-            resource: wgpu::BindingResource::TextureView(&texture2.view),
-        };
-        let bind_group2_entry2 = wgpu::BindGroupEntry {
-            binding: 1,
-            resource: wgpu::BindingResource::Sampler(&texture2.sampler),
-        };
-        let bind_group2_desc = wgpu::BindGroupDescriptor {
-            layout: &texture_bind_group_layout,
-            entries: &[bind_group2_entry1, bind_group2_entry2],
-            label: Some("diffuse_bind_group"),
-        };
-        let bind_group2 = device.create_bind_group(&bind_group2_desc);
-        let bind_group_buffer = vec![bind_group1, bind_group2];
-        let bind_group_buffer_idx = 0;
-        // set a default background color
-        let color = wgpu::Color{
-            r: 1.0, 
-            g: 1.0, 
-            b: 1.0, 
-            a: 1.0
         };
+        let unlit_shaders = scene_desc.materials.iter()
+            .map(|m| match m.unlit_shader.as_deref() {
+                None => Ok(UNLIT_SHADERS[0]),
+                Some(name) => UNLIT_SHADERS.iter().copied().find(|&s| s == name)
+                    .ok_or_else(|| anyhow::anyhow!("unknown unlit shader {} for material {}", name, m.material.name)),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // bound at group 0 by every pipeline, the unlit and blinn-phong ones
+        // only sample the base color texture
+        let material_bind_group_layout = material::Material::create_bind_group_layout(device);
+        let materials = loaded_materials.iter()
+            .map(|loaded| material::Material::new(device, queue, &material_bind_group_layout, loaded))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let [r, g, b, a] = scene_desc.clear_color;
+        let color = wgpu::Color { r, g, b, a };
         // load in shaders
         let shader_source = wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into());
         let shader_desc = wgpu::ShaderModuleDescriptor {
@@ -211,267 +311,700 @@ impl State {
         // create render pipeline
         let render_pipeline_layout_desc = wgpu::PipelineLayoutDescriptor{
             label: Some("Render Pipeline Layout"),
-            bind_group_layouts: &[&texture_bind_group_layout],
+            bind_group_layouts: &[&material_bind_group_layout],
             push_constant_ranges: &[],
         };
         let render_pipeline_layout = device.create_pipeline_layout(&render_pipeline_layout_desc);
-        let vertex_state = wgpu::VertexState {
-            module: &shader,
-            entry_point: "vs_main",
-            buffers: &[Vertex::desc()],
+        let debug_view = debug_view::DebugViewSettings::new(
+            device.features().contains(wgpu::Features::POLYGON_MODE_LINE));
+        let render_pipelines = create_render_pipelines(
+            device, &render_pipeline_layout, &shader, scene_format,
+            msaa.multisample_state(), &debug_view, &unlit_shaders);
+        let meshes = scene_desc.meshes.iter()
+            .map(|desc| scene::Mesh::from_desc(device, desc))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let instance_buffer = buffer::DynamicBuffer::new(
+            device, "Instance Buffer", wgpu::BufferUsages::VERTEX, scene::InstanceRaw::SIZE);
+        // lines drawn on top of the scene, toggled with D
+        let debug_draw = debug_draw::DebugDraw::new(
+            device, scene_format, Some(texture::DEPTH_FORMAT), msaa.sample_count());
+        // the bindings can be replaced with a file named by LEARN_WGPU_BINDINGS
+        let input_map = match std::env::var("LEARN_WGPU_BINDINGS") {
+            Ok(path) => input::InputMap::load(path),
+            Err(_) => input::InputMap::from_json(include_str!("bindings.json")),
+        }?;
+        // camera and lights for the lit pipelines, cycled with L, placed by
+        // the scene's nodes
+        let mut lighting = lighting::Lighting::new(device);
+        let mut scene = scene_desc.graph(&mut lighting.lights);
+        scene.update_world_matrices();
+        scene.place_lights(&mut lighting.lights);
+        let aspect = size.width as f32 / size.height.max(1) as f32;
+        let camera = scene.camera(aspect).unwrap_or_else(|| camera::Camera::new(aspect));
+        let camera_buffer = camera::CameraBuffer::new(device, &camera);
+        let sun_direction = lighting.lights.iter()
+            .find(|light| light.kind == lighting::LightKind::Directional)
+            .map_or(-cgmath::Vector3::unit_z(), |light| light.direction);
+        // a sky matching the first directional light, or with the sun overhead
+        // without one, for the skybox and image based lighting
+        let environment = environment::Environment::procedural_sky(
+            device, queue, -sun_direction, 20.0, &environment::EnvironmentSettings::default());
+        let skybox = skybox::Skybox::new(
+            device, scene_format, Some(texture::DEPTH_FORMAT), msaa.sample_count(),
+            &camera_buffer.bind_group_layout, &environment);
+        lighting.set_environment(device, environment);
+        let shadows = shadow::ShadowMaps::new(device, shadow::ShadowSettings::default(), Vertex::desc());
+        // a fountain of particles toggled with P, where compute shaders are available
+        let particles = if adapter.get_downlevel_capabilities().flags.contains(wgpu::DownlevelFlags::COMPUTE_SHADERS) {
+            let particle_texture = texture::Texture::from_image(
+                device, queue, &particles::soft_dot_image(32), Some("particle"))?;
+            let particles = particles::ParticleSystem::new(
+                device,
+                particles::EmitterDesc::default(),
+                &particle_texture,
+                &camera_buffer.bind_group_layout,
+                scene_format,
+                Some(texture::DEPTH_FORMAT),
+                msaa.sample_count(),
+            )?;
+            Some(particles)
+        } else {
+            None
         };
-        let fragment_state = wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: config.format,
-                blend: Some(wgpu::BlendState::REPLACE),
-                write_mask: wgpu::ColorWrites::ALL,
-            })]
-        };
-        let primitive_state = PrimitiveState {
-            topology: wgpu::PrimitiveTopology::TriangleList,
-            strip_index_format: None,
-            front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
-            polygon_mode: wgpu::PolygonMode::Fill,
-            unclipped_depth: false,
-            conservative: false,
-        };
-        let multisample_state = wgpu::MultisampleState {
-            count: 1,
-            mask: !0,
-            alpha_to_coverage_enabled: false,
-        };
-        let render_pipeline_desc1 = wgpu::RenderPipelineDescriptor{
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: vertex_state,
-            fragment: Some(fragment_state),
-            primitive: primitive_state,
-            depth_stencil: None,
-            multisample: multisample_state,
-            multiview: None,
-        };
-        let render_pipeline1 = device.create_render_pipeline(&render_pipeline_desc1);
-        // challenge render pipeline
-        let vertex_state2 = wgpu::VertexState {
-            module: &shader,
-            entry_point: "vs_main",
-            buffers: &[Vertex::desc()],
-        };
-        let fragment_state2 = wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_main2",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: config.format,
-                blend: Some(wgpu::BlendState::REPLACE),
-                write_mask: wgpu::ColorWrites::ALL,
-            })]
-        };
-        let render_pipeline_desc2 = wgpu::RenderPipelineDescriptor{
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: vertex_state2,
-            fragment: Some(fragment_state2),
-            primitive: primitive_state,
-            depth_stencil: None,
-            multisample: multisample_state,
-            multiview: None,
-        };
-        let render_pipeline2 = device.create_render_pipeline(&render_pipeline_desc2);
-        let render_pipelines = vec![render_pipeline1, render_pipeline2];
-        let render_pipeline_idx = 0;
-        // create the vertex buffer
-        let vertex_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Vertex Buffer"),
-                contents: bytemuck::cast_slice(VERTICES),
-                usage: wgpu::BufferUsages::VERTEX,
-            }
-        );
-        // create the index buffer
-        let index_buffers = vec![
-            device.create_buffer_init(
-                &wgpu::util::BufferInitDescriptor{
-                    label: Some("Index buffer 1"),
-                    contents: bytemuck::cast_slice(INDICES_PENTAGON),
-                    usage: wgpu::BufferUsages::INDEX,
-                }
-            ),
-            device.create_buffer_init(
-                &wgpu::util::BufferInitDescriptor {
-                    label: Some("Index buffer 2"),
-                    contents: bytemuck::cast_slice(INDICES_CHALLENGE),
-                    usage: wgpu::BufferUsages::INDEX,
-                }
-            )
-        ];
-        let index_buffer_idx: usize = 0;
-        let num_indices = INDICES_PENTAGON.len() as u32;
-
-        return State {
-            window,
-            surface,
-            device,
-            queue,
-            config,
-            size,
+        let lit_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Lit Shader"),
+            source: wgpu::ShaderSource::Wgsl(format!("{}{}", include_str!("lit.wgsl"), shadow::SHADER).into()),
+        });
+        let lit_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Lit Pipeline Layout"),
+            bind_group_layouts: &[
+                &material_bind_group_layout,
+                &camera_buffer.bind_group_layout,
+                &lighting.bind_group_layout,
+                &shadows.bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let lit_pipeline = create_lit_pipeline(
+            device, &lit_pipeline_layout, &lit_shader, scene_format,
+            msaa.multisample_state(), debug_view.cull_mode);
+        let pbr_shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("PBR Shader"),
+            source: wgpu::ShaderSource::Wgsl(format!("{}{}", include_str!("pbr.wgsl"), shadow::SHADER).into()),
+        });
+        let pbr_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("PBR Pipeline Layout"),
+            bind_group_layouts: &[
+                &material_bind_group_layout,
+                &camera_buffer.bind_group_layout,
+                &lighting.bind_group_layout,
+                &shadows.bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let pbr_pipeline = create_lit_pipeline(
+            device, &pbr_pipeline_layout, &pbr_shader, scene_format,
+            msaa.multisample_state(), debug_view.cull_mode);
+
+        Ok(State {
             color,
+            shader,
+            render_pipeline_layout,
             render_pipelines, 
-            render_pipeline_idx, 
-            vertex_buffer,
-            index_buffers,
-            index_buffer_idx,
-            num_indices,
-            bind_group_buffer,
-            bind_group_buffer_idx,
+            unlit_shaders,
+            scene_path,
+            meshes,
+            shape: scene.find("shape"),
+            light_pivot: scene.find("point light pivot"),
+            spot_light: scene.find("spot light"),
+            camera_pivot: scene.find("camera pivot"),
+            scene,
+            instance_buffer,
+            draws: Vec::new(),
+            debug_draw,
+            show_debug: false,
+            debug_view,
+            msaa,
+            depth_texture,
+            supported_sample_counts,
+            transient_pool: render_graph::TransientPool::new(),
+            post,
+            tone_mapping,
+            camera,
+            camera_buffer,
+            lighting,
+            shadows,
+            skybox,
+            show_skybox: false,
+            lit_shader,
+            lit_pipeline_layout,
+            lit_pipeline,
+            pbr_shader,
+            pbr_pipeline_layout,
+            pbr_pipeline,
+            materials,
+            shading: scene_desc.shading,
+            particles,
+            show_particles: false,
+            input_map,
+            previous_animation_time: 0.0,
+            animation_time: 0.0,
+            rng: rand::rngs::StdRng::seed_from_u64(ctx.seed()),
+        })
+    }
+
+
+    // runs what was triggered since the last frame
+    fn handle_actions(&mut self, ctx: &mut app::AppContext) {
+        if self.input_map.pressed("exit") {
+            ctx.exit();
+        }
+        // the next key or button pressed swaps the mesh from then on
+        if self.input_map.pressed("rebind_swap") {
+            self.input_map.rebind_next("swap");
+        }
+        if self.input_map.pressed("random_color") {
+            self.color = wgpu::Color{
+                r: self.rng.gen_range(0.0..1.0),
+                g: self.rng.gen_range(0.0..1.0),
+                b: self.rng.gen_range(0.0..1.0),
+                a: 1.0,
+            };
+        }
+        // the next mesh and material of the scene
+        if let Some(shape) = self.shape.filter(|_| self.input_map.pressed("swap")) {
+            if let Some(instance) = &mut self.scene.node_mut(shape).mesh {
+                instance.mesh = (instance.mesh + 1) % self.meshes.len();
+                instance.material = (instance.material + 1) % self.materials.len();
+            }
+        }
+        if self.input_map.pressed("save_scene") {
+            let scene_path = self.scene_path.as_deref().unwrap_or(std::path::Path::new("demo.json"));
+            let extension = scene_path.extension().and_then(|e| e.to_str()).unwrap_or("json");
+            let path = scene_path.with_extension(format!("saved.{}", extension));
+            match self.scene_desc().save(&path) {
+                Ok(()) => log::info!("saved the scene to {}", path.display()),
+                Err(e) => eprintln!("{:?}", e),
+            }
+        }
+        if self.input_map.pressed("debug_lines") {
+            self.show_debug = !self.show_debug;
+        }
+        if self.input_map.pressed("debug_view") {
+            self.set_debug_view(ctx, self.debug_view.view.next());
+        }
+        if self.input_map.pressed("cull_mode") {
+            self.set_cull_mode(ctx, self.debug_view.next_cull_mode());
+        }
+        if self.input_map.pressed("msaa") {
+            // cycle through the supported sample counts
+            let counts = &self.supported_sample_counts;
+            let current = counts.iter().position(|&c| c == self.msaa.sample_count()).unwrap_or(0);
+            let next = counts[(current + 1) % counts.len()];
+            if let Err(e) = self.set_sample_count(ctx, next) {
+                eprintln!("{:?}", e);
+            }
+        }
+        if self.input_map.pressed("shading") {
+            self.shading = self.shading.next();
+        }
+        if self.input_map.pressed("skybox") {
+            self.show_skybox = !self.show_skybox;
+        }
+        if self.input_map.pressed("particles") {
+            self.show_particles = !self.show_particles;
+        }
+        if self.input_map.pressed("tone_mapping") {
+            self.tone_mapping.operator = self.tone_mapping.operator.next();
+            self.post.set_tone_mapping(&self.tone_mapping);
         }
+        // exposure in quarter stops
+        for (action, stops) in [("exposure_up", 0.25), ("exposure_down", -0.25)] {
+            if self.input_map.pressed(action) {
+                self.tone_mapping.exposure *= 2f32.powf(stops);
+                self.post.set_tone_mapping(&self.tone_mapping);
+            }
+        }
+        // effects in chain order
+        for i in 0..9 {
+            if self.input_map.pressed(&format!("effect_{}", i + 1)) {
+                self.post.toggle(i);
+            }
+        }
+        self.input_map.end_frame();
     }
 
-    pub fn window(&self) -> &Window {
-        &self.window
+    fn set_debug_view(&mut self, ctx: &app::AppContext, view: debug_view::DebugView) {
+        self.debug_view.view = view;
+        self.rebuild_pipelines(ctx);
     }
 
-    fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
-        if new_size.width > 0 && new_size.height > 0 {
-            self.size = new_size;
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config)
+    fn set_cull_mode(&mut self, ctx: &app::AppContext, cull_mode: Option<wgpu::Face>) {
+        self.debug_view.cull_mode = cull_mode;
+        self.rebuild_pipelines(ctx);
+    }
+
+    fn set_sample_count(&mut self, ctx: &app::AppContext, sample_count: u32) -> anyhow::Result<()> {
+        self.msaa.set_sample_count(
+            &ctx.gpu.device, ctx.gpu.config(), sample_count, &self.supported_sample_counts)?;
+        self.depth_texture = texture::Texture::create_depth_texture(
+            &ctx.gpu.device, ctx.gpu.config(), sample_count, "Depth Texture");
+        self.debug_draw = debug_draw::DebugDraw::new(
+            &ctx.gpu.device, post::HDR_FORMAT, Some(texture::DEPTH_FORMAT), sample_count);
+        self.skybox = skybox::Skybox::new(
+            &ctx.gpu.device,
+            post::HDR_FORMAT,
+            Some(texture::DEPTH_FORMAT),
+            sample_count,
+            &self.camera_buffer.bind_group_layout,
+            self.lighting.environment(),
+        );
+        if let Some(particles) = &mut self.particles {
+            particles.set_sample_count(&ctx.gpu.device, sample_count);
         }
+        self.rebuild_pipelines(ctx);
+        Ok(())
     }
 
-    fn input(&mut self, event: &WindowEvent) -> bool {
-        match event {
-            WindowEvent::MouseInput { 
-                button: MouseButton::Left, 
-                state: ElementState::Pressed, 
-                .. 
-            } => {
-                self.color = wgpu::Color{
-                    r: rand::thread_rng().gen_range(0.0..1.0),
-                    g: rand::thread_rng().gen_range(0.0..1.0),
-                    b: rand::thread_rng().gen_range(0.0..1.0),
-                    a: 1.0,
-                };
-                self.window().request_redraw();
-                true
-            },
-            WindowEvent::KeyboardInput { 
-                input: KeyboardInput {
-                    scancode: 0x39, 
-                    state: ElementState::Pressed,
-                    ..
-                },
-                ..
-            } => {
-                self.bind_group_buffer_idx = if self.bind_group_buffer_idx > 0 { 0 } else { 1 };
-                self.render_pipeline_idx = if self.render_pipeline_idx > 0 { 0 } else { 1 };
-                self.index_buffer_idx = if self.index_buffer_idx > 0 { 0 } else { 1 };
-                if self.index_buffer_idx == 0 {
-                    self.num_indices = INDICES_PENTAGON.len() as u32;
-                } else {
-                    self.num_indices = INDICES_CHALLENGE.len() as u32;
-                }
-                true
+    fn rebuild_pipelines(&mut self, ctx: &app::AppContext) {
+        self.render_pipelines = create_render_pipelines(
+            &ctx.gpu.device,
+            &self.render_pipeline_layout,
+            &self.shader,
+            post::HDR_FORMAT,
+            self.msaa.multisample_state(),
+            &self.debug_view,
+            &self.unlit_shaders,
+        );
+        self.lit_pipeline = create_lit_pipeline(
+            &ctx.gpu.device,
+            &self.lit_pipeline_layout,
+            &self.lit_shader,
+            post::HDR_FORMAT,
+            self.msaa.multisample_state(),
+            self.debug_view.cull_mode,
+        );
+        self.pbr_pipeline = create_lit_pipeline(
+            &ctx.gpu.device,
+            &self.pbr_pipeline_layout,
+            &self.pbr_shader,
+            post::HDR_FORMAT,
+            self.msaa.multisample_state(),
+            self.debug_view.cull_mode,
+        );
+    }
+
+    // the lit pipelines only replace the regular shaded view
+    fn effective_shading(&self) -> lighting::Shading {
+        if self.debug_view.view == debug_view::DebugView::Shaded {
+            self.shading
+        } else {
+            lighting::Shading::Unlit
+        }
+    }
+
+    // per frame work before drawing, `alpha` of the way from the previous
+    // update to the latest
+    fn prepare_frame(&mut self, ctx: &app::AppContext, time: &timing::FrameTime, alpha: f32) {
+        if self.shading != lighting::Shading::Unlit {
+            let t = self.previous_animation_time + (self.animation_time - self.previous_animation_time) * alpha;
+            self.animate_lights(t);
+        }
+        self.update_scene(ctx);
+        if let Some(particles) = self.particles.as_mut().filter(|_| self.show_particles) {
+            particles.prepare(&ctx.gpu.queue, &self.camera);
+        }
+        // debug lines are in clip space unless the camera is in use
+        let view_proj = if self.effective_shading() != lighting::Shading::Unlit {
+            self.camera.view_projection_matrix()
+        } else {
+            cgmath::Matrix4::identity()
+        };
+        self.debug_draw.set_view_proj(&ctx.gpu.queue, view_proj);
+        if self.shading != lighting::Shading::Unlit {
+            self.shadows.update(&ctx.gpu.queue, &self.camera, &self.lighting.lights);
+        }
+        if self.show_debug {
+            self.draw_triangle_winding();
+            // the light gizmos and shadow frusta hide behind the meshes, the
+            // winding lines lie on them and stay on top
+            if self.shading != lighting::Shading::Unlit {
+                self.debug_draw.set_depth_test(true);
+                self.lighting.draw_debug(&mut self.debug_draw);
+                self.shadows.draw_debug(&mut self.debug_draw);
+                self.debug_draw.set_depth_test(false);
+            }
+        }
+        // a couple of times a second is plenty
+        if let Some(window) = ctx.window().filter(|_| time.frame.is_multiple_of(30)) {
+            window.set_title(&format!("{:.0} fps", time.fps));
+        }
+    }
+
+    // the point light circles the mesh and the spot light sweeps across it
+    fn animate_lights(&mut self, t: f32) {
+        if let Some(pivot) = self.light_pivot {
+            let local = *self.scene.node(pivot).local();
+            let rotation = cgmath::Quaternion::from_angle_z(cgmath::Rad(t));
+            self.scene.set_transform(pivot, scene::Transform { rotation, ..local });
+        }
+        if let Some(spot) = self.spot_light {
+            let local = *self.scene.node(spot).local();
+            let target = cgmath::Vector3::new(0.5 * (0.7 * t).sin(), 0.0, 0.0);
+            let rotation = cgmath::Quaternion::from_arc(-cgmath::Vector3::unit_z(), target - local.translation, None);
+            self.scene.set_transform(spot, scene::Transform { rotation, ..local });
+        }
+    }
+
+    // the scene as it is now, for saving
+    fn scene_desc(&self) -> scene::SceneDesc {
+        let wgpu::Color { r, g, b, a } = self.color;
+        scene::SceneDesc {
+            clear_color: [r, g, b, a],
+            shading: self.shading,
+            meshes: self.meshes.iter().map(scene::Mesh::to_desc).collect(),
+            materials: self.materials.iter()
+                .zip(&self.unlit_shaders)
+                .map(|(material, &shader)| scene::SceneMaterial {
+                    material: material.desc.clone(),
+                    unlit_shader: (shader != UNLIT_SHADERS[0]).then(|| shader.to_string()),
+                })
+                .collect(),
+            nodes: self.scene.to_node_descs(&self.lighting.lights),
+        }
+    }
+
+    // moves the lights and the camera to their nodes and collects the
+    // meshes to draw
+    fn update_scene(&mut self, ctx: &app::AppContext) {
+        self.scene.update_world_matrices();
+        self.scene.place_lights(&mut self.lighting.lights);
+        if let Some(camera) = self.scene.camera(self.camera.aspect) {
+            self.camera = camera;
+        }
+        // also without a camera node, so the aspect set by `resize` gets there
+        self.camera_buffer.update(&ctx.gpu.queue, &self.camera);
+        let (draws, instances) = self.scene.draw_list();
+        self.instance_buffer.write_slice(&ctx.gpu.device, &ctx.gpu.queue, &instances);
+        self.draws = draws;
+    }
+
+    // outline every triangle of the meshes drawn, green if its winding is
+    // counter-clockwise (front facing) and red if it will be culled
+    fn draw_triangle_winding(&mut self) {
+        for draw in &self.draws {
+            let mesh = &self.meshes[draw.mesh];
+            let world = self.scene.node(draw.node).world_matrix();
+            for triangle in mesh.indices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| {
+                    let [x, y, z] = mesh.vertices[triangle[i] as usize].position;
+                    let position = world * cgmath::Vector4::new(x, y, z, 1.0);
+                    [position.x, position.y, position.z]
+                });
+                let cross = (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0]);
+                let color = if cross > 0.0 { debug_draw::GREEN } else { debug_draw::RED };
+                self.debug_draw.line(a, b, color);
+                self.debug_draw.line(b, c, color);
+                self.debug_draw.line(c, a, color);
+                // mark the first vertex so the direction can be followed
+                let centroid = [0, 1, 2].map(|i| (a[i] + b[i] + c[i]) / 3.0);
+                self.debug_draw.line(centroid, a, debug_draw::YELLOW);
+            }
+        }
+    }
+}
+
+impl app::App for State {
+    fn config() -> app::AppConfig {
+        app::AppConfig {
+            gpu: gpu::GpuSettings {
+                // wireframe views use line polygon mode where the adapter has it,
+                // and msaa counts other than 4 need adapter specific format features
+                optional_features: wgpu::Features::POLYGON_MODE_LINE
+                    | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+                // output extended range colors if the display supports it
+                prefer_hdr_output: true,
             },
-            _ => false,
+            ..Default::default()
         }
     }
 
-    fn update(&mut self) {
+    fn init(ctx: &app::AppContext) -> anyhow::Result<Self> {
+        State::new(ctx)
+    }
+
+    fn resize(&mut self, ctx: &mut app::AppContext, new_size: winit::dpi::PhysicalSize<u32>) {
+        self.msaa.resize(&ctx.gpu.device, ctx.gpu.config());
+        self.depth_texture = texture::Texture::create_depth_texture(
+            &ctx.gpu.device, ctx.gpu.config(), self.msaa.sample_count(), "Depth Texture");
+        // the camera buffer is updated with the next frame
+        self.camera.aspect = new_size.width as f32 / new_size.height as f32;
+    }
+
+    fn input(&mut self, _ctx: &mut app::AppContext, event: &WindowEvent) -> bool {
+        self.input_map.handle_event(event)
+    }
+
+    fn gamepad_input(&mut self, _ctx: &mut app::AppContext, event: &gamepad::GamepadEvent) -> bool {
+        self.input_map.handle_gamepad_event(event)
+    }
+
+    // advances the simulation, once per fixed step or once per frame
+    fn update(&mut self, ctx: &mut app::AppContext, time: &timing::FrameTime) {
+        if let Some(particles) = self.particles.as_mut().filter(|_| self.show_particles) {
+            particles.step(&ctx.gpu.device, &ctx.gpu.queue, time.delta);
+        }
+        self.previous_animation_time = self.animation_time;
+        self.animation_time += time.delta;
+        // the left stick of the first pad orbits the camera around the shape
+        if let Some(pad) = ctx.gamepads.connected().next() {
+            let stick = ctx.gamepads.stick(pad, gamepad::Stick::Left);
+            if let Some(pivot) = self.camera_pivot.filter(|_| stick.x != 0.0) {
+                let local = *self.scene.node(pivot).local();
+                let rotation = cgmath::Quaternion::from_angle_y(cgmath::Rad(-stick.x * ORBIT_SPEED * time.delta));
+                self.scene.set_transform(pivot, scene::Transform { rotation: rotation * local.rotation, ..local });
+            }
+        }
     }
 
-    fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let texture_desc = wgpu::TextureViewDescriptor::default();
-        let view = output.texture.create_view(&texture_desc);
+    fn render(&mut self, ctx: &mut app::AppContext, view: &wgpu::TextureView, time: &timing::FrameTime, alpha: f32) {
+        self.handle_actions(ctx);
+        self.prepare_frame(ctx, time, alpha);
+        self.debug_draw.prepare(&ctx.gpu.device, &ctx.gpu.queue);
         let encoder_desc = wgpu::CommandEncoderDescriptor{label: Some("Render Encoder")};
-        let mut encoder = self.device.create_command_encoder(&encoder_desc);
-        // prepare render pass
-        let ops = wgpu::Operations{
-            load: wgpu::LoadOp::Clear(self.color), 
-            store: true
-        };
-        let color_attachment = wgpu::RenderPassColorAttachment{
-                view: &view,
-                resolve_target: None,
-                ops: ops,
+        let mut encoder = ctx.gpu.device.create_command_encoder(&encoder_desc);
+        self.post.prepare(&ctx.gpu.queue);
+        self.lighting.prepare(&ctx.gpu.queue, self.shadows.assignments());
+        let mut graph = render_graph::RenderGraph::new();
+        let surface = graph.import_texture("surface", view);
+        let scene = graph.create_texture("scene", render_graph::TransientTexture::new(
+            post::HDR_FORMAT, render_graph::TextureSize::SurfaceRelative(1.0)));
+        let shading = self.effective_shading();
+        let mut scene_reads = Vec::new();
+        if shading != lighting::Shading::Unlit {
+            let shadow_maps = graph.import_texture("shadow_maps", self.shadows.view());
+            scene_reads.push(shadow_maps);
+            graph.add_pass("shadows", &[], &[shadow_maps], |ctx| {
+                for layer in 0..self.shadows.layer_count() {
+                    let mut shadow_pass = self.shadows.begin_pass(ctx.encoder, layer);
+                    for (i, draw) in self.draws.iter().enumerate() {
+                        let mesh = &self.meshes[draw.mesh];
+                        shadow_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice());
+                        shadow_pass.set_vertex_buffer(1, scene::InstanceRaw::slice(self.instance_buffer.buffer(), i));
+                        shadow_pass.set_index_buffer(mesh.index_buffer.slice(), wgpu::IndexFormat::Uint16);
+                        shadow_pass.draw_indexed(0..mesh.num_indices, 0, 0..1);
+                    }
+                }
+            });
+        }
+        graph.add_pass("scene", &scene_reads, &[scene], |ctx| {
+            // prepare render pass
+            let ops = wgpu::Operations{
+                load: wgpu::LoadOp::Clear(self.color), 
+                store: true
             };
-        let render_pass_desc = wgpu::RenderPassDescriptor{
-            label: Some("Render Pass"),
-            color_attachments: &[Some(color_attachment)],
-            depth_stencil_attachment: None,
-        };
-        let mut render_pass = encoder.begin_render_pass(&render_pass_desc);
-        render_pass.set_pipeline(&self.render_pipelines[self.render_pipeline_idx]);
-        render_pass.set_bind_group(0, &self.bind_group_buffer[self.bind_group_buffer_idx], &[]);
-        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(
-            self.index_buffers[self.index_buffer_idx].slice(..), 
-            wgpu::IndexFormat::Uint16,
-        );
-        render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
-        // need to release mut borrow before calling finish on encoder
-        drop(render_pass);
+            // with msaa this draws into the multisampled texture and resolves into the scene
+            let color_attachment = self.msaa.color_attachment(ctx.resources.texture(scene), ops);
+            let render_pass_desc = wgpu::RenderPassDescriptor{
+                label: Some("Render Pass"),
+                color_attachments: &[Some(color_attachment)],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            };
+            let mut render_pass = ctx.encoder.begin_render_pass(&render_pass_desc);
+            for (i, draw) in self.draws.iter().enumerate() {
+                match shading {
+                    lighting::Shading::Unlit => render_pass.set_pipeline(&self.render_pipelines[draw.material]),
+                    lighting::Shading::BlinnPhong => render_pass.set_pipeline(&self.lit_pipeline),
+                    lighting::Shading::Pbr => render_pass.set_pipeline(&self.pbr_pipeline),
+                }
+                render_pass.set_bind_group(0, &self.materials[draw.material].bind_group, &[]);
+                if shading != lighting::Shading::Unlit {
+                    render_pass.set_bind_group(1, &self.camera_buffer.bind_group, &[]);
+                    render_pass.set_bind_group(2, &self.lighting.bind_group, &[]);
+                    render_pass.set_bind_group(3, &self.shadows.bind_group, &[]);
+                }
+                let mesh = &self.meshes[draw.mesh];
+                render_pass.set_vertex_buffer(1, scene::InstanceRaw::slice(self.instance_buffer.buffer(), i));
+                if self.debug_view.uses_barycentric_wireframe() {
+                    render_pass.set_vertex_buffer(0, mesh.wireframe_vertex_buffer.slice());
+                    render_pass.draw(0..mesh.num_indices, 0..1);
+                } else {
+                    render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice());
+                    render_pass.set_index_buffer(mesh.index_buffer.slice(), wgpu::IndexFormat::Uint16);
+                    render_pass.draw_indexed(0..mesh.num_indices, 0, 0..1);
+                }
+            }
+            // after the meshes, so it only fills what they left empty
+            if self.show_skybox {
+                self.skybox.draw(&mut render_pass, &self.camera_buffer.bind_group);
+            }
+            if let Some(particles) = self.particles.as_ref().filter(|_| self.show_particles) {
+                particles.draw(&mut render_pass, &self.camera_buffer.bind_group);
+            }
+            self.debug_draw.draw(&mut render_pass);
+        });
+        self.post.add_passes(&ctx.gpu.device, &mut graph, scene, surface);
+        let surface_size = (ctx.gpu.config().width, ctx.gpu.config().height);
+        if let Err(e) = graph.execute(&ctx.gpu.device, &mut self.transient_pool, &mut encoder, surface_size) {
+            eprintln!("{:?}", e);
+        }
         // submit command buffer (as an iter) to render queue
-        self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
-
-        Ok(())
+        ctx.gpu.queue.submit(std::iter::once(encoder.finish()));
     }
 }
 
 
+const USAGE: &str = "usage: learn-wgpu [--seed N] [--deterministic] \
+    [--record FILE | --replay FILE [--headless [--capture PNG]]]";
+
+// --record FILE saves the input of the run to FILE, --replay FILE runs
+// FILE's input again, in a window or with --headless without one, and
+// --capture saves the last frame of a headless replay. --seed sets what
+// the random colors start from and --deterministic makes the same input
+// draw the same frames
 pub async fn run() {
     env_logger::init();
-    let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().build(&event_loop).unwrap();
-
-    let mut state = State::new(window).await;
-
-    event_loop.run(move | event, _, control_flow | {
-        match event {
-            Event::WindowEvent {ref event, window_id} 
-                if window_id == state.window().id() => {
-                    if !state.input(event) {
-                        match event {
-                            WindowEvent::CloseRequested | WindowEvent::KeyboardInput {
-                                input: KeyboardInput {
-                                        state: ElementState::Pressed,
-                                        virtual_keycode: Some(VirtualKeyCode::Escape),
-                                        ..
-                                    },
-                                ..
-                            } => *control_flow = ControlFlow::Exit,
-                            WindowEvent::Resized(physical_size) => {
-                                state.resize(*physical_size);
-                            }
-                            WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
-                                state.resize(**new_inner_size)
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-            Event::RedrawRequested(window_id) 
-                if window_id == state.window().id() => {
-                    state.update();
-                    match state.render() {
-                        Ok(_) => {}
-                        Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
-                        Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
-                        Err(e) => eprintln!("{:?}", e),
-                    }
-                }
-            Event::MainEventsCleared => {
-                // RedrawRequested will only trigger once
-                // unless we request it
-                state.window().request_redraw();
+    let Some(options) = Options::parse(std::env::args().skip(1)) else {
+        eprintln!("{}", USAGE);
+        return;
+    };
+    let app_config = options.app_config;
+    if !options.headless {
+        app::run_app_with::<State>(app_config).await;
+        return;
+    }
+    let replay::RecordMode::Replay(path) = &app_config.record_mode else {
+        eprintln!("{}", USAGE);
+        return;
+    };
+    if let Err(e) = replay_headless(&app_config, path, options.capture.as_deref()).await {
+        eprintln!("{:?}", e);
+    }
+}
+
+// the command line of the demo
+struct Options {
+    app_config: app::AppConfig,
+    headless: bool,
+    capture: Option<String>,
+}
+
+impl Options {
+    // None if an option is unknown or missing its value
+    fn parse(mut args: impl Iterator<Item = String>) -> Option<Self> {
+        let mut options = Options {
+            app_config: <State as app::App>::config(),
+            headless: false,
+            capture: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--deterministic" => options.app_config.deterministic = true,
+                "--seed" => options.app_config.seed = Some(args.next()?.parse().ok()?),
+                "--capture" => options.capture = Some(args.next()?),
+                "--record" => options.app_config.record_mode = replay::RecordMode::Record(args.next()?.into()),
+                "--replay" => options.app_config.record_mode = replay::RecordMode::Replay(args.next()?.into()),
+                _ => return None,
             }
-            _ => {}
         }
-    });
+        Some(options)
+    }
+}
+
+async fn replay_headless(app_config: &app::AppConfig, path: &std::path::Path, capture: Option<&str>) -> anyhow::Result<()> {
+    let recording = replay::Recording::load(path)?;
+    let mut frames = 0;
+    let mut captured = None;
+    app::replay_headless::<State>(app_config.clone(), &recording, |_, ctx| {
+        frames += 1;
+        // the last frame drawn, whether the recording ran out or the app exited
+        let last = frames == recording.frames.len() || ctx.exit_requested();
+        if let Some(capture) = capture.filter(|_| last) {
+            captured = Some(save_frame(&ctx.gpu, capture));
+        }
+    }).await?;
+    if capture.is_some() {
+        captured.unwrap_or_else(|| Err(anyhow::anyhow!("{} has no frames to capture", path.display())))?;
+    }
+    log::info!("replayed {} frames", frames);
+    Ok(())
+}
+
+fn save_frame(gpu: &gpu::GpuContext, path: &str) -> anyhow::Result<()> {
+    read_frame(gpu)?.save(path)?;
+    Ok(())
+}
+
+// the headless frame just submitted
+fn read_frame(gpu: &gpu::GpuContext) -> anyhow::Result<image::RgbaImage> {
+    let mut readback = gpu.read_frame().ok_or_else(|| anyhow::anyhow!("not headless"))?;
+    // called from inside `run`'s executor, so wait on the device instead
+    gpu.device.poll(wgpu::Maintain::Wait);
+    let pixels = readback.try_take().ok_or_else(|| anyhow::anyhow!("frame readback didn't finish"))??;
+    let size = gpu.size();
+    let image = image::RgbaImage::from_raw(size.width, size.height, pixels)
+        .ok_or_else(|| anyhow::anyhow!("frame readback has the wrong size"))?;
+    Ok(image)
 }
 
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use replay::{RecordedEvent, RecordedFrame, Recording};
+
+    // a click each other frame, each picking a random clear color
+    fn clicks(seed: u64) -> Recording {
+        let mut recording = Recording::new(seed, winit::dpi::PhysicalSize::new(64, 48));
+        for i in 0..8 {
+            let state = if i % 2 == 0 { ElementState::Pressed } else { ElementState::Released };
+            recording.frames.push(RecordedFrame {
+                delta: Duration::from_secs(1) / 60,
+                events: vec![RecordedEvent::MouseInput { state, button: MouseButton::Left }],
+            });
+        }
+        recording
+    }
+
+    // the clear color and the pixels of every frame
+    fn replay(recording: &Recording) -> Option<(Vec<wgpu::Color>, Vec<image::RgbaImage>)> {
+        let app_config = app::AppConfig { deterministic: true, ..<State as app::App>::config() };
+        let mut colors = Vec::new();
+        let mut frames = Vec::new();
+        let replayed = futures::executor::block_on(app::replay_headless::<State>(app_config, recording, |state, ctx| {
+            colors.push(state.color);
+            frames.push(read_frame(&ctx.gpu).unwrap());
+        }));
+        match replayed {
+            Ok(_) => Some((colors, frames)),
+            Err(e) => {
+                eprintln!("skipping, no adapter: {:?}", e);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn replays_are_deterministic() {
+        let (colors, frames) = match replay(&clicks(42)) {
+            Some(replayed) => replayed,
+            None => return,
+        };
+        let (again_colors, again_frames) = replay(&clicks(42)).unwrap();
+        assert_eq!(colors, again_colors);
+        assert!(frames == again_frames, "replaying the same recording drew different frames");
+        // the clicks changed the color, differently for another seed
+        assert_ne!(colors[0], colors[colors.len() - 1]);
+        let (other_colors, _) = replay(&clicks(43)).unwrap();
+        assert_ne!(colors, other_colors);
+    }
+}
@@ -0,0 +1,334 @@
+use bytemuck::Zeroable;
+use cgmath::{InnerSpace, Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use wgpu::util::DeviceExt;
+
+use crate::debug_draw::DebugDraw;
+use crate::environment::{self, Environment};
+use crate::shadow::ShadowLayers;
+
+// must match the array size in lit.wgsl
+pub const MAX_LIGHTS: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightKind {
+    Point = 0,
+    Directional = 1,
+    Spot = 2,
+}
+
+// how lit geometry is shaded
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shading {
+    Unlit,
+    BlinnPhong,
+    Pbr,
+}
+
+impl Shading {
+    pub fn next(self) -> Self {
+        match self {
+            Shading::Unlit => Shading::BlinnPhong,
+            Shading::BlinnPhong => Shading::Pbr,
+            Shading::Pbr => Shading::Unlit,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Light {
+    pub kind: LightKind,
+    // unused for directional lights
+    pub position: Point3<f32>,
+    // the way the light shines, unused for point lights
+    pub direction: Vector3<f32>,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    // distance at which point and spot lights fade out completely
+    pub range: f32,
+    // spot cone half angles in radians, full intensity inside `inner_angle`
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    // directional and spot lights only, see `ShadowMaps::update`
+    pub cast_shadows: bool,
+}
+
+impl Light {
+    pub fn point(position: Point3<f32>, color: [f32; 3], intensity: f32, range: f32) -> Self {
+        Self {
+            kind: LightKind::Point,
+            position,
+            direction: -Vector3::unit_y(),
+            color,
+            intensity,
+            range,
+            inner_angle: 0.0,
+            outer_angle: 0.0,
+            cast_shadows: false,
+        }
+    }
+
+    pub fn directional(direction: Vector3<f32>, color: [f32; 3], intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Directional,
+            position: Point3::new(0.0, 0.0, 0.0),
+            direction,
+            color,
+            intensity,
+            range: 0.0,
+            inner_angle: 0.0,
+            outer_angle: 0.0,
+            cast_shadows: false,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn spot(
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        color: [f32; 3],
+        intensity: f32,
+        range: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+    ) -> Self {
+        Self {
+            kind: LightKind::Spot,
+            position,
+            direction,
+            color,
+            intensity,
+            range,
+            inner_angle,
+            outer_angle,
+            cast_shadows: false,
+        }
+    }
+
+    fn to_raw(self, shadow: Option<ShadowLayers>) -> LightRaw {
+        let direction = if self.direction.magnitude2() > 0.0 {
+            self.direction.normalize()
+        } else {
+            -Vector3::unit_y()
+        };
+        LightRaw {
+            position: self.position.into(),
+            kind: self.kind as u32,
+            direction: direction.into(),
+            range: self.range,
+            color: self.color,
+            intensity: self.intensity,
+            cone: [self.inner_angle.cos(), self.outer_angle.cos(), 0.0, 0.0],
+            shadow: match shadow {
+                Some(layers) => [layers.first as i32, layers.count as i32, 0, 0],
+                None => [-1, 0, 0, 0],
+            },
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightRaw {
+    position: [f32; 3],
+    kind: u32,
+    direction: [f32; 3],
+    range: f32,
+    color: [f32; 3],
+    intensity: f32,
+    // cosines of the inner and outer spot angles
+    cone: [f32; 4],
+    // first shadow map layer or -1, and layer count
+    shadow: [i32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsUniform {
+    ambient: [f32; 3],
+    count: u32,
+    shininess: f32,
+    specular: f32,
+    environment_intensity: f32,
+    // lod of the roughest prefiltered environment mip
+    environment_max_lod: f32,
+    lights: [LightRaw; MAX_LIGHTS],
+}
+
+// the scene's lights and the uniform buffer they are uploaded to, plus the
+// environment maps for image based lighting. lights can be added and
+// changed freely and are sent to the gpu in `prepare`
+pub struct Lighting {
+    pub ambient: [f32; 3],
+    // blinn-phong specular exponent and strength
+    pub shininess: f32,
+    pub specular: f32,
+    // scales the environment's contribution in the pbr shader
+    pub environment_intensity: f32,
+    pub lights: Vec<Light>,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+    buffer: wgpu::Buffer,
+    environment: Environment,
+    environment_sampler: wgpu::Sampler,
+}
+
+impl Lighting {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let environment_texture = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // irradiance, prefiltered radiance and the brdf lut
+                environment_texture(1, wgpu::TextureViewDimension::Cube),
+                environment_texture(2, wgpu::TextureViewDimension::Cube),
+                environment_texture(3, wgpu::TextureViewDimension::D2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("lights_bind_group_layout"),
+        });
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lights Buffer"),
+            contents: bytemuck::bytes_of(&LightsUniform::zeroed()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let environment = Environment::empty(device);
+        let environment_sampler = environment::create_sampler(device);
+        let bind_group = Self::create_bind_group(
+            device, &bind_group_layout, &buffer, &environment, &environment_sampler);
+        Self {
+            ambient: [0.05, 0.05, 0.05],
+            shininess: 32.0,
+            specular: 0.5,
+            environment_intensity: 1.0,
+            lights: Vec::new(),
+            bind_group_layout,
+            bind_group,
+            buffer,
+            environment,
+            environment_sampler,
+        }
+    }
+
+    // replaces the (initially black) environment used for image based lighting
+    pub fn set_environment(&mut self, device: &wgpu::Device, environment: Environment) {
+        self.bind_group = Self::create_bind_group(
+            device, &self.bind_group_layout, &self.buffer, &environment, &self.environment_sampler);
+        self.environment = environment;
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffer: &wgpu::Buffer,
+        environment: &Environment,
+        sampler: &wgpu::Sampler,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&environment.irradiance_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&environment.prefiltered_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&environment.brdf_lut_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("lights_bind_group"),
+        })
+    }
+
+    // returns the index of the light in `lights`
+    pub fn add(&mut self, light: Light) -> usize {
+        self.lights.push(light);
+        self.lights.len() - 1
+    }
+
+    // `shadows` are the shadow map layers of each light, as given by
+    // `ShadowMaps::assignments`. lights past its end have no shadows
+    pub fn prepare(&self, queue: &wgpu::Queue, shadows: &[Option<ShadowLayers>]) {
+        if self.lights.len() > MAX_LIGHTS {
+            log::warn!("{} lights, only the first {} are used", self.lights.len(), MAX_LIGHTS);
+        }
+        let mut uniform = LightsUniform::zeroed();
+        uniform.ambient = self.ambient;
+        uniform.shininess = self.shininess;
+        uniform.specular = self.specular;
+        uniform.environment_intensity = self.environment_intensity;
+        uniform.environment_max_lod = (self.environment.prefiltered_mip_count - 1) as f32;
+        for (i, (raw, light)) in uniform.lights.iter_mut().zip(self.lights.iter()).enumerate() {
+            *raw = light.to_raw(shadows.get(i).copied().flatten());
+            uniform.count += 1;
+        }
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+
+    // light positions and directions drawn in their own color
+    pub fn draw_debug(&self, debug_draw: &mut DebugDraw) {
+        for light in self.lights.iter() {
+            let color = [light.color[0], light.color[1], light.color[2], 1.0];
+            let direction = if light.direction.magnitude2() > 0.0 {
+                light.direction.normalize()
+            } else {
+                -Vector3::unit_y()
+            };
+            match light.kind {
+                LightKind::Point => {
+                    debug_draw.sphere(light.position, 0.05, color);
+                }
+                LightKind::Directional => {
+                    let origin = Point3::new(0.0, 0.0, 0.0) - direction * 0.5;
+                    debug_draw.line(origin, origin + direction * 0.5, color);
+                }
+                LightKind::Spot => {
+                    debug_draw.sphere(light.position, 0.03, color);
+                    let reach = light.range.min(1.0);
+                    debug_draw.line(light.position, light.position + direction * reach, color);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// Vertex shader
+
+struct Camera {
+    view_proj: mat4x4<f32>,
+    view_position: vec4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> camera: Camera;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+};
+
+// the node's world matrix and its inverse transpose, from the instance buffer
+struct InstanceInput {
+    @location(4) model_0: vec4<f32>,
+    @location(5) model_1: vec4<f32>,
+    @location(6) model_2: vec4<f32>,
+    @location(7) model_3: vec4<f32>,
+    @location(8) normal_0: vec3<f32>,
+    @location(9) normal_1: vec3<f32>,
+    @location(10) normal_2: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) world_normal: vec3<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    let normal_matrix = mat3x3<f32>(instance.normal_0, instance.normal_1, instance.normal_2);
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.world_position = world_position.xyz;
+    out.world_normal = normal_matrix * model.normal;
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
+
+// Fragment shader
+
+// the base color of the material bind group
+@group(0) @binding(1)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(2)
+var s_diffuse: sampler;
+
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_DIRECTIONAL: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    // cosines of the inner and outer spot angles
+    cone: vec4<f32>,
+    // first shadow map layer, negative without shadows, and layer count
+    shadow: vec4<i32>,
+};
+
+struct Lights {
+    ambient: vec3<f32>,
+    count: u32,
+    shininess: f32,
+    specular: f32,
+    environment_intensity: f32,
+    environment_max_lod: f32,
+    lights: array<Light, 64>,
+};
+@group(2) @binding(0)
+var<uniform> lights: Lights;
+
+// inverse square falloff, windowed to reach zero at the light's range
+fn attenuation(distance: f32, range: f32) -> f32 {
+    let ratio = distance / range;
+    let window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return window * window / (distance * distance + 1.0);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let albedo = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let normal = normalize(in.world_normal);
+    let view_dir = normalize(camera.view_position.xyz - in.world_position);
+
+    var diffuse = vec3<f32>(0.0);
+    var specular = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.count; i += 1u) {
+        let light = lights.lights[i];
+        var light_dir: vec3<f32>;
+        var strength = light.intensity;
+        if light.kind == LIGHT_DIRECTIONAL {
+            light_dir = -light.direction;
+        } else {
+            let to_light = light.position - in.world_position;
+            let distance = length(to_light);
+            light_dir = to_light / distance;
+            strength *= attenuation(distance, light.range);
+            if light.kind == LIGHT_SPOT {
+                let cos_angle = dot(-light_dir, light.direction);
+                strength *= smoothstep(light.cone.y, light.cone.x, cos_angle);
+            }
+        }
+        strength *= shadow_factor(light.shadow.xy, in.world_position, normalize(in.world_normal));
+        let radiance = light.color * strength;
+        let n_dot_l = max(dot(normal, light_dir), 0.0);
+        diffuse += radiance * n_dot_l;
+        // blinn-phong uses the half vector instead of the reflection
+        let half_dir = normalize(view_dir + light_dir);
+        let n_dot_h = max(dot(normal, half_dir), 0.0);
+        specular += radiance * pow(n_dot_h, lights.shininess) * lights.specular * f32(n_dot_l > 0.0);
+    }
+    let color = (lights.ambient + diffuse) * albedo.rgb + specular;
+    return vec4<f32>(color, albedo.a);
+}
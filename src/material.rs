@@ -0,0 +1,483 @@
+use std::path::Path;
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use wgpu::util::DeviceExt;
+
+use crate::texture;
+
+// the textures of a material in the order they are bound, each as a
+// texture followed by its sampler after the factors uniform at binding 0
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TextureSlot {
+    BaseColor = 0,
+    // roughness in green, metallic in blue like gltf
+    MetallicRoughness = 1,
+    Normal = 2,
+    Occlusion = 3,
+    Emissive = 4,
+}
+
+impl TextureSlot {
+    pub const ALL: [TextureSlot; 5] = [
+        TextureSlot::BaseColor,
+        TextureSlot::MetallicRoughness,
+        TextureSlot::Normal,
+        TextureSlot::Occlusion,
+        TextureSlot::Emissive,
+    ];
+
+    pub fn texture_binding(self) -> u32 {
+        1 + 2 * self as u32
+    }
+
+    // only color textures are stored in srgb, the rest is linear data
+    pub fn format(self) -> wgpu::TextureFormat {
+        match self {
+            TextureSlot::BaseColor | TextureSlot::Emissive => wgpu::TextureFormat::Rgba8UnormSrgb,
+            _ => wgpu::TextureFormat::Rgba8Unorm,
+        }
+    }
+
+    // used when the material has no texture in this slot, so that the
+    // factors are used as they are
+    fn default_pixel(self) -> [u8; 4] {
+        match self {
+            TextureSlot::Normal => [128, 128, 255, 255],
+            _ => [255, 255, 255, 255],
+        }
+    }
+}
+
+// a metallic-roughness material as stored in material files. texture
+// paths are relative to the file the material was loaded from
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialDesc {
+    pub name: String,
+    pub base_color_factor: [f32; 4],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub emissive_factor: [f32; 3],
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+    pub base_color_texture: Option<String>,
+    pub metallic_roughness_texture: Option<String>,
+    pub normal_texture: Option<String>,
+    pub occlusion_texture: Option<String>,
+    pub emissive_texture: Option<String>,
+}
+
+// the gltf defaults
+impl Default for MaterialDesc {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            base_color_factor: [1.0; 4],
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            emissive_factor: [0.0; 3],
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+        }
+    }
+}
+
+impl MaterialDesc {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    pub fn texture(&self, slot: TextureSlot) -> Option<&str> {
+        match slot {
+            TextureSlot::BaseColor => self.base_color_texture.as_deref(),
+            TextureSlot::MetallicRoughness => self.metallic_roughness_texture.as_deref(),
+            TextureSlot::Normal => self.normal_texture.as_deref(),
+            TextureSlot::Occlusion => self.occlusion_texture.as_deref(),
+            TextureSlot::Emissive => self.emissive_texture.as_deref(),
+        }
+    }
+
+    fn uniform(&self) -> MaterialUniform {
+        MaterialUniform {
+            base_color_factor: self.base_color_factor,
+            emissive_factor: self.emissive_factor,
+            metallic_factor: self.metallic_factor,
+            roughness_factor: self.roughness_factor,
+            normal_scale: self.normal_scale,
+            occlusion_strength: self.occlusion_strength,
+            _padding: 0.0,
+        }
+    }
+}
+
+// a material description with its decoded images, ready to be uploaded
+pub struct LoadedMaterial {
+    pub desc: MaterialDesc,
+    pub images: Vec<(TextureSlot, image::DynamicImage)>,
+}
+
+impl LoadedMaterial {
+    // `read` returns the encoded image behind one of the texture paths,
+    // which lets materials come from files, archives or include_bytes!
+    pub fn from_desc<F>(desc: MaterialDesc, mut read: F) -> Result<Self>
+    where
+        F: FnMut(&str) -> Result<Vec<u8>>,
+    {
+        let mut images = Vec::new();
+        for slot in TextureSlot::ALL {
+            if let Some(path) = desc.texture(slot) {
+                let bytes = read(path).with_context(|| format!("reading texture {}", path))?;
+                images.push((slot, image::load_from_memory(&bytes)?));
+            }
+        }
+        Ok(Self { desc, images })
+    }
+
+    // a material file, with textures next to it
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let desc = MaterialDesc::from_json(&std::fs::read_to_string(path)?)
+            .with_context(|| format!("parsing material {}", path.display()))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::from_desc(desc, |texture| Ok(std::fs::read(dir.join(texture))?))
+    }
+}
+
+// every material of a .gltf or .glb file, in the file's order so mesh
+// primitives can refer to them by index
+pub fn load_gltf<P: AsRef<Path>>(path: P) -> Result<Vec<LoadedMaterial>> {
+    let path = path.as_ref();
+    let gltf = gltf::Gltf::open(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let read_uri = |uri: &str| -> Result<Vec<u8>> {
+        if uri.starts_with("data:") {
+            bail!("embedded data uris are not supported, use a .glb or external files");
+        }
+        Ok(std::fs::read(dir.join(uri))?)
+    };
+    let read_image = |image: gltf::Image| -> Result<(Option<String>, image::DynamicImage)> {
+        let (uri, bytes) = match image.source() {
+            gltf::image::Source::Uri { uri, .. } => (Some(uri.to_string()), read_uri(uri)?),
+            gltf::image::Source::View { view, .. } => {
+                let buffer = match view.buffer().source() {
+                    gltf::buffer::Source::Bin => gltf.blob.clone()
+                        .ok_or_else(|| anyhow!("missing binary chunk"))?,
+                    gltf::buffer::Source::Uri(uri) => read_uri(uri)?,
+                };
+                let range = view.offset()..view.offset() + view.length();
+                let bytes = buffer.get(range).ok_or_else(|| anyhow!("image outside of its buffer"))?;
+                (None, bytes.to_vec())
+            }
+        };
+        Ok((uri, image::load_from_memory(&bytes)?))
+    };
+
+    let mut materials = Vec::new();
+    for material in gltf.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        let mut desc = MaterialDesc {
+            name: material.name().unwrap_or_default().to_string(),
+            base_color_factor: pbr.base_color_factor(),
+            metallic_factor: pbr.metallic_factor(),
+            roughness_factor: pbr.roughness_factor(),
+            emissive_factor: material.emissive_factor(),
+            ..Default::default()
+        };
+        let mut textures = Vec::new();
+        if let Some(info) = pbr.base_color_texture() {
+            textures.push((TextureSlot::BaseColor, info.texture()));
+        }
+        if let Some(info) = pbr.metallic_roughness_texture() {
+            textures.push((TextureSlot::MetallicRoughness, info.texture()));
+        }
+        if let Some(normal) = material.normal_texture() {
+            desc.normal_scale = normal.scale();
+            textures.push((TextureSlot::Normal, normal.texture()));
+        }
+        if let Some(occlusion) = material.occlusion_texture() {
+            desc.occlusion_strength = occlusion.strength();
+            textures.push((TextureSlot::Occlusion, occlusion.texture()));
+        }
+        if let Some(info) = material.emissive_texture() {
+            textures.push((TextureSlot::Emissive, info.texture()));
+        }
+        let mut images = Vec::new();
+        for (slot, texture) in textures {
+            let (uri, image) = read_image(texture.source())?;
+            // keep external paths so the material can be saved as a material file
+            let field = match slot {
+                TextureSlot::BaseColor => &mut desc.base_color_texture,
+                TextureSlot::MetallicRoughness => &mut desc.metallic_roughness_texture,
+                TextureSlot::Normal => &mut desc.normal_texture,
+                TextureSlot::Occlusion => &mut desc.occlusion_texture,
+                TextureSlot::Emissive => &mut desc.emissive_texture,
+            };
+            *field = uri;
+            images.push((slot, image));
+        }
+        materials.push(LoadedMaterial { desc, images });
+    }
+    Ok(materials)
+}
+
+// .gltf and .glb files give all their materials, anything else is read
+// as a single material file
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<LoadedMaterial>> {
+    let path = path.as_ref();
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("gltf" | "glb") => load_gltf(path),
+        _ => Ok(vec![LoadedMaterial::load(path)?]),
+    }
+}
+
+// must match the Material struct in pbr.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniform {
+    base_color_factor: [f32; 4],
+    emissive_factor: [f32; 3],
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    occlusion_strength: f32,
+    _padding: f32,
+}
+
+// a material on the gpu, its factors and textures in one bind group
+pub struct Material {
+    pub desc: MaterialDesc,
+    pub bind_group: wgpu::BindGroup,
+    buffer: wgpu::Buffer,
+    // kept alive for the bind group
+    _textures: Vec<texture::Texture>,
+}
+
+impl Material {
+    // the layout shared by all materials, the factors uniform at binding 0
+    // and a texture and sampler per `TextureSlot`
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let mut entries = vec![wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }];
+        for slot in TextureSlot::ALL {
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding: slot.texture_binding(),
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            });
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding: slot.texture_binding() + 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            });
+        }
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &entries,
+            label: Some("material_bind_group_layout"),
+        })
+    }
+
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        material: &LoadedMaterial,
+    ) -> Result<Self> {
+        let desc = material.desc.clone();
+        let mut textures = Vec::new();
+        for slot in TextureSlot::ALL {
+            let label = format!("{} {:?}", desc.name, slot);
+            let texture = match material.images.iter().find(|(s, _)| *s == slot) {
+                Some((_, image)) => texture::Texture::from_image_with_format(
+                    device, queue, image, Some(&label), slot.format())?,
+                None => {
+                    let pixel = image::Rgba(slot.default_pixel());
+                    let image = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, pixel));
+                    texture::Texture::from_image_with_format(
+                        device, queue, &image, Some(&label), slot.format())?
+                }
+            };
+            textures.push(texture);
+        }
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Material Buffer"),
+            contents: bytemuck::bytes_of(&desc.uniform()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: buffer.as_entire_binding(),
+        }];
+        for (slot, texture) in TextureSlot::ALL.iter().zip(textures.iter()) {
+            entries.push(wgpu::BindGroupEntry {
+                binding: slot.texture_binding(),
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            });
+            entries.push(wgpu::BindGroupEntry {
+                binding: slot.texture_binding() + 1,
+                resource: wgpu::BindingResource::Sampler(&texture.sampler),
+            });
+        }
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: Some("material_bind_group"),
+        });
+        Ok(Self {
+            desc,
+            bind_group,
+            buffer,
+            _textures: textures,
+        })
+    }
+
+    // uploads the factors after `desc` was changed, textures stay as they are
+    pub fn update(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&self.desc.uniform()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    // an empty directory of its own for each test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("learn-wgpu-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_png(path: &Path, pixel: [u8; 4]) {
+        image::RgbaImage::from_pixel(2, 2, image::Rgba(pixel)).save(path).unwrap();
+    }
+
+    const GLTF: &str = r#"{
+        "asset": { "version": "2.0" },
+        "images": [{ "uri": "base.png" }, { "uri": "normal.png" }],
+        "textures": [{ "source": 0 }, { "source": 1 }],
+        "materials": [
+            {
+                "name": "painted",
+                "pbrMetallicRoughness": {
+                    "baseColorFactor": [1.0, 0.5, 0.25, 1.0],
+                    "baseColorTexture": { "index": 0 },
+                    "metallicFactor": 0.0,
+                    "roughnessFactor": 0.75
+                },
+                "normalTexture": { "index": 1, "scale": 0.5 },
+                "emissiveFactor": [0.1, 0.2, 0.3]
+            },
+            { "name": "plain" }
+        ]
+    }"#;
+
+    #[test]
+    fn gltf_materials_load_in_order_with_their_textures() {
+        let dir = temp_dir("gltf");
+        write_png(&dir.join("base.png"), [255, 0, 0, 255]);
+        write_png(&dir.join("normal.png"), [128, 128, 255, 255]);
+        std::fs::write(dir.join("scene.gltf"), GLTF).unwrap();
+        let materials = load(dir.join("scene.gltf"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let materials = materials.unwrap();
+
+        assert_eq!(materials.len(), 2);
+        let painted = &materials[0];
+        assert_eq!(painted.desc, MaterialDesc {
+            name: "painted".to_string(),
+            base_color_factor: [1.0, 0.5, 0.25, 1.0],
+            metallic_factor: 0.0,
+            roughness_factor: 0.75,
+            emissive_factor: [0.1, 0.2, 0.3],
+            normal_scale: 0.5,
+            base_color_texture: Some("base.png".to_string()),
+            normal_texture: Some("normal.png".to_string()),
+            ..Default::default()
+        });
+        let slots: Vec<TextureSlot> = painted.images.iter().map(|(slot, _)| *slot).collect();
+        assert_eq!(slots, [TextureSlot::BaseColor, TextureSlot::Normal]);
+        assert_eq!(painted.images[0].1.to_rgba8().get_pixel(0, 0).0, [255, 0, 0, 255]);
+        // the gltf defaults, without textures
+        assert_eq!(materials[1].desc, MaterialDesc { name: "plain".to_string(), ..Default::default() });
+        assert!(materials[1].images.is_empty());
+    }
+
+    #[test]
+    fn gltf_data_uris_are_rejected() {
+        let dir = temp_dir("gltf-data-uri");
+        let gltf = GLTF.replace(r#""uri": "base.png""#, r#""uri": "data:image/png;base64,AAAA""#);
+        std::fs::write(dir.join("scene.gltf"), gltf).unwrap();
+        let materials = load_gltf(dir.join("scene.gltf"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let error = materials.err().unwrap().to_string();
+        assert!(error.contains("data uris are not supported"), "{}", error);
+    }
+
+    #[test]
+    fn material_files_load_textures_next_to_them() {
+        let dir = temp_dir("material-file");
+        write_png(&dir.join("rough.png"), [0, 64, 255, 255]);
+        let desc = MaterialDesc {
+            name: "metal".to_string(),
+            metallic_factor: 1.0,
+            roughness_factor: 0.25,
+            metallic_roughness_texture: Some("rough.png".to_string()),
+            ..Default::default()
+        };
+        desc.save(dir.join("metal.json")).unwrap();
+        let materials = load(dir.join("metal.json"));
+        let missing = load(dir.join("missing.json"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let materials = materials.unwrap();
+
+        assert_eq!(materials.len(), 1);
+        assert_eq!(materials[0].desc, desc);
+        assert_eq!(materials[0].images.len(), 1);
+        assert_eq!(materials[0].images[0].0, TextureSlot::MetallicRoughness);
+        assert_eq!(materials[0].images[0].1.to_rgba8().get_pixel(1, 1).0, [0, 64, 255, 255]);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn material_json_fills_in_the_defaults() {
+        let desc = MaterialDesc::from_json(r#"{ "name": "red", "base_color_factor": [1.0, 0.0, 0.0, 1.0] }"#).unwrap();
+        assert_eq!(desc, MaterialDesc {
+            name: "red".to_string(),
+            base_color_factor: [1.0, 0.0, 0.0, 1.0],
+            ..Default::default()
+        });
+        assert_eq!(MaterialDesc::from_json(&desc.to_json().unwrap()).unwrap(), desc);
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::*;
+
+// sample counts we know how to configure, besides 1 (no msaa)
+pub const SAMPLE_COUNTS: [u32; 4] = [1, 2, 4, 8];
+
+// sample counts usable for render attachments of `format`. counts other than
+// 1 and 4 depend on the adapter and need TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+pub fn supported_sample_counts(
+    adapter: &wgpu::Adapter,
+    device_features: wgpu::Features,
+    format: wgpu::TextureFormat,
+) -> Vec<u32> {
+    let flags = adapter.get_texture_format_features(format).flags;
+    let adapter_specific = device_features.contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+    SAMPLE_COUNTS.iter()
+        .copied()
+        .filter(|&count| {
+            count == 1 || (flags.sample_count_supported(count)
+                && flags.contains(wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE)
+                && (count == 4 || adapter_specific))
+        })
+        .collect()
+}
+
+// a multisampled color target that is resolved into a single sampled one
+pub struct Msaa {
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    // None when rendering straight into the target
+    view: Option<wgpu::TextureView>,
+}
+
+impl Msaa {
+    // `config` only provides the size, `format` is the one of the resolve target
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let mut msaa = Self { format, sample_count, view: None };
+        msaa.resize(device, config);
+        msaa
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    pub fn set_sample_count(
+        &mut self,
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+        supported: &[u32],
+    ) -> Result<()> {
+        if !supported.contains(&sample_count) {
+            bail!("{}x msaa is not supported for {:?}, supported counts are {:?}",
+                sample_count, self.format, supported);
+        }
+        self.sample_count = sample_count;
+        self.resize(device, config);
+        Ok(())
+    }
+
+    // the multisampled texture has to match the resolve target size
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        if self.sample_count <= 1 {
+            self.view = None;
+            return;
+        }
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Multisampled Color Texture"),
+            size: wgpu::Extent3d {
+                width: config.width,
+                height: config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: self.sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        self.view = Some(texture.create_view(&wgpu::TextureViewDescriptor::default()));
+    }
+
+    pub fn multisample_state(&self) -> wgpu::MultisampleState {
+        wgpu::MultisampleState {
+            count: self.sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        }
+    }
+
+    // renders into the multisampled texture and resolves into `target`,
+    // or into `target` directly without msaa
+    pub fn color_attachment<'a>(
+        &'a self,
+        target: &'a wgpu::TextureView,
+        ops: wgpu::Operations<wgpu::Color>,
+    ) -> wgpu::RenderPassColorAttachment<'a> {
+        match &self.view {
+            Some(view) => wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: Some(target),
+                ops,
+            },
+            None => wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops,
+            },
+        }
+    }
+}
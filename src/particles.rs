@@ -0,0 +1,539 @@
+use anyhow::*;
+use cgmath::InnerSpace;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::Camera;
+use crate::compute::{ComputeKernel, Readback, StorageBuffer};
+use crate::texture;
+
+// samples per curve given to the shaders
+pub const CURVE_SAMPLES: usize = 16;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Particle {
+    pub position: [f32; 3],
+    pub age: f32,
+    pub velocity: [f32; 3],
+    // dead once age reaches it, slots start out with 0
+    pub lifetime: f32,
+}
+
+impl Particle {
+    // velocity is skipped, only the simulation needs it
+    const ATTRIBUTES: [wgpu::VertexAttribute; 3] = [
+        wgpu::VertexAttribute { format: wgpu::VertexFormat::Float32x3, offset: 0, shader_location: 0 },
+        wgpu::VertexAttribute { format: wgpu::VertexFormat::Float32, offset: 12, shader_location: 1 },
+        wgpu::VertexAttribute { format: wgpu::VertexFormat::Float32, offset: 28, shader_location: 2 },
+    ];
+
+    // per instance, position, age and lifetime
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Particle>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.age < self.lifetime
+    }
+}
+
+pub trait Lerp: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Lerp for [f32; 4] {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        [0, 1, 2, 3].map(|i| self[i].lerp(other[i], t))
+    }
+}
+
+// piecewise linear over 0..1, keys sorted by time
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Curve<T> {
+    pub keys: Vec<(f32, T)>,
+}
+
+impl<T: Lerp> Curve<T> {
+    pub fn constant(value: T) -> Self {
+        Self { keys: vec![(0.0, value)] }
+    }
+
+    pub fn sample(&self, t: f32) -> Option<T> {
+        let (first, last) = (self.keys.first()?, self.keys.last()?);
+        if t <= first.0 {
+            return Some(first.1);
+        }
+        let value = self.keys.windows(2)
+            .find(|pair| t <= pair[1].0)
+            .map(|pair| {
+                let span = pair[1].0 - pair[0].0;
+                let f = if span > 0.0 { (t - pair[0].0) / span } else { 1.0 };
+                pair[0].1.lerp(pair[1].1, f)
+            });
+        Some(value.unwrap_or(last.1))
+    }
+
+    fn bake(&self, fallback: T) -> [T; CURVE_SAMPLES] {
+        std::array::from_fn(|i| {
+            self.sample(i as f32 / (CURVE_SAMPLES - 1) as f32).unwrap_or(fallback)
+        })
+    }
+}
+
+// an emitter spawning particles at a point, with random velocities and lifetimes
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmitterDesc {
+    pub position: [f32; 3],
+    // particles per second
+    pub spawn_rate: f32,
+    // seconds, min and max
+    pub lifetime: [f32; 2],
+    pub velocity: [f32; 3],
+    // velocities vary by up to this much per axis
+    pub velocity_spread: [f32; 3],
+    pub gravity: [f32; 3],
+    // over the lifetime of each particle, multiplied with the texture
+    pub color: Curve<[f32; 4]>,
+    // billboard width and height in world units
+    pub size: Curve<f32>,
+    // the oldest slots are reused once they are all taken
+    pub max_particles: u32,
+    pub seed: u32,
+}
+
+impl Default for EmitterDesc {
+    // a small fountain
+    fn default() -> Self {
+        Self {
+            position: [0.0, -0.8, 0.0],
+            spawn_rate: 300.0,
+            lifetime: [1.0, 2.0],
+            velocity: [0.0, 1.5, 0.0],
+            velocity_spread: [0.3, 0.2, 0.3],
+            gravity: [0.0, -1.5, 0.0],
+            color: Curve {
+                keys: vec![
+                    (0.0, [1.0, 1.0, 0.8, 1.0]),
+                    (0.3, [1.0, 0.6, 0.1, 0.8]),
+                    (1.0, [0.8, 0.1, 0.0, 0.0]),
+                ],
+            },
+            size: Curve { keys: vec![(0.0, 0.06), (1.0, 0.02)] },
+            max_particles: 4096,
+            seed: 0,
+        }
+    }
+}
+
+// shared by the simulation and the billboards, see particles.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ParticleParams {
+    position: [f32; 4],
+    velocity: [f32; 4],
+    velocity_spread: [f32; 4],
+    gravity: [f32; 4],
+    spawn: [u32; 4],
+    camera_right: [f32; 4],
+    camera_up: [f32; 4],
+    colors: [[f32; 4]; CURVE_SAMPLES],
+    sizes: [[f32; 4]; CURVE_SAMPLES / 4],
+}
+
+impl ParticleParams {
+    fn new(desc: &EmitterDesc) -> Self {
+        let sizes = desc.size.bake(0.0);
+        let [p, v, s, g] = [desc.position, desc.velocity, desc.velocity_spread, desc.gravity];
+        Self {
+            position: [p[0], p[1], p[2], 0.0],
+            velocity: [v[0], v[1], v[2], desc.lifetime[0]],
+            velocity_spread: [s[0], s[1], s[2], desc.lifetime[1]],
+            gravity: [g[0], g[1], g[2], 0.0],
+            spawn: [0, 0, 0, desc.seed],
+            camera_right: [1.0, 0.0, 0.0, 0.0],
+            camera_up: [0.0, 1.0, 0.0, 0.0],
+            colors: desc.color.bake([1.0; 4]),
+            sizes: std::array::from_fn(|i| std::array::from_fn(|j| sizes[i * 4 + j])),
+        }
+    }
+
+    fn set_camera(&mut self, camera: &Camera) {
+        let forward = (camera.target - camera.eye).normalize();
+        let right = forward.cross(camera.up).normalize();
+        let up = right.cross(forward);
+        self.camera_right = [right.x, right.y, right.z, 0.0];
+        self.camera_up = [up.x, up.y, up.z, 0.0];
+    }
+
+    // the curves at a fraction of the lifetime, like the vertex shader
+    fn color_and_size(&self, t: f32) -> ([f32; 4], f32) {
+        let x = t.clamp(0.0, 1.0) * (CURVE_SAMPLES - 1) as f32;
+        let i = x.floor().min((CURVE_SAMPLES - 2) as f32);
+        let f = x - i;
+        let i = i as usize;
+        let size = |i: usize| self.sizes[i / 4][i % 4];
+        (self.colors[i].lerp(self.colors[i + 1], f), size(i).lerp(size(i + 1), f))
+    }
+}
+
+// which slots get new particles, identical for the cpu and gpu paths
+#[derive(Clone, Debug, Default)]
+struct Spawner {
+    accumulator: f32,
+    next_slot: u32,
+    spawned: u32,
+}
+
+impl Spawner {
+    fn step(&mut self, desc: &EmitterDesc, params: &mut ParticleParams, dt: f32) {
+        let max = desc.max_particles.max(1);
+        self.accumulator += desc.spawn_rate * dt;
+        let count = self.accumulator.floor().max(0.0);
+        self.accumulator -= count;
+        let count = (count as u32).min(max);
+        params.position[3] = dt;
+        params.spawn = [self.next_slot, count, self.spawned, desc.seed];
+        self.next_slot = (self.next_slot + count) % max;
+        self.spawned = self.spawned.wrapping_add(count);
+    }
+}
+
+fn pcg(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn random(hash: u32) -> f32 {
+    (hash >> 8) as f32 / 16777216.0
+}
+
+// cs_simulate on the cpu, operation for operation
+fn simulate(particles: &mut [Particle], params: &ParticleParams) {
+    let count = particles.len() as u32;
+    let dt = params.position[3];
+    for (i, p) in particles.iter_mut().enumerate() {
+        let slot = (i as u32 + count - params.spawn[0]) % count;
+        if slot < params.spawn[1] {
+            let h0 = pcg(params.spawn[3].wrapping_add(pcg(params.spawn[2].wrapping_add(slot))));
+            let h1 = pcg(h0);
+            let h2 = pcg(h1);
+            let h3 = pcg(h2);
+            let r = [random(h0), random(h1), random(h2)];
+            p.position = [params.position[0], params.position[1], params.position[2]];
+            p.velocity = [0, 1, 2].map(|k| params.velocity[k] + params.velocity_spread[k] * (r[k] * 2.0 - 1.0));
+            p.age = 0.0;
+            p.lifetime = params.velocity[3] + (params.velocity_spread[3] - params.velocity[3]) * random(h3);
+        } else if p.is_alive() {
+            for k in 0..3 {
+                p.velocity[k] += params.gravity[k] * dt;
+                p.position[k] += p.velocity[k] * dt;
+            }
+            p.age += dt;
+        }
+    }
+}
+
+// the simulation without a gpu, giving the same particles as `ParticleSystem`
+pub struct CpuParticleSystem {
+    desc: EmitterDesc,
+    spawner: Spawner,
+    params: ParticleParams,
+    particles: Vec<Particle>,
+}
+
+impl CpuParticleSystem {
+    pub fn new(desc: EmitterDesc) -> Self {
+        let params = ParticleParams::new(&desc);
+        let particles = vec![Particle::default(); desc.max_particles.max(1) as usize];
+        Self { desc, spawner: Spawner::default(), params, particles }
+    }
+
+    pub fn desc(&self) -> &EmitterDesc {
+        &self.desc
+    }
+
+    // advances the simulation by `dt` seconds
+    pub fn step(&mut self, dt: f32) {
+        self.spawner.step(&self.desc, &mut self.params, dt);
+        simulate(&mut self.particles, &self.params);
+    }
+
+    // every slot, dead particles included
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    // the color and size a particle is drawn with
+    // empty slots have no lifetime, they get the look of a particle at its end
+    pub fn appearance(&self, particle: &Particle) -> ([f32; 4], f32) {
+        let t = if particle.lifetime > 0.0 { particle.age / particle.lifetime } else { 1.0 };
+        self.params.color_and_size(t)
+    }
+}
+
+// simulated in a compute shader and drawn as camera facing textured quads
+pub struct ParticleSystem {
+    desc: EmitterDesc,
+    spawner: Spawner,
+    params: ParticleParams,
+    particles: StorageBuffer<Particle>,
+    params_buffer: wgpu::Buffer,
+    kernel: ComputeKernel,
+    simulate_bind_group: wgpu::BindGroup,
+    shader: wgpu::ShaderModule,
+    pipeline_layout: wgpu::PipelineLayout,
+    pipeline: wgpu::RenderPipeline,
+    color_format: wgpu::TextureFormat,
+    depth_format: Option<wgpu::TextureFormat>,
+    texture_bind_group: wgpu::BindGroup,
+    params_bind_group: wgpu::BindGroup,
+}
+
+impl ParticleSystem {
+    // `camera_layout` is the one of `CameraBuffer`, bound at group 1 when drawing
+    pub fn new(
+        device: &wgpu::Device,
+        desc: EmitterDesc,
+        texture: &texture::Texture,
+        camera_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+        depth_format: Option<wgpu::TextureFormat>,
+        sample_count: u32,
+    ) -> Result<Self> {
+        let source = include_str!("particles.wgsl");
+        let params = ParticleParams::new(&desc);
+        let particles = StorageBuffer::zeroed(
+            device, "Particle Buffer", desc.max_particles.max(1) as usize, wgpu::BufferUsages::VERTEX);
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Params Buffer"),
+            size: std::mem::size_of::<ParticleParams>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let kernel = ComputeKernel::new(device, "Particle Simulation", source, "cs_simulate")?;
+        let simulate_bind_group = kernel.create_bind_group(device, 0, &[
+            (0, particles.binding()),
+            (1, params_buffer.as_entire_binding()),
+        ]);
+        let texture_layout = texture::Texture::create_bind_group_layout(device);
+        let texture_bind_group = texture.create_bind_group(device, &texture_layout);
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("particle_params_bind_group_layout"),
+        });
+        let params_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: params_buffer.as_entire_binding(),
+            }],
+            label: Some("particle_params_bind_group"),
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Particle Shader"),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Particle Pipeline Layout"),
+            bind_group_layouts: &[&texture_layout, camera_layout, &params_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = create_pipeline(device, &pipeline_layout, &shader, color_format, depth_format, sample_count);
+        Ok(Self {
+            desc,
+            spawner: Spawner::default(),
+            params,
+            particles,
+            params_buffer,
+            kernel,
+            simulate_bind_group,
+            shader,
+            pipeline_layout,
+            pipeline,
+            color_format,
+            depth_format,
+            texture_bind_group,
+            params_bind_group,
+        })
+    }
+
+    pub fn desc(&self) -> &EmitterDesc {
+        &self.desc
+    }
+
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device, &self.pipeline_layout, &self.shader, self.color_format, self.depth_format, sample_count);
+    }
+
+    // advances the simulation by `dt` seconds, submitting the work right away
+    // so several steps can run before a frame is drawn
+    pub fn step(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, dt: f32) {
+        self.spawner.step(&self.desc, &mut self.params, dt);
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[self.params]));
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Particle Encoder"),
+        });
+        self.kernel.dispatch(&mut encoder, &[&self.simulate_bind_group], [self.particles.len() as u32, 1, 1]);
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    // faces the billboards towards `camera`
+    pub fn prepare(&mut self, queue: &wgpu::Queue, camera: &Camera) {
+        self.params.set_camera(camera);
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[self.params]));
+    }
+
+    // every slot, dead particles included
+    pub fn read_particles(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Readback<Particle> {
+        self.particles.read(device, queue)
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.texture_bind_group, &[]);
+        render_pass.set_bind_group(1, camera_bind_group, &[]);
+        render_pass.set_bind_group(2, &self.params_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.particles.buffer().slice(..));
+        render_pass.draw(0..6, 0..self.particles.len() as u32);
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    color_format: wgpu::TextureFormat,
+    depth_format: Option<wgpu::TextureFormat>,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    // additive, so the draw order of the particles doesn't matter
+    let additive = wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::SrcAlpha,
+        dst_factor: wgpu::BlendFactor::One,
+        operation: wgpu::BlendOperation::Add,
+    };
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Particle Pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[Particle::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: color_format,
+                blend: Some(wgpu::BlendState {
+                    color: additive,
+                    alpha: additive,
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        // hidden behind the scene, but blended in any order so they don't
+        // write depth
+        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
+            format,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+// a round spot fading out towards the edge, the default particle texture
+pub fn soft_dot_image(size: u32) -> image::DynamicImage {
+    let image = image::RgbaImage::from_fn(size, size, |x, y| {
+        let center = (size as f32 - 1.0) / 2.0;
+        let dx = (x as f32 - center) / (center + 0.5);
+        let dy = (y as f32 - center) / (center + 0.5);
+        let falloff = (1.0 - (dx * dx + dy * dy).sqrt()).clamp(0.0, 1.0);
+        image::Rgba([255, 255, 255, (falloff * falloff * 255.0).round() as u8])
+    });
+    image::DynamicImage::ImageRgba8(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &[f32], b: &[f32], what: &str, slot: usize) {
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() <= 1e-3 * (1.0 + y.abs()), "{} of slot {}: {:?} vs {:?}", what, slot, a, b);
+        }
+    }
+
+    #[test]
+    fn gpu_matches_cpu() {
+        let (adapter, device, queue) = match futures::executor::block_on(crate::compute::request_headless_device()) {
+            Result::Ok(gpu) => gpu,
+            Err(e) => {
+                eprintln!("skipping, no adapter: {:?}", e);
+                return;
+            }
+        };
+        if !adapter.get_downlevel_capabilities().flags.contains(wgpu::DownlevelFlags::COMPUTE_SHADERS) {
+            eprintln!("skipping, no compute shaders");
+            return;
+        }
+        // few enough slots that some get reused
+        let desc = EmitterDesc { max_particles: 64, seed: 7, ..Default::default() };
+        let texture = texture::Texture::from_image(&device, &queue, &soft_dot_image(8), None).unwrap();
+        let camera_buffer = crate::camera::CameraBuffer::new(&device, &Camera::new(1.0));
+        let mut gpu = ParticleSystem::new(
+            &device, desc.clone(), &texture, &camera_buffer.bind_group_layout,
+            wgpu::TextureFormat::Rgba8UnormSrgb, None, 1).unwrap();
+        let mut cpu = CpuParticleSystem::new(desc);
+        for _ in 0..90 {
+            gpu.step(&device, &queue, 1.0 / 60.0);
+            cpu.step(1.0 / 60.0);
+        }
+        let particles = futures::executor::block_on(gpu.read_particles(&device, &queue).wait(&device)).unwrap();
+        assert_eq!(particles.len(), cpu.particles().len());
+        assert!(cpu.particles().iter().any(Particle::is_alive));
+        for (i, (g, c)) in particles.iter().zip(cpu.particles()).enumerate() {
+            assert_close(&g.position, &c.position, "position", i);
+            assert_close(&g.velocity, &c.velocity, "velocity", i);
+            assert_close(&[g.age, g.lifetime], &[c.age, c.lifetime], "age and lifetime", i);
+        }
+    }
+
+    #[test]
+    fn empty_slots_have_an_appearance() {
+        let cpu = CpuParticleSystem::new(EmitterDesc::default());
+        let (color, size) = cpu.appearance(&Particle::default());
+        assert!(color.iter().all(|c| c.is_finite()) && size.is_finite());
+    }
+}
@@ -0,0 +1,146 @@
+// Particle simulation and billboard rendering. the simulation is mirrored
+// on the cpu in particles.rs, keep the two in sync
+
+struct Particle {
+    position: vec3<f32>,
+    age: f32,
+    velocity: vec3<f32>,
+    // dead once age reaches it, slots start out with 0
+    lifetime: f32,
+};
+
+struct Params {
+    // w is the time step
+    position: vec4<f32>,
+    // w is the shortest lifetime
+    velocity: vec4<f32>,
+    // w is the longest lifetime
+    velocity_spread: vec4<f32>,
+    gravity: vec4<f32>,
+    // first slot, number of slots, spawn counter and seed of this step's spawns
+    spawn: vec4<u32>,
+    camera_right: vec4<f32>,
+    camera_up: vec4<f32>,
+    colors: array<vec4<f32>, 16>,
+    // four sizes per element
+    sizes: array<vec4<f32>, 4>,
+};
+
+@group(2) @binding(0)
+var<uniform> params: Params;
+
+// Compute shader
+
+@group(0) @binding(0)
+var<storage, read_write> particles: array<Particle>;
+@group(0) @binding(1)
+var<uniform> sim_params: Params;
+
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// 24 bits, so the float is exact
+fn random(hash: u32) -> f32 {
+    return f32(hash >> 8u) / 16777216.0;
+}
+
+@compute @workgroup_size(64)
+fn cs_simulate(@builtin(global_invocation_id) id: vec3<u32>) {
+    let count = arrayLength(&particles);
+    let i = id.x;
+    if i >= count {
+        return;
+    }
+    let dt = sim_params.position.w;
+    // spawns reuse the slots after the previous step's, wrapping around
+    let slot = (i + count - sim_params.spawn.x) % count;
+    var p = particles[i];
+    if slot < sim_params.spawn.y {
+        let h0 = pcg(sim_params.spawn.w + pcg(sim_params.spawn.z + slot));
+        let h1 = pcg(h0);
+        let h2 = pcg(h1);
+        let h3 = pcg(h2);
+        let r = vec3<f32>(random(h0), random(h1), random(h2));
+        p.position = sim_params.position.xyz;
+        p.velocity = sim_params.velocity.xyz + sim_params.velocity_spread.xyz * (r * 2.0 - 1.0);
+        p.age = 0.0;
+        p.lifetime = sim_params.velocity.w + (sim_params.velocity_spread.w - sim_params.velocity.w) * random(h3);
+    } else if p.age < p.lifetime {
+        p.velocity += sim_params.gravity.xyz * dt;
+        p.position += p.velocity * dt;
+        p.age += dt;
+    }
+    particles[i] = p;
+}
+
+// Vertex shader
+
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+    view_position: vec4<f32>,
+    inverse_view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+// curves are 16 samples over the lifetime, linearly interpolated
+fn curve_position(t: f32) -> vec2<f32> {
+    let x = clamp(t, 0.0, 1.0) * 15.0;
+    let i = min(floor(x), 14.0);
+    return vec2<f32>(i, x - i);
+}
+
+fn size_sample(i: u32) -> f32 {
+    return params.sizes[i / 4u][i % 4u];
+}
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) index: u32,
+    @location(0) position: vec3<f32>,
+    @location(1) age: f32,
+    @location(2) lifetime: f32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    if age >= lifetime {
+        // outside the clip volume
+        out.clip_position = vec4<f32>(2.0, 2.0, 2.0, 1.0);
+        return out;
+    }
+    let c = curve_position(age / lifetime);
+    let i = u32(c.x);
+    out.color = params.colors[i] + (params.colors[i + 1u] - params.colors[i]) * c.y;
+    let size = size_sample(i) + (size_sample(i + 1u) - size_sample(i)) * c.y;
+    // two triangles, corners in 0..1
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 0.0), vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 0.0), vec2<f32>(1.0, 1.0), vec2<f32>(0.0, 1.0),
+    );
+    let corner = corners[index];
+    let offset = (corner - 0.5) * size;
+    let world = position + params.camera_right.xyz * offset.x + params.camera_up.xyz * offset.y;
+    out.clip_position = camera.view_proj * vec4<f32>(world, 1.0);
+    out.tex_coords = vec2<f32>(corner.x, 1.0 - corner.y);
+    return out;
+}
+
+// Fragment shader
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(t_diffuse, s_diffuse, in.tex_coords) * in.color;
+}
@@ -0,0 +1,239 @@
+// Vertex shader
+
+struct Camera {
+    view_proj: mat4x4<f32>,
+    view_position: vec4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> camera: Camera;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec4<f32>,
+};
+
+// the node's world matrix and its inverse transpose, from the instance buffer
+struct InstanceInput {
+    @location(4) model_0: vec4<f32>,
+    @location(5) model_1: vec4<f32>,
+    @location(6) model_2: vec4<f32>,
+    @location(7) model_3: vec4<f32>,
+    @location(8) normal_0: vec3<f32>,
+    @location(9) normal_1: vec3<f32>,
+    @location(10) normal_2: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) world_normal: vec3<f32>,
+    @location(3) world_tangent: vec4<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    let normal_matrix = mat3x3<f32>(instance.normal_0, instance.normal_1, instance.normal_2);
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.world_position = world_position.xyz;
+    out.world_normal = normal_matrix * model.normal;
+    // tangents lie in the surface, they transform like positions
+    out.world_tangent = vec4<f32>((model_matrix * vec4<f32>(model.tangent.xyz, 0.0)).xyz, model.tangent.w);
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
+
+// Fragment shader
+
+struct Material {
+    base_color_factor: vec4<f32>,
+    emissive_factor: vec3<f32>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    occlusion_strength: f32,
+};
+@group(0) @binding(0)
+var<uniform> material: Material;
+@group(0) @binding(1)
+var t_base_color: texture_2d<f32>;
+@group(0) @binding(2)
+var s_base_color: sampler;
+@group(0) @binding(3)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(4)
+var s_metallic_roughness: sampler;
+// tangent space, stored linear
+@group(0) @binding(5)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(6)
+var s_normal: sampler;
+@group(0) @binding(7)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(8)
+var s_occlusion: sampler;
+@group(0) @binding(9)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(10)
+var s_emissive: sampler;
+
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_DIRECTIONAL: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    // cosines of the inner and outer spot angles
+    cone: vec4<f32>,
+    // first shadow map layer, negative without shadows, and layer count
+    shadow: vec4<i32>,
+};
+
+struct Lights {
+    ambient: vec3<f32>,
+    count: u32,
+    // blinn-phong only
+    shininess: f32,
+    specular: f32,
+    environment_intensity: f32,
+    environment_max_lod: f32,
+    lights: array<Light, 64>,
+};
+@group(2) @binding(0)
+var<uniform> lights: Lights;
+@group(2) @binding(1)
+var t_irradiance: texture_cube<f32>;
+@group(2) @binding(2)
+var t_prefiltered: texture_cube<f32>;
+@group(2) @binding(3)
+var t_brdf_lut: texture_2d<f32>;
+@group(2) @binding(4)
+var s_environment: sampler;
+
+const PI: f32 = 3.14159265359;
+
+// inverse square falloff, windowed to reach zero at the light's range
+fn attenuation(distance: f32, range: f32) -> f32 {
+    let ratio = distance / range;
+    let window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return window * window / (distance * distance + 1.0);
+}
+
+// trowbridge-reitz (ggx) normal distribution
+fn distribution_ggx(n_dot_h: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+// height correlated smith visibility, includes the 1 / (4 n.l n.v) term
+fn visibility_smith_ggx(n_dot_v: f32, n_dot_l: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let ggx_v = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - alpha2) + alpha2);
+    let ggx_l = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - alpha2) + alpha2);
+    return 0.5 / max(ggx_v + ggx_l, 0.0001);
+}
+
+fn fresnel_schlick(v_dot_h: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(1.0 - v_dot_h, 5.0);
+}
+
+// the normal map sample moved from tangent into world space. the tangent is
+// re-orthogonalized as interpolation skews it, w flips mirrored uvs
+fn perturbed_normal(in: VertexOutput) -> vec3<f32> {
+    var tangent_normal = textureSample(t_normal, s_normal, in.tex_coords).xyz * 2.0 - 1.0;
+    tangent_normal = vec3<f32>(tangent_normal.xy * material.normal_scale, tangent_normal.z);
+    let n = normalize(in.world_normal);
+    let t = normalize(in.world_tangent.xyz - n * dot(n, in.world_tangent.xyz));
+    let b = cross(n, t) * in.world_tangent.w;
+    return normalize(mat3x3<f32>(t, b, n) * tangent_normal);
+}
+
+// fresnel averaged over the lobe, for light from every direction
+fn fresnel_schlick_roughness(n_dot_v: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(1.0 - n_dot_v, 5.0);
+}
+
+// split sum image based lighting from the environment maps
+fn environment_lighting(
+    normal: vec3<f32>,
+    view_dir: vec3<f32>,
+    n_dot_v: f32,
+    f0: vec3<f32>,
+    diffuse_color: vec3<f32>,
+    roughness: f32,
+) -> vec3<f32> {
+    let fresnel = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    let irradiance = textureSample(t_irradiance, s_environment, normal).rgb;
+    let reflected = reflect(-view_dir, normal);
+    let lod = roughness * lights.environment_max_lod;
+    let prefiltered = textureSampleLevel(t_prefiltered, s_environment, reflected, lod).rgb;
+    let brdf = textureSample(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, roughness)).rg;
+    let diffuse = (1.0 - fresnel) * diffuse_color * irradiance;
+    let specular = prefiltered * (f0 * brdf.x + brdf.y);
+    return (diffuse + specular) * lights.environment_intensity;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let base_color = textureSample(t_base_color, s_base_color, in.tex_coords) * material.base_color_factor;
+    let metallic_roughness = textureSample(t_metallic_roughness, s_metallic_roughness, in.tex_coords);
+    let metallic = metallic_roughness.b * material.metallic_factor;
+    let roughness = clamp(metallic_roughness.g * material.roughness_factor, 0.04, 1.0);
+    let occlusion = mix(1.0, textureSample(t_occlusion, s_occlusion, in.tex_coords).r, material.occlusion_strength);
+    let emissive = textureSample(t_emissive, s_emissive, in.tex_coords).rgb * material.emissive_factor;
+
+    let normal = perturbed_normal(in);
+    let view_dir = normalize(camera.view_position.xyz - in.world_position);
+    let n_dot_v = max(dot(normal, view_dir), 0.0001);
+    let alpha = roughness * roughness;
+    // dielectrics reflect about 4% at normal incidence, metals their base color
+    let f0 = mix(vec3<f32>(0.04), base_color.rgb, metallic);
+    let diffuse_color = base_color.rgb * (1.0 - metallic);
+
+    // sampled before the light loop, which isn't uniform control flow
+    let environment = environment_lighting(normal, view_dir, n_dot_v, f0, diffuse_color, roughness);
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.count; i += 1u) {
+        let light = lights.lights[i];
+        var light_dir: vec3<f32>;
+        var strength = light.intensity;
+        if light.kind == LIGHT_DIRECTIONAL {
+            light_dir = -light.direction;
+        } else {
+            let to_light = light.position - in.world_position;
+            let distance = length(to_light);
+            light_dir = to_light / distance;
+            strength *= attenuation(distance, light.range);
+            if light.kind == LIGHT_SPOT {
+                let cos_angle = dot(-light_dir, light.direction);
+                strength *= smoothstep(light.cone.y, light.cone.x, cos_angle);
+            }
+        }
+        strength *= shadow_factor(light.shadow.xy, in.world_position, normalize(in.world_normal));
+        let n_dot_l = max(dot(normal, light_dir), 0.0);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+        let half_dir = normalize(view_dir + light_dir);
+        let n_dot_h = max(dot(normal, half_dir), 0.0);
+        let v_dot_h = max(dot(view_dir, half_dir), 0.0);
+        // cook-torrance specular with a lambertian diffuse lobe
+        let fresnel = fresnel_schlick(v_dot_h, f0);
+        let specular = fresnel * distribution_ggx(n_dot_h, alpha) * visibility_smith_ggx(n_dot_v, n_dot_l, alpha);
+        let diffuse = (1.0 - fresnel) * diffuse_color / PI;
+        color += (diffuse + specular) * light.color * strength * n_dot_l;
+    }
+    let ambient = (lights.ambient * base_color.rgb + environment) * occlusion;
+    return vec4<f32>(color + ambient + emissive, base_color.a);
+}
@@ -0,0 +1,355 @@
+use wgpu::util::DeviceExt;
+
+use crate::render_graph::{RenderGraph, ResourceId, TextureSize, TransientTexture};
+use crate::tonemap::ToneMapping;
+
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+const PRELUDE: &str = include_str!("post.wgsl");
+
+// the built in effects, see `PostChain::with_default_effects`
+pub const TONEMAP: &str = include_str!("post/tonemap.wgsl");
+pub const COLOR_GRADING: &str = include_str!("post/color_grading.wgsl");
+pub const FXAA: &str = include_str!("post/fxaa.wgsl");
+pub const VIGNETTE: &str = include_str!("post/vignette.wgsl");
+pub const GRAYSCALE: &str = include_str!("post/grayscale.wgsl");
+pub const GAMMA: &str = include_str!("post/gamma.wgsl");
+const COPY: &str = include_str!("post/copy.wgsl");
+
+// one full screen pass of the chain
+pub struct PostEffect {
+    pub name: String,
+    pub enabled: bool,
+    // available to the shader as `params.values`
+    pub params: [f32; 4],
+    params_buffer: wgpu::Buffer,
+    // renders into the next intermediate target
+    intermediate_pipeline: wgpu::RenderPipeline,
+    // renders into the final output when this is the last enabled effect
+    output_pipeline: wgpu::RenderPipeline,
+    // bound as `t_extra`
+    extra: Option<wgpu::TextureView>,
+}
+
+// renders the scene from an hdr target through a list of effects into the surface
+pub struct PostChain {
+    effects: Vec<PostEffect>,
+    // used when nothing is enabled
+    copy: PostEffect,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline_layout: wgpu::PipelineLayout,
+    sampler: wgpu::Sampler,
+    // bound as `t_extra` for effects without their own texture
+    dummy_view: wgpu::TextureView,
+    output_format: wgpu::TextureFormat,
+}
+
+impl PostChain {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, output_format: wgpu::TextureFormat) -> Self {
+        let filterable_texture = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                filterable_texture(0),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                filterable_texture(3),
+            ],
+            label: Some("post_bind_group_layout"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Post Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let dummy_view = create_lut_texture(device, queue, &image::RgbaImage::from_pixel(
+            1, 1, image::Rgba([255, 255, 255, 255])));
+        let copy = Self::create_effect(
+            device, &pipeline_layout, output_format, "copy", COPY, [0.0; 4], None);
+        Self {
+            effects: Vec::new(),
+            copy,
+            bind_group_layout,
+            pipeline_layout,
+            sampler,
+            dummy_view,
+            output_format,
+        }
+    }
+
+    // tone mapping, color grading, fxaa, vignette, grayscale and gamma,
+    // with the ones that change the look disabled
+    pub fn with_default_effects(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        output_format: wgpu::TextureFormat,
+    ) -> Self {
+        let mut chain = Self::new(device, queue, output_format);
+        chain.add_effect(device, "tonemap", TONEMAP, ToneMapping::new(false).params());
+        let lut = create_lut_texture(device, queue, &identity_lut(16));
+        chain.add_effect_with_texture(device, "color grading", COLOR_GRADING, [1.0, 0.0, 0.0, 0.0], lut);
+        chain.add_effect(device, "fxaa", FXAA, [0.0; 4]);
+        chain.add_effect(device, "vignette", VIGNETTE, [0.5, 0.4, 0.0, 0.0]);
+        chain.add_effect(device, "grayscale", GRAYSCALE, [1.0, 0.0, 0.0, 0.0]);
+        // srgb surfaces already encode gamma when written to
+        chain.add_effect(device, "gamma", GAMMA, [2.2, 0.0, 0.0, 0.0]);
+        for name in ["color grading", "vignette", "grayscale", "gamma"] {
+            chain.set_enabled(name, false);
+        }
+        chain
+    }
+
+    // `source` is wgsl defining `fn effect(uv: vec2<f32>) -> vec4<f32>`,
+    // see post.wgsl for what it can access. returns the effect's index
+    pub fn add_effect(&mut self, device: &wgpu::Device, name: &str, source: &str, params: [f32; 4]) -> usize {
+        let effect = Self::create_effect(
+            device, &self.pipeline_layout, self.output_format, name, source, params, None);
+        self.effects.push(effect);
+        self.effects.len() - 1
+    }
+
+    pub fn add_effect_with_texture(
+        &mut self,
+        device: &wgpu::Device,
+        name: &str,
+        source: &str,
+        params: [f32; 4],
+        extra: wgpu::TextureView,
+    ) -> usize {
+        let effect = Self::create_effect(
+            device, &self.pipeline_layout, self.output_format, name, source, params, Some(extra));
+        self.effects.push(effect);
+        self.effects.len() - 1
+    }
+
+    pub fn effects(&self) -> &[PostEffect] {
+        &self.effects
+    }
+
+    pub fn effect_mut(&mut self, name: &str) -> Option<&mut PostEffect> {
+        self.effects.iter_mut().find(|e| e.name == name)
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        if let Some(effect) = self.effect_mut(name) {
+            effect.enabled = enabled;
+        }
+    }
+
+    // sets the parameters of the effect named "tonemap"
+    pub fn set_tone_mapping(&mut self, tone_mapping: &ToneMapping) {
+        if let Some(effect) = self.effect_mut("tonemap") {
+            effect.params = tone_mapping.params();
+        }
+    }
+
+    pub fn toggle(&mut self, index: usize) {
+        if let Some(effect) = self.effects.get_mut(index) {
+            effect.enabled = !effect.enabled;
+        }
+    }
+
+    // reorder the chain, effects run from first to last
+    pub fn move_effect(&mut self, from: usize, to: usize) {
+        if from < self.effects.len() && to < self.effects.len() {
+            let effect = self.effects.remove(from);
+            self.effects.insert(to, effect);
+        }
+    }
+
+    // upload the effect parameters, call before `add_passes`
+    pub fn prepare(&self, queue: &wgpu::Queue) {
+        for effect in self.effects.iter().filter(|e| e.enabled) {
+            queue.write_buffer(&effect.params_buffer, 0, bytemuck::cast_slice(&effect.params));
+        }
+    }
+
+    // one pass per enabled effect from `input` to `output`, ping-ponging
+    // through transient hdr targets in between
+    pub fn add_passes<'a>(
+        &'a self,
+        device: &'a wgpu::Device,
+        graph: &mut RenderGraph<'a>,
+        input: ResourceId,
+        output: ResourceId,
+    ) {
+        let mut enabled: Vec<&PostEffect> = self.effects.iter().filter(|e| e.enabled).collect();
+        if enabled.is_empty() {
+            enabled.push(&self.copy);
+        }
+        let mut source = input;
+        for (i, &effect) in enabled.iter().enumerate() {
+            let last = i == enabled.len() - 1;
+            let (target, pipeline) = if last {
+                (output, &effect.output_pipeline)
+            } else {
+                let target = graph.create_texture(
+                    &format!("post {}", effect.name),
+                    TransientTexture::new(HDR_FORMAT, TextureSize::SurfaceRelative(1.0)),
+                );
+                (target, &effect.intermediate_pipeline)
+            };
+            let extra = effect.extra.as_ref().unwrap_or(&self.dummy_view);
+            graph.add_pass(&effect.name, &[source], &[target], move |ctx| {
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &self.bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(ctx.resources.texture(source)),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Sampler(&self.sampler),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: effect.params_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: wgpu::BindingResource::TextureView(extra),
+                        },
+                    ],
+                    label: Some("post_bind_group"),
+                });
+                let color_attachment = wgpu::RenderPassColorAttachment {
+                    view: ctx.resources.texture(target),
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                };
+                let mut render_pass = ctx.encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Post Pass"),
+                    color_attachments: &[Some(color_attachment)],
+                    depth_stencil_attachment: None,
+                });
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_bind_group(0, &bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            });
+            source = target;
+        }
+    }
+
+    fn create_effect(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        output_format: wgpu::TextureFormat,
+        name: &str,
+        source: &str,
+        params: [f32; 4],
+        extra: Option<wgpu::TextureView>,
+    ) -> PostEffect {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(name),
+            source: wgpu::ShaderSource::Wgsl(format!("{}\n{}", PRELUDE, source).into()),
+        });
+        let create_pipeline = |format| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(name),
+                layout: Some(layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Post Params Buffer"),
+            contents: bytemuck::cast_slice(&params),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        PostEffect {
+            name: name.to_string(),
+            enabled: true,
+            params,
+            params_buffer,
+            intermediate_pipeline: create_pipeline(HDR_FORMAT),
+            output_pipeline: create_pipeline(output_format),
+            extra,
+        }
+    }
+}
+
+// a lut that leaves colors unchanged, as a starting point for grading
+pub fn identity_lut(size: u32) -> image::RgbaImage {
+    let scale = |v: u32| (v * 255 / (size - 1).max(1)) as u8;
+    image::RgbaImage::from_fn(size * size, size, |x, y| {
+        image::Rgba([scale(x % size), scale(y), scale(x / size), 255])
+    })
+}
+
+// luts hold plain values, so unlike textures for display they are not srgb
+pub fn create_lut_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    lut: &image::RgbaImage,
+) -> wgpu::TextureView {
+    let texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Color Grading Lut"),
+            size: wgpu::Extent3d {
+                width: lut.width(),
+                height: lut.height(),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        lut,
+    );
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
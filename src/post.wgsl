@@ -0,0 +1,39 @@
+// Shared prelude for post processing effects. An effect provides
+// `fn effect(uv: vec2<f32>) -> vec4<f32>` and reads the previous
+// pass through `t_input`.
+
+struct PostParams {
+    values: vec4<f32>,
+};
+
+@group(0) @binding(0)
+var t_input: texture_2d<f32>;
+@group(0) @binding(1)
+var s_input: sampler;
+@group(0) @binding(2)
+var<uniform> params: PostParams;
+// effect specific texture, e.g. a color grading lut
+@group(0) @binding(3)
+var t_extra: texture_2d<f32>;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// Vertex shader
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    // a single triangle covering the whole screen
+    var out: VertexOutput;
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    out.clip_position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+// Fragment shader
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return effect(in.uv);
+}
@@ -0,0 +1,21 @@
+// looks colors up in a lut stored as a horizontal strip of blue slices,
+// `size * size` wide and `size` high. params.x blends the graded color in
+fn lut_sample(size: f32, red: f32, green: f32, slice: f32) -> vec3<f32> {
+    let uv = vec2<f32>(
+        (slice * size + red * (size - 1.0) + 0.5) / (size * size),
+        (green * (size - 1.0) + 0.5) / size,
+    );
+    return textureSample(t_extra, s_input, uv).rgb;
+}
+
+fn effect(uv: vec2<f32>) -> vec4<f32> {
+    let color = textureSample(t_input, s_input, uv);
+    let c = clamp(color.rgb, vec3<f32>(0.0), vec3<f32>(1.0));
+    let size = f32(textureDimensions(t_extra).y);
+    let blue = c.b * (size - 1.0);
+    let slice = floor(blue);
+    let low = lut_sample(size, c.r, c.g, slice);
+    let high = lut_sample(size, c.r, c.g, min(slice + 1.0, size - 1.0));
+    let graded = mix(low, high, blue - slice);
+    return vec4<f32>(mix(color.rgb, graded, params.values.x), color.a);
+}
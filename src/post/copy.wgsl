@@ -0,0 +1,4 @@
+// passes the input through, used when every effect is disabled
+fn effect(uv: vec2<f32>) -> vec4<f32> {
+    return textureSample(t_input, s_input, uv);
+}
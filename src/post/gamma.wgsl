@@ -0,0 +1,5 @@
+// gamma correction for surfaces without an srgb format, params.x is the gamma
+fn effect(uv: vec2<f32>) -> vec4<f32> {
+    let color = textureSample(t_input, s_input, uv);
+    return vec4<f32>(pow(max(color.rgb, vec3<f32>(0.0)), vec3<f32>(1.0 / params.values.x)), color.a);
+}
@@ -0,0 +1,6 @@
+// params.x blends between the original color and its luminance
+fn effect(uv: vec2<f32>) -> vec4<f32> {
+    let color = textureSample(t_input, s_input, uv);
+    let luminance = dot(color.rgb, vec3<f32>(0.2126, 0.7152, 0.0722));
+    return vec4<f32>(mix(color.rgb, vec3<f32>(luminance), params.values.x), color.a);
+}
@@ -0,0 +1,81 @@
+// tone mapping from the hdr scene to display range.
+// params.x is the exposure, params.y the operator (see `ToneMapOperator`)
+// and params.z the peak output value, 1.0 for sdr surfaces
+
+fn reinhard(color: vec3<f32>) -> vec3<f32> {
+    return color / (vec3<f32>(1.0) + color);
+}
+
+// narkowicz's fit of the aces filmic curve
+fn aces(color: vec3<f32>) -> vec3<f32> {
+    let numerator = color * (2.51 * color + vec3<f32>(0.03));
+    let denominator = color * (2.43 * color + vec3<f32>(0.59)) + vec3<f32>(0.14);
+    return clamp(numerator / denominator, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+fn agx_contrast(x: vec3<f32>) -> vec3<f32> {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x
+        + 0.4298 * x2 + 0.1191 * x - vec3<f32>(0.00232);
+}
+
+// agx with the default look, using the polynomial contrast approximation
+fn agx(color: vec3<f32>) -> vec3<f32> {
+    let inset = mat3x3<f32>(
+        vec3<f32>(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        vec3<f32>(0.0784335999999992, 0.878468636469772, 0.0784336),
+        vec3<f32>(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+    );
+    let outset = mat3x3<f32>(
+        vec3<f32>(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        vec3<f32>(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        vec3<f32>(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+    var c = inset * color;
+    c = clamp(log2(max(c, vec3<f32>(1e-10))), vec3<f32>(min_ev), vec3<f32>(max_ev));
+    c = (c - vec3<f32>(min_ev)) / (max_ev - min_ev);
+    c = outset * agx_contrast(c);
+    // the curve produces display encoded values, the surface expects linear
+    return pow(max(c, vec3<f32>(0.0)), vec3<f32>(2.2));
+}
+
+fn hable(x: vec3<f32>) -> vec3<f32> {
+    let a = 0.15;
+    let b = 0.50;
+    let c = 0.10;
+    let d = 0.20;
+    let e = 0.02;
+    let f = 0.30;
+    return (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f;
+}
+
+// john hable's uncharted 2 filmic curve
+fn uncharted2(color: vec3<f32>) -> vec3<f32> {
+    let white = 11.2;
+    return hable(color * 2.0) / hable(vec3<f32>(white));
+}
+
+fn effect(uv: vec2<f32>) -> vec4<f32> {
+    let color = textureSample(t_input, s_input, uv);
+    let exposed = color.rgb * params.values.x;
+    var mapped: vec3<f32>;
+    switch u32(params.values.y) {
+        case 1u: {
+            mapped = aces(exposed);
+        }
+        case 2u: {
+            mapped = agx(exposed);
+        }
+        case 3u: {
+            mapped = uncharted2(exposed);
+        }
+        default: {
+            mapped = reinhard(exposed);
+        }
+    }
+    // hdr surfaces take values above 1.0, so stretch the curve to their peak
+    return vec4<f32>(mapped * params.values.z, color.a);
+}
@@ -0,0 +1,7 @@
+// params.x is the intensity, params.y the radius where darkening starts
+fn effect(uv: vec2<f32>) -> vec4<f32> {
+    let color = textureSample(t_input, s_input, uv);
+    let distance = length(uv - vec2<f32>(0.5)) * 1.41421356;
+    let falloff = smoothstep(params.values.y, 1.0, distance);
+    return vec4<f32>(color.rgb * (1.0 - falloff * params.values.x), color.a);
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use anyhow::*;
+
+// handle to a texture or buffer declared on a `RenderGraph`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResourceId(usize);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextureSize {
+    // a fraction of the surface size, e.g. 0.5 for a half resolution bloom target
+    SurfaceRelative(f32),
+    Absolute(u32, u32),
+}
+
+impl TextureSize {
+    fn resolve(&self, surface_size: (u32, u32)) -> (u32, u32) {
+        match *self {
+            TextureSize::SurfaceRelative(scale) => (
+                ((surface_size.0 as f32 * scale) as u32).max(1),
+                ((surface_size.1 as f32 * scale) as u32).max(1),
+            ),
+            TextureSize::Absolute(width, height) => (width, height),
+        }
+    }
+}
+
+// a texture that only lives for part of a frame and whose memory
+// may be shared with other transient textures
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TransientTexture {
+    pub format: wgpu::TextureFormat,
+    pub size: TextureSize,
+    pub sample_count: u32,
+    // on top of RENDER_ATTACHMENT, and TEXTURE_BINDING when not multisampled
+    pub usage: wgpu::TextureUsages,
+}
+
+impl TransientTexture {
+    pub fn new(format: wgpu::TextureFormat, size: TextureSize) -> Self {
+        Self {
+            format,
+            size,
+            sample_count: 1,
+            usage: wgpu::TextureUsages::empty(),
+        }
+    }
+}
+
+// physical textures are shared between transients with equal keys
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct TextureKey {
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+    usage: wgpu::TextureUsages,
+}
+
+enum Resource<'a> {
+    Transient(TransientTexture),
+    ImportedTexture(&'a wgpu::TextureView),
+    ImportedBuffer(&'a wgpu::Buffer),
+}
+
+struct Declared<'a> {
+    name: String,
+    resource: Resource<'a>,
+}
+
+// keeps transient textures alive between frames so they are only
+// recreated when their description or the surface size changes
+#[derive(Default)]
+pub struct TransientPool {
+    textures: HashMap<TextureKey, Vec<(wgpu::Texture, wgpu::TextureView)>>,
+}
+
+impl TransientPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn ensure(&mut self, device: &wgpu::Device, key: TextureKey, count: usize) {
+        let textures = self.textures.entry(key).or_default();
+        while textures.len() < count {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("Transient Texture"),
+                size: wgpu::Extent3d {
+                    width: key.width,
+                    height: key.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: key.sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format: key.format,
+                usage: key.usage,
+                view_formats: &[],
+            });
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            textures.push((texture, view));
+        }
+    }
+}
+
+// what a pass can see while recording
+pub struct Resources<'r> {
+    declared: &'r [Declared<'r>],
+    // physical texture for each transient, by resource index
+    transient_views: HashMap<usize, &'r wgpu::TextureView>,
+    surface_size: (u32, u32),
+}
+
+impl<'r> Resources<'r> {
+    pub fn texture(&self, id: ResourceId) -> &'r wgpu::TextureView {
+        match &self.declared[id.0].resource {
+            Resource::ImportedTexture(view) => view,
+            Resource::Transient(_) => self.transient_views[&id.0],
+            Resource::ImportedBuffer(_) => panic!("{} is a buffer, not a texture", self.declared[id.0].name),
+        }
+    }
+
+    pub fn buffer(&self, id: ResourceId) -> &'r wgpu::Buffer {
+        match &self.declared[id.0].resource {
+            Resource::ImportedBuffer(buffer) => buffer,
+            _ => panic!("{} is a texture, not a buffer", self.declared[id.0].name),
+        }
+    }
+
+    // resolved size of a transient texture, or the surface size for imports
+    pub fn size(&self, id: ResourceId) -> (u32, u32) {
+        match &self.declared[id.0].resource {
+            Resource::Transient(desc) => desc.size.resolve(self.surface_size),
+            _ => self.surface_size,
+        }
+    }
+}
+
+pub struct PassContext<'e, 'r> {
+    pub encoder: &'e mut wgpu::CommandEncoder,
+    pub resources: &'e Resources<'r>,
+}
+
+type PassFn<'a> = Box<dyn FnMut(&mut PassContext) + 'a>;
+
+struct Pass<'a> {
+    name: String,
+    reads: Vec<ResourceId>,
+    writes: Vec<ResourceId>,
+    record: PassFn<'a>,
+}
+
+// a frame described as passes with declared inputs and outputs. the graph
+// orders the passes, drops the ones nothing depends on, assigns memory to
+// transient textures and records everything into one command encoder.
+// it is cheap to build, so a new graph is usually set up every frame
+pub struct RenderGraph<'a> {
+    resources: Vec<Declared<'a>>,
+    passes: Vec<Pass<'a>>,
+}
+
+impl<'a> Default for RenderGraph<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> RenderGraph<'a> {
+    pub fn new() -> Self {
+        Self {
+            resources: Vec::new(),
+            passes: Vec::new(),
+        }
+    }
+
+    pub fn create_texture(&mut self, name: &str, desc: TransientTexture) -> ResourceId {
+        self.declare(name, Resource::Transient(desc))
+    }
+
+    // textures owned outside the graph, like the surface, count as outputs
+    pub fn import_texture(&mut self, name: &str, view: &'a wgpu::TextureView) -> ResourceId {
+        self.declare(name, Resource::ImportedTexture(view))
+    }
+
+    pub fn import_buffer(&mut self, name: &str, buffer: &'a wgpu::Buffer) -> ResourceId {
+        self.declare(name, Resource::ImportedBuffer(buffer))
+    }
+
+    // passes using the same resource run in the order they were added when
+    // one of them writes it
+    pub fn add_pass<F>(&mut self, name: &str, reads: &[ResourceId], writes: &[ResourceId], record: F)
+    where
+        F: FnMut(&mut PassContext) + 'a,
+    {
+        self.passes.push(Pass {
+            name: name.to_string(),
+            reads: reads.to_vec(),
+            writes: writes.to_vec(),
+            record: Box::new(record),
+        });
+    }
+
+    // pass indices in execution order, without passes that don't contribute
+    // to an imported resource
+    pub fn compile(&self) -> Result<Vec<usize>> {
+        let pass_count = self.passes.len();
+        // dependencies[i] are the passes that must run before pass i. a read
+        // sees the latest write added before it, and a write waits for the
+        // earlier passes using the resource
+        let mut dependencies: Vec<Vec<usize>> = vec![Vec::new(); pass_count];
+        let mut writers: Vec<Vec<usize>> = vec![Vec::new(); self.resources.len()];
+        let mut readers: Vec<Vec<usize>> = vec![Vec::new(); self.resources.len()];
+        for (i, pass) in self.passes.iter().enumerate() {
+            for id in pass.reads.iter() {
+                dependencies[i].extend(writers[id.0].last().copied());
+            }
+            for id in pass.writes.iter() {
+                dependencies[i].extend(writers[id.0].iter().copied());
+                dependencies[i].extend(readers[id.0].iter().copied());
+            }
+            for id in pass.reads.iter() {
+                readers[id.0].push(i);
+            }
+            for id in pass.writes.iter() {
+                writers[id.0].push(i);
+            }
+        }
+
+        // walk back from the passes writing imported resources
+        let mut live = vec![false; pass_count];
+        let mut stack: Vec<usize> = (0..pass_count)
+            .filter(|&i| self.passes[i].writes.iter().any(|id| self.is_imported(*id)))
+            .collect();
+        while let Some(i) = stack.pop() {
+            if !live[i] {
+                live[i] = true;
+                stack.extend(dependencies[i].iter().copied());
+            }
+        }
+
+        sort_passes(&dependencies, &live).map_err(|stuck| {
+            let names: Vec<&str> = stuck.iter().map(|&i| self.passes[i].name.as_str()).collect();
+            anyhow!("render graph has a cycle between passes {:?}", names)
+        })
+    }
+
+    pub fn execute(
+        mut self,
+        device: &wgpu::Device,
+        pool: &mut TransientPool,
+        encoder: &mut wgpu::CommandEncoder,
+        surface_size: (u32, u32),
+    ) -> Result<()> {
+        let order = self.compile()?;
+
+        // lifetime of each transient as (first, last) position in `order`
+        let mut lifetimes: HashMap<usize, (usize, usize)> = HashMap::new();
+        for (step, &i) in order.iter().enumerate() {
+            let pass = &self.passes[i];
+            for id in pass.reads.iter().chain(pass.writes.iter()) {
+                if let Resource::Transient(_) = self.resources[id.0].resource {
+                    let lifetime = lifetimes.entry(id.0).or_insert((step, step));
+                    lifetime.1 = step;
+                }
+            }
+        }
+
+        // alias transients that never overlap onto the same physical texture
+        let mut by_first_use: Vec<(usize, (usize, usize))> = lifetimes.into_iter().collect();
+        by_first_use.sort_by_key(|(_, (first, _))| *first);
+        // per key, the last use of each physical texture handed out
+        let mut slots: HashMap<TextureKey, Vec<usize>> = HashMap::new();
+        let mut assignment: Vec<(usize, TextureKey, usize)> = Vec::new();
+        for (resource, (first, last)) in by_first_use {
+            let key = match &self.resources[resource].resource {
+                Resource::Transient(desc) => self.texture_key(desc, surface_size),
+                _ => unreachable!(),
+            };
+            let key_slots = slots.entry(key).or_default();
+            let slot = match key_slots.iter().position(|&end| end < first) {
+                Some(slot) => {
+                    key_slots[slot] = last;
+                    slot
+                }
+                None => {
+                    key_slots.push(last);
+                    key_slots.len() - 1
+                }
+            };
+            assignment.push((resource, key, slot));
+        }
+        for (key, key_slots) in slots.iter() {
+            pool.ensure(device, *key, key_slots.len());
+        }
+        // textures for keys not used this frame are stale, e.g. after a resize
+        pool.textures.retain(|key, _| slots.contains_key(key));
+
+        let transient_views = assignment.iter()
+            .map(|&(resource, key, slot)| (resource, &pool.textures[&key][slot].1))
+            .collect();
+        let resources = Resources {
+            declared: &self.resources,
+            transient_views,
+            surface_size,
+        };
+        for i in order {
+            let mut ctx = PassContext {
+                encoder,
+                resources: &resources,
+            };
+            (self.passes[i].record)(&mut ctx);
+        }
+        Ok(())
+    }
+
+    fn declare(&mut self, name: &str, resource: Resource<'a>) -> ResourceId {
+        self.resources.push(Declared {
+            name: name.to_string(),
+            resource,
+        });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    fn is_imported(&self, id: ResourceId) -> bool {
+        !matches!(self.resources[id.0].resource, Resource::Transient(_))
+    }
+
+    fn texture_key(&self, desc: &TransientTexture, surface_size: (u32, u32)) -> TextureKey {
+        let (width, height) = desc.size.resolve(surface_size);
+        // multisampled targets are resolved rather than sampled
+        let sampled = if desc.sample_count == 1 {
+            wgpu::TextureUsages::TEXTURE_BINDING
+        } else {
+            wgpu::TextureUsages::empty()
+        };
+        TextureKey {
+            format: desc.format,
+            width,
+            height,
+            sample_count: desc.sample_count,
+            usage: desc.usage | wgpu::TextureUsages::RENDER_ATTACHMENT | sampled,
+        }
+    }
+}
+
+// kahn's algorithm over the live passes, picking the earliest added ready
+// pass each step. on a cycle, the live passes that could not be ordered
+fn sort_passes(dependencies: &[Vec<usize>], live: &[bool]) -> Result<Vec<usize>, Vec<usize>> {
+    let pass_count = dependencies.len();
+    let mut remaining: Vec<usize> = (0..pass_count)
+        .map(|i| dependencies[i].iter().filter(|&&d| live[d]).count())
+        .collect();
+    let mut order = Vec::new();
+    let mut done = vec![false; pass_count];
+    while let Some(i) = (0..pass_count).find(|&i| live[i] && !done[i] && remaining[i] == 0) {
+        done[i] = true;
+        order.push(i);
+        for (j, deps) in dependencies.iter().enumerate() {
+            remaining[j] -= deps.iter().filter(|&&d| d == i).count();
+        }
+    }
+    let live_count = live.iter().filter(|&&l| l).count();
+    if order.len() != live_count {
+        return Err((0..pass_count).filter(|&i| live[i] && !done[i]).collect());
+    }
+    Result::Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn device() -> Option<wgpu::Device> {
+        match futures::executor::block_on(crate::compute::request_headless_device()) {
+            Result::Ok((_, device, _)) => Some(device),
+            Err(e) => {
+                eprintln!("skipping, no adapter: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // stands in for the surface
+    fn target(device: &wgpu::Device) -> wgpu::TextureView {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Target"),
+            size: wgpu::Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    fn transient() -> TransientTexture {
+        TransientTexture::new(wgpu::TextureFormat::Rgba8Unorm, TextureSize::SurfaceRelative(1.0))
+    }
+
+    #[test]
+    fn passes_keep_declaration_order_around_writes() {
+        let device = match device() {
+            Some(device) => device,
+            None => return,
+        };
+        let view = target(&device);
+        let mut graph = RenderGraph::new();
+        let surface = graph.import_texture("surface", &view);
+        let a = graph.create_texture("a", transient());
+        let b = graph.create_texture("b", transient());
+        // read after write
+        graph.add_pass("write a", &[], &[a], |_| {});
+        graph.add_pass("write b", &[], &[b], |_| {});
+        graph.add_pass("read a", &[a], &[surface], |_| {});
+        // write after read, must not overtake "read a"
+        graph.add_pass("rewrite a", &[b], &[a], |_| {});
+        graph.add_pass("read a again", &[a], &[surface], |_| {});
+        assert_eq!(graph.compile().unwrap(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn passes_not_reaching_an_import_are_culled() {
+        let device = match device() {
+            Some(device) => device,
+            None => return,
+        };
+        let view = target(&device);
+        let mut graph = RenderGraph::new();
+        let surface = graph.import_texture("surface", &view);
+        let used = graph.create_texture("used", transient());
+        let unused = graph.create_texture("unused", transient());
+        let dead_end = graph.create_texture("dead end", transient());
+        graph.add_pass("unused", &[], &[unused], |_| {});
+        graph.add_pass("used", &[], &[used], |_| {});
+        graph.add_pass("reads unused", &[unused], &[dead_end], |_| {});
+        graph.add_pass("present", &[used], &[surface], |_| {});
+        assert_eq!(graph.compile().unwrap(), [1, 3]);
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        // declaration order can't produce one, so sort directly
+        let dependencies = vec![vec![], vec![2], vec![1], vec![0]];
+        assert_eq!(sort_passes(&dependencies, &[true; 4]), Err(vec![1, 2]));
+        // dead passes in a cycle don't matter
+        assert_eq!(sort_passes(&dependencies, &[true, false, false, true]), Result::Ok(vec![0, 3]));
+    }
+
+    #[test]
+    fn transients_with_disjoint_lifetimes_share_a_texture() {
+        let device = match device() {
+            Some(device) => device,
+            None => return,
+        };
+        let view = target(&device);
+        let mut pool = TransientPool::new();
+        let views: [Cell<*const wgpu::TextureView>; 3] = Default::default();
+        let mut graph = RenderGraph::new();
+        let surface = graph.import_texture("surface", &view);
+        let ids = [
+            graph.create_texture("a", transient()),
+            graph.create_texture("b", transient()),
+            graph.create_texture("c", transient()),
+        ];
+        // a is done before c is first written, b overlaps both
+        let views_ref = &views;
+        graph.add_pass("write a", &[], &[ids[0]], move |ctx| views_ref[0].set(ctx.resources.texture(ids[0])));
+        graph.add_pass("a to b", &[ids[0]], &[ids[1]], move |ctx| views_ref[1].set(ctx.resources.texture(ids[1])));
+        graph.add_pass("b to c", &[ids[1]], &[ids[2]], move |ctx| views_ref[2].set(ctx.resources.texture(ids[2])));
+        graph.add_pass("present", &[ids[2]], &[surface], |_| {});
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        graph.execute(&device, &mut pool, &mut encoder, (4, 4)).unwrap();
+
+        assert_eq!(views[0].get(), views[2].get());
+        assert_ne!(views[0].get(), views[1].get());
+        assert_eq!(pool.textures.len(), 1);
+        assert_eq!(pool.textures.values().next().unwrap().len(), 2);
+    }
+}
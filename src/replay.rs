@@ -0,0 +1,273 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{
+    DeviceId, ElementState, Ime, KeyboardInput, ModifiersState, MouseButton, MouseScrollDelta, TouchPhase, WindowEvent,
+};
+
+use crate::gamepad::GamepadEvent;
+
+// what `run_app` does with the input it gets
+#[derive(Clone, Debug, Default)]
+pub enum RecordMode {
+    #[default]
+    Off,
+    // writes the input and frame times to the file on exit
+    Record(PathBuf),
+    // feeds the app the recorded input and frame times instead of the live
+    // ones, until the recording runs out
+    Replay(PathBuf),
+}
+
+// the input events an app is given, without device ids. window events
+// that can't be recreated, like scale factor changes and dropped files,
+// are left out
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordedEvent {
+    Resized(PhysicalSize<u32>),
+    Focused(bool),
+    ReceivedCharacter(char),
+    KeyboardInput { input: KeyboardInput, is_synthetic: bool },
+    ModifiersChanged(ModifiersState),
+    Ime(Ime),
+    CursorMoved(PhysicalPosition<f64>),
+    CursorEntered,
+    CursorLeft,
+    MouseWheel { delta: MouseScrollDelta, phase: TouchPhase },
+    MouseInput { state: ElementState, button: MouseButton },
+    Gamepad(GamepadEvent),
+}
+
+impl RecordedEvent {
+    pub fn from_window_event(event: &WindowEvent) -> Option<Self> {
+        Some(match event {
+            WindowEvent::Resized(size) => RecordedEvent::Resized(*size),
+            WindowEvent::Focused(focused) => RecordedEvent::Focused(*focused),
+            WindowEvent::ReceivedCharacter(c) => RecordedEvent::ReceivedCharacter(*c),
+            WindowEvent::KeyboardInput { input, is_synthetic, .. } => {
+                RecordedEvent::KeyboardInput { input: *input, is_synthetic: *is_synthetic }
+            }
+            WindowEvent::ModifiersChanged(state) => RecordedEvent::ModifiersChanged(*state),
+            WindowEvent::Ime(ime) => RecordedEvent::Ime(ime.clone()),
+            WindowEvent::CursorMoved { position, .. } => RecordedEvent::CursorMoved(*position),
+            WindowEvent::CursorEntered { .. } => RecordedEvent::CursorEntered,
+            WindowEvent::CursorLeft { .. } => RecordedEvent::CursorLeft,
+            WindowEvent::MouseWheel { delta, phase, .. } => RecordedEvent::MouseWheel { delta: *delta, phase: *phase },
+            WindowEvent::MouseInput { state, button, .. } => RecordedEvent::MouseInput { state: *state, button: *button },
+            _ => return None,
+        })
+    }
+
+    // None for gamepad events. the device id is a placeholder that must
+    // not be passed back to winit
+    #[allow(deprecated)]
+    pub fn to_window_event(&self) -> Option<WindowEvent<'static>> {
+        let device_id = unsafe { DeviceId::dummy() };
+        let modifiers = ModifiersState::empty();
+        Some(match self.clone() {
+            RecordedEvent::Resized(size) => WindowEvent::Resized(size),
+            RecordedEvent::Focused(focused) => WindowEvent::Focused(focused),
+            RecordedEvent::ReceivedCharacter(c) => WindowEvent::ReceivedCharacter(c),
+            RecordedEvent::KeyboardInput { input, is_synthetic } => {
+                WindowEvent::KeyboardInput { device_id, input, is_synthetic }
+            }
+            RecordedEvent::ModifiersChanged(state) => WindowEvent::ModifiersChanged(state),
+            RecordedEvent::Ime(ime) => WindowEvent::Ime(ime),
+            RecordedEvent::CursorMoved(position) => WindowEvent::CursorMoved { device_id, position, modifiers },
+            RecordedEvent::CursorEntered => WindowEvent::CursorEntered { device_id },
+            RecordedEvent::CursorLeft => WindowEvent::CursorLeft { device_id },
+            RecordedEvent::MouseWheel { delta, phase } => WindowEvent::MouseWheel { device_id, delta, phase, modifiers },
+            RecordedEvent::MouseInput { state, button } => WindowEvent::MouseInput { device_id, state, button, modifiers },
+            RecordedEvent::Gamepad(_) => return None,
+        })
+    }
+}
+
+// the input handled before a frame, in order, and the time since the
+// previous frame
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub delta: Duration,
+    pub events: Vec<RecordedEvent>,
+}
+
+// everything needed to run an app again the way it ran before, given the
+// app only depends on its input, its frame times and the seed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    // what the app's random numbers were seeded with
+    pub seed: u64,
+    // of the surface when the app started
+    pub size: PhysicalSize<u32>,
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    pub fn new(seed: u64, size: PhysicalSize<u32>) -> Self {
+        Self { seed, size, frames: Vec::new() }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read recording {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("invalid recording {}", path.display()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_json()?)
+            .with_context(|| format!("can't write recording {}", path.display()))
+    }
+}
+
+// collects events as they are handled, and closes a frame with its time
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    recording: Recording,
+    events: Vec<RecordedEvent>,
+}
+
+impl Recorder {
+    pub fn new(seed: u64, size: PhysicalSize<u32>) -> Self {
+        Self {
+            recording: Recording::new(seed, size),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn record_window_event(&mut self, event: &WindowEvent) {
+        self.events.extend(RecordedEvent::from_window_event(event));
+    }
+
+    pub fn record_gamepad_event(&mut self, event: &GamepadEvent) {
+        self.events.push(RecordedEvent::Gamepad(*event));
+    }
+
+    pub fn end_frame(&mut self, delta: Duration) {
+        let events = std::mem::take(&mut self.events);
+        self.recording.frames.push(RecordedFrame { delta, events });
+    }
+
+    // events after the last frame are dropped, they had no effect yet
+    pub fn recording(&self) -> &Recording {
+        &self.recording
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use winit::event::VirtualKeyCode;
+
+    use super::*;
+    use crate::gamepad::{GamepadAxis, GamepadButton, GamepadEventKind, GamepadId};
+
+    fn key(state: ElementState) -> KeyboardInput {
+        #[allow(deprecated)]
+        KeyboardInput {
+            scancode: 38,
+            state,
+            virtual_keycode: Some(VirtualKeyCode::L),
+            modifiers: ModifiersState::empty(),
+        }
+    }
+
+    fn gamepad(kind: GamepadEventKind) -> GamepadEvent {
+        GamepadEvent { id: GamepadId(0), kind }
+    }
+
+    #[test]
+    fn recording_round_trips_through_json() {
+        let mut recording = Recording::new(7, PhysicalSize::new(800, 600));
+        recording.frames.push(RecordedFrame { delta: Duration::from_millis(16), events: Vec::new() });
+        recording.frames.push(RecordedFrame {
+            delta: Duration::from_nanos(16_666_667),
+            events: vec![
+                RecordedEvent::Resized(PhysicalSize::new(640, 480)),
+                RecordedEvent::KeyboardInput { input: key(ElementState::Pressed), is_synthetic: false },
+                RecordedEvent::ModifiersChanged(ModifiersState::CTRL | ModifiersState::SHIFT),
+                RecordedEvent::CursorMoved(PhysicalPosition::new(10.5, -3.25)),
+                RecordedEvent::MouseWheel { delta: MouseScrollDelta::LineDelta(0.0, -1.0), phase: TouchPhase::Moved },
+                RecordedEvent::MouseInput { state: ElementState::Released, button: MouseButton::Other(4) },
+                RecordedEvent::Gamepad(gamepad(GamepadEventKind::ButtonPressed(GamepadButton::South))),
+                RecordedEvent::Gamepad(gamepad(GamepadEventKind::AxisChanged(GamepadAxis::LeftStickX, -0.5))),
+            ],
+        });
+        let json = recording.to_json().unwrap();
+        assert_eq!(Recording::from_json(&json).unwrap(), recording);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn window_events_convert_both_ways() {
+        let device_id = unsafe { DeviceId::dummy() };
+        let modifiers = ModifiersState::empty();
+        let events = [
+            WindowEvent::KeyboardInput { device_id, input: key(ElementState::Pressed), is_synthetic: false },
+            WindowEvent::KeyboardInput { device_id, input: key(ElementState::Released), is_synthetic: true },
+            WindowEvent::ReceivedCharacter('l'),
+            WindowEvent::ModifiersChanged(ModifiersState::ALT),
+            WindowEvent::CursorMoved { device_id, position: PhysicalPosition::new(1.5, 2.0), modifiers },
+            WindowEvent::CursorEntered { device_id },
+            WindowEvent::CursorLeft { device_id },
+            WindowEvent::MouseWheel {
+                device_id,
+                delta: MouseScrollDelta::PixelDelta(PhysicalPosition::new(0.0, 12.0)),
+                phase: TouchPhase::Started,
+                modifiers,
+            },
+            WindowEvent::MouseInput { device_id, state: ElementState::Pressed, button: MouseButton::Left, modifiers },
+            WindowEvent::MouseInput { device_id, state: ElementState::Released, button: MouseButton::Right, modifiers },
+        ];
+        for event in events {
+            let recorded = RecordedEvent::from_window_event(&event).unwrap();
+            assert_eq!(recorded.to_window_event(), Some(event));
+        }
+        assert_eq!(RecordedEvent::from_window_event(&WindowEvent::CloseRequested), None);
+        assert_eq!(RecordedEvent::Gamepad(gamepad(GamepadEventKind::Connected)).to_window_event(), None);
+    }
+
+    #[test]
+    fn recorder_groups_events_by_frame() {
+        let cursor = |x: f64| WindowEvent::CursorMoved {
+            device_id: unsafe { DeviceId::dummy() },
+            position: PhysicalPosition::new(x, 0.0),
+            #[allow(deprecated)]
+            modifiers: ModifiersState::empty(),
+        };
+        let mut recorder = Recorder::new(1, PhysicalSize::new(100, 100));
+        recorder.record_window_event(&cursor(1.0));
+        recorder.record_window_event(&WindowEvent::CloseRequested);
+        recorder.record_gamepad_event(&gamepad(GamepadEventKind::Connected));
+        recorder.end_frame(Duration::from_millis(10));
+        recorder.end_frame(Duration::from_millis(20));
+        recorder.record_window_event(&cursor(2.0));
+        recorder.end_frame(Duration::from_millis(30));
+        // never reaches a frame
+        recorder.record_window_event(&cursor(3.0));
+
+        let frames = &recorder.recording().frames;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0], RecordedFrame {
+            delta: Duration::from_millis(10),
+            events: vec![
+                RecordedEvent::CursorMoved(PhysicalPosition::new(1.0, 0.0)),
+                RecordedEvent::Gamepad(gamepad(GamepadEventKind::Connected)),
+            ],
+        });
+        assert_eq!(frames[1], RecordedFrame { delta: Duration::from_millis(20), events: Vec::new() });
+        assert_eq!(frames[2].events, [RecordedEvent::CursorMoved(PhysicalPosition::new(2.0, 0.0))]);
+    }
+}
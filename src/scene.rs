@@ -0,0 +1,919 @@
+use std::path::Path;
+
+use anyhow::*;
+use cgmath::{EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, One, Point3, Quaternion, SquareMatrix, Vector3, Vector4};
+use serde::{Deserialize, Serialize};
+
+use crate::buffer;
+use crate::camera;
+use crate::debug_view;
+use crate::lighting::{Light, LightKind, Shading};
+use crate::material::{LoadedMaterial, MaterialDesc};
+use crate::tangent;
+use crate::Vertex;
+
+// translation, rotation and scale relative to the parent node, applied
+// scale first
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "TransformDesc", into = "TransformDesc")]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn from_translation(translation: Vector3<f32>) -> Self {
+        Self { translation, ..Self::default() }
+    }
+
+    pub fn from_rotation(rotation: Quaternion<f32>) -> Self {
+        Self { rotation, ..Self::default() }
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+// a transform in scene files, the rotation as a quaternion in x, y, z, w
+// order like gltf
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct TransformDesc {
+    translation: [f32; 3],
+    rotation: [f32; 4],
+    scale: [f32; 3],
+}
+
+impl Default for TransformDesc {
+    fn default() -> Self {
+        Transform::default().into()
+    }
+}
+
+impl From<Transform> for TransformDesc {
+    fn from(transform: Transform) -> Self {
+        let Quaternion { v, s } = transform.rotation;
+        Self {
+            translation: transform.translation.into(),
+            rotation: [v.x, v.y, v.z, s],
+            scale: transform.scale.into(),
+        }
+    }
+}
+
+impl From<TransformDesc> for Transform {
+    fn from(desc: TransformDesc) -> Self {
+        let [x, y, z, w] = desc.rotation;
+        Self {
+            translation: desc.translation.into(),
+            rotation: Quaternion::new(w, x, y, z),
+            scale: desc.scale.into(),
+        }
+    }
+}
+
+// indices into the app's meshes and materials
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MeshInstance {
+    pub mesh: usize,
+    pub material: usize,
+}
+
+// a perspective camera looking down the node's -z with +y up. the aspect
+// ratio comes from the surface
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraComponent {
+    // degrees
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub name: String,
+    pub mesh: Option<MeshInstance>,
+    // an index into `Lighting::lights`, placed at the node's origin and
+    // shining down its -z
+    pub light: Option<usize>,
+    pub camera: Option<CameraComponent>,
+    local: Transform,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    world: Matrix4<f32>,
+    // the world matrix is out of date. descendants of dirty nodes are
+    // always dirty too
+    dirty: bool,
+}
+
+impl Node {
+    pub fn local(&self) -> &Transform {
+        &self.local
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    // as of the last `SceneGraph::update_world_matrices`
+    pub fn world_matrix(&self) -> Matrix4<f32> {
+        self.world
+    }
+
+    pub fn world_position(&self) -> Point3<f32> {
+        Point3::from_vec(self.world.w.truncate())
+    }
+
+    // the node's -z in world space
+    pub fn world_forward(&self) -> Vector3<f32> {
+        (self.world * -Vector4::unit_z()).truncate().normalize()
+    }
+}
+
+// nodes with transforms relative to their parents. world matrices are
+// cached and only recomputed for nodes whose transform, or an ancestor's,
+// changed since the last `update_world_matrices`
+#[derive(Clone, Debug, Default)]
+pub struct SceneGraph {
+    // removed nodes leave None so ids stay valid
+    nodes: Vec<Option<Node>>,
+    roots: Vec<NodeId>,
+    pub active_camera: Option<NodeId>,
+}
+
+impl SceneGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, name: &str, parent: Option<NodeId>) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Some(Node {
+            name: name.to_string(),
+            mesh: None,
+            light: None,
+            camera: None,
+            local: Transform::default(),
+            parent,
+            children: Vec::new(),
+            world: Matrix4::identity(),
+            dirty: true,
+        }));
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(id),
+            None => self.roots.push(id),
+        }
+        id
+    }
+
+    // with all its descendants
+    pub fn remove(&mut self, id: NodeId) {
+        let parent = self.node(id).parent;
+        self.siblings_mut(parent).retain(|&child| child != id);
+        for removed in self.subtree(id) {
+            self.nodes[removed.0] = None;
+            if self.active_camera == Some(removed) {
+                self.active_camera = None;
+            }
+        }
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.nodes.get(id.0).is_some_and(Option::is_some)
+    }
+
+    // panics for removed nodes
+    pub fn node(&self, id: NodeId) -> &Node {
+        self.nodes[id.0].as_ref().expect("node was removed")
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.nodes[id.0].as_mut().expect("node was removed")
+    }
+
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.iter().find(|(_, node)| node.name == name).map(|(id, _)| id)
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn set_transform(&mut self, id: NodeId, local: Transform) {
+        self.node_mut(id).local = local;
+        self.mark_dirty(id);
+    }
+
+    // keeps the local transform, so the node moves with its new parent
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) -> Result<()> {
+        if let Some(parent) = parent {
+            if self.subtree(id).contains(&parent) {
+                bail!("{:?} can't be parented to its own descendant {:?}", id, parent);
+            }
+        }
+        let old_parent = self.node(id).parent;
+        self.siblings_mut(old_parent).retain(|&child| child != id);
+        self.siblings_mut(parent).push(id);
+        self.node_mut(id).parent = parent;
+        self.mark_dirty(id);
+        Ok(())
+    }
+
+    // recomputes the world matrices of the nodes that changed, parents
+    // before their children
+    pub fn update_world_matrices(&mut self) {
+        let mut stack: Vec<(NodeId, Matrix4<f32>)> = self.roots.iter().rev()
+            .map(|&root| (root, Matrix4::identity()))
+            .collect();
+        while let Some((id, parent_world)) = stack.pop() {
+            let node = self.node_mut(id);
+            if node.dirty {
+                node.world = parent_world * node.local.matrix();
+                node.dirty = false;
+            }
+            let world = node.world;
+            stack.extend(node.children.iter().rev().map(|&child| (child, world)));
+        }
+    }
+
+    // depth first, parents before their children
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> + '_ {
+        let mut order = Vec::new();
+        for &root in &self.roots {
+            order.extend(self.subtree(root));
+        }
+        order.into_iter().map(move |id| (id, self.node(id)))
+    }
+
+    // the active camera's view as a `camera::Camera`
+    pub fn camera(&self, aspect: f32) -> Option<camera::Camera> {
+        let node = self.node(self.active_camera?);
+        let component = node.camera?;
+        let eye = node.world_position();
+        Some(camera::Camera {
+            eye,
+            target: eye + node.world_forward(),
+            up: (node.world * Vector4::unit_y()).truncate().normalize(),
+            aspect,
+            fovy: component.fovy,
+            znear: component.znear,
+            zfar: component.zfar,
+        })
+    }
+
+    // moves the lights of light nodes to where the nodes are
+    pub fn place_lights(&self, lights: &mut [Light]) {
+        for (_, node) in self.iter() {
+            if let Some(light) = node.light.and_then(|i| lights.get_mut(i)) {
+                light.position = node.world_position();
+                light.direction = node.world_forward();
+            }
+        }
+    }
+
+    // the mesh nodes in drawing order, with the instance data for each
+    pub fn draw_list(&self) -> (Vec<DrawItem>, Vec<InstanceRaw>) {
+        self.iter()
+            .filter_map(|(node, n)| n.mesh.map(|instance| (
+                DrawItem { node, mesh: instance.mesh, material: instance.material },
+                InstanceRaw::new(n.world),
+            )))
+            .unzip()
+    }
+
+    // the nodes as stored in scene files, with the lights they place taken
+    // from `lights`
+    pub fn to_node_descs(&self, lights: &[Light]) -> Vec<NodeDesc> {
+        self.roots.iter().map(|&root| self.to_node_desc(root, lights)).collect()
+    }
+
+    fn to_node_desc(&self, id: NodeId, lights: &[Light]) -> NodeDesc {
+        let node = self.node(id);
+        NodeDesc {
+            name: node.name.clone(),
+            transform: node.local,
+            mesh: node.mesh,
+            light: node.light.and_then(|i| lights.get(i)).map(LightDesc::from),
+            camera: node.camera,
+            children: node.children.iter().map(|&child| self.to_node_desc(child, lights)).collect(),
+        }
+    }
+
+    fn subtree(&self, id: NodeId) -> Vec<NodeId> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            nodes.push(id);
+            stack.extend(self.node(id).children.iter().rev());
+        }
+        nodes
+    }
+
+    fn siblings_mut(&mut self, parent: Option<NodeId>) -> &mut Vec<NodeId> {
+        match parent {
+            Some(parent) => &mut self.node_mut(parent).children,
+            None => &mut self.roots,
+        }
+    }
+
+    fn mark_dirty(&mut self, id: NodeId) {
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let node = self.node_mut(id);
+            // the descendants of a dirty node are dirty already
+            if !node.dirty {
+                node.dirty = true;
+                stack.extend(node.children.iter().copied());
+            }
+        }
+    }
+}
+
+// a mesh node to draw, its instance data is at the same index
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DrawItem {
+    pub node: NodeId,
+    pub mesh: usize,
+    pub material: usize,
+}
+
+// per node vertex data in the second vertex buffer of mesh pipelines
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct InstanceRaw {
+    pub model: [[f32; 4]; 4],
+    // for normals and tangents, the inverse transpose of the model matrix
+    pub normal: [[f32; 3]; 3],
+}
+
+impl InstanceRaw {
+    pub fn new(model: Matrix4<f32>) -> Self {
+        let linear = Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+        let normal = linear.invert().map_or(linear, |inverse| inverse.transpose());
+        Self {
+            model: model.into(),
+            normal: normal.into(),
+        }
+    }
+
+    pub const SIZE: wgpu::BufferAddress = std::mem::size_of::<Self>() as wgpu::BufferAddress;
+
+    // the `index`th instance of a buffer of them
+    pub fn slice(buffer: &wgpu::Buffer, index: usize) -> wgpu::BufferSlice<'_> {
+        let start = index as wgpu::BufferAddress * Self::SIZE;
+        buffer.slice(start..start + Self::SIZE)
+    }
+
+    // locations 4 to 7 are the model matrix columns, 8 to 10 the normal matrix's
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        const ATTRIBUTES: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
+            4 => Float32x4, 5 => Float32x4, 6 => Float32x4, 7 => Float32x4,
+            8 => Float32x3, 9 => Float32x3, 10 => Float32x3,
+        ];
+        wgpu::VertexBufferLayout {
+            array_stride: Self::SIZE,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+// geometry on the gpu, drawn by every node whose `MeshInstance` names it
+pub struct Mesh {
+    pub name: String,
+    pub vertex_buffer: buffer::DynamicBuffer,
+    pub index_buffer: buffer::DynamicBuffer,
+    // un-indexed copy for the barycentric wireframe
+    pub wireframe_vertex_buffer: buffer::DynamicBuffer,
+    pub num_indices: u32,
+    // kept on the cpu for debug drawing and saving
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u16>,
+}
+
+impl Mesh {
+    pub fn new(device: &wgpu::Device, name: &str, vertices: &[Vertex], indices: &[u16]) -> Self {
+        // writable so the geometry can be replaced later
+        let vertex_buffer = buffer::DynamicBuffer::with_contents(
+            device,
+            &format!("{} Vertex Buffer", name),
+            wgpu::BufferUsages::VERTEX,
+            bytemuck::cast_slice(vertices),
+        );
+        let index_buffer = buffer::DynamicBuffer::with_contents(
+            device,
+            &format!("{} Index Buffer", name),
+            wgpu::BufferUsages::INDEX,
+            bytemuck::cast_slice(indices),
+        );
+        let wireframe_vertex_buffer = buffer::DynamicBuffer::with_contents(
+            device,
+            &format!("{} Wireframe Vertex Buffer", name),
+            wgpu::BufferUsages::VERTEX,
+            bytemuck::cast_slice(&debug_view::expand_triangles(vertices, indices)),
+        );
+        Self {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            wireframe_vertex_buffer,
+            num_indices: indices.len() as u32,
+            vertices: vertices.to_vec(),
+            indices: indices.to_vec(),
+        }
+    }
+
+    pub fn from_desc(device: &wgpu::Device, desc: &MeshDesc) -> Result<Self> {
+        let (vertices, indices) = desc.geometry()?;
+        Ok(Self::new(device, &desc.name, &vertices, &indices))
+    }
+
+    pub fn to_desc(&self) -> MeshDesc {
+        MeshDesc {
+            name: self.name.clone(),
+            positions: self.vertices.iter().map(|v| v.position).collect(),
+            tex_coords: self.vertices.iter().map(|v| v.tex_coords).collect(),
+            normals: self.vertices.iter().map(|v| v.normal).collect(),
+            indices: self.indices.clone(),
+        }
+    }
+}
+
+// geometry as stored in scene files, with tangents generated on load
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MeshDesc {
+    pub name: String,
+    pub positions: Vec<[f32; 3]>,
+    // one per position, like the normals
+    pub tex_coords: Vec<[f32; 2]>,
+    pub normals: Vec<[f32; 3]>,
+    // triangles with their vertices in counter-clockwise order
+    pub indices: Vec<u16>,
+}
+
+impl MeshDesc {
+    // the vertices with their tangents and the indices over them. vertices
+    // on uv seams may be split, so there can be more than in the desc
+    pub fn geometry(&self) -> Result<(Vec<Vertex>, Vec<u16>)> {
+        let indices: Vec<u32> = self.indices.iter().map(|&i| i as u32).collect();
+        let tangents = tangent::compute_tangents(&self.positions, &self.normals, &self.tex_coords, &indices);
+        if tangents.sources.len() > u16::MAX as usize + 1 {
+            bail!("mesh {} has more than {} vertices once split along its uv seams", self.name, u16::MAX as usize + 1);
+        }
+        let vertices = tangents.sources.iter().zip(&tangents.tangents)
+            .map(|(&i, &tangent)| Vertex {
+                position: self.positions[i as usize],
+                tex_coords: self.tex_coords[i as usize],
+                normal: self.normals[i as usize],
+                tangent,
+            })
+            .collect();
+        Ok((vertices, tangents.indices.iter().map(|&i| i as u16).collect()))
+    }
+
+    fn validate(&self) -> Result<()> {
+        let count = self.positions.len();
+        if self.tex_coords.len() != count || self.normals.len() != count {
+            bail!("mesh {} needs as many tex coords and normals as positions", self.name);
+        }
+        if count == 0 || self.indices.is_empty() {
+            bail!("mesh {} has no triangles", self.name);
+        }
+        if let Some(i) = self.normals.iter().position(|n| n.iter().all(|&c| c == 0.0)) {
+            bail!("mesh {} has a zero length normal at vertex {}", self.name, i);
+        }
+        if !self.indices.len().is_multiple_of(3) {
+            bail!("mesh {} has {} indices, not whole triangles", self.name, self.indices.len());
+        }
+        if let Some(index) = self.indices.iter().find(|&&i| i as usize >= count) {
+            bail!("mesh {} has index {} but {} vertices", self.name, index, count);
+        }
+        Ok(())
+    }
+}
+
+// a light in scene files. it is placed at its node's origin, shining down
+// the node's -z
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LightDesc {
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    // spot cone half angles in radians
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub cast_shadows: bool,
+}
+
+impl LightDesc {
+    pub fn light(&self) -> Light {
+        Light {
+            kind: self.kind,
+            position: Point3::origin(),
+            direction: -Vector3::unit_z(),
+            color: self.color,
+            intensity: self.intensity,
+            range: self.range,
+            inner_angle: self.inner_angle,
+            outer_angle: self.outer_angle,
+            cast_shadows: self.cast_shadows,
+        }
+    }
+}
+
+impl Default for LightDesc {
+    fn default() -> Self {
+        (&Light::point(Point3::origin(), [1.0; 3], 1.0, 1.0)).into()
+    }
+}
+
+impl From<&Light> for LightDesc {
+    fn from(light: &Light) -> Self {
+        Self {
+            kind: light.kind,
+            color: light.color,
+            intensity: light.intensity,
+            range: light.range,
+            inner_angle: light.inner_angle,
+            outer_angle: light.outer_angle,
+            cast_shadows: light.cast_shadows,
+        }
+    }
+}
+
+// a material in scene files, texture paths relative to the scene file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneMaterial {
+    pub material: MaterialDesc,
+    // the fragment entry point of the app's unlit pipeline, its default
+    // one if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unlit_shader: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeDesc {
+    pub name: String,
+    #[serde(default)]
+    pub transform: Transform,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<MeshInstance>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub light: Option<LightDesc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<CameraComponent>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<NodeDesc>,
+}
+
+// a scene as stored in .json or .ron scene files
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SceneDesc {
+    pub clear_color: [f64; 4],
+    // the pipeline the meshes are drawn with at first
+    pub shading: Shading,
+    pub meshes: Vec<MeshDesc>,
+    pub materials: Vec<SceneMaterial>,
+    // the root nodes. the first camera node, depth first, is the active one
+    pub nodes: Vec<NodeDesc>,
+}
+
+impl Default for SceneDesc {
+    fn default() -> Self {
+        Self {
+            clear_color: [1.0; 4],
+            shading: Shading::Unlit,
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+}
+
+impl SceneDesc {
+    pub fn from_json(json: &str) -> Result<Self> {
+        let desc: Self = serde_json::from_str(json)?;
+        desc.validate()?;
+        Ok(desc)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_ron(ron: &str) -> Result<Self> {
+        let desc: Self = ron::from_str(ron)?;
+        desc.validate()?;
+        Ok(desc)
+    }
+
+    pub fn to_ron(&self) -> Result<String> {
+        Ok(ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?)
+    }
+
+    // .ron files are read as ron, anything else as json
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read scene {}", path.display()))?;
+        let desc = if is_ron(path) { Self::from_ron(&text) } else { Self::from_json(&text) };
+        desc.with_context(|| format!("invalid scene {}", path.display()))
+    }
+
+    // texture paths are kept as they are, so they stay valid for files
+    // saved next to the one the scene was loaded from
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let text = if is_ron(path) { self.to_ron()? } else { self.to_json()? };
+        std::fs::write(path, text)
+            .with_context(|| format!("can't write scene {}", path.display()))
+    }
+
+    // the materials with their textures, read relative to `dir`, the
+    // directory of the scene file
+    pub fn load_materials(&self, dir: &Path) -> Result<Vec<LoadedMaterial>> {
+        self.load_materials_with(|texture| Ok(std::fs::read(dir.join(texture))?))
+    }
+
+    // with the bytes of each texture path from `read`, for scenes that
+    // aren't files
+    pub fn load_materials_with<F>(&self, mut read: F) -> Result<Vec<LoadedMaterial>>
+    where
+        F: FnMut(&str) -> Result<Vec<u8>>,
+    {
+        self.materials.iter()
+            .map(|m| LoadedMaterial::from_desc(m.material.clone(), &mut read))
+            .collect()
+    }
+
+    // the node tree, appending the lights of light nodes to `lights`
+    pub fn graph(&self, lights: &mut Vec<Light>) -> SceneGraph {
+        let mut graph = SceneGraph::new();
+        let mut stack: Vec<(&NodeDesc, Option<NodeId>)> = self.nodes.iter().rev().map(|node| (node, None)).collect();
+        while let Some((desc, parent)) = stack.pop() {
+            let id = graph.add(&desc.name, parent);
+            let node = graph.node_mut(id);
+            node.local = desc.transform;
+            node.mesh = desc.mesh;
+            node.camera = desc.camera;
+            if let Some(light) = &desc.light {
+                lights.push(light.light());
+                node.light = Some(lights.len() - 1);
+            }
+            if desc.camera.is_some() && graph.active_camera.is_none() {
+                graph.active_camera = Some(id);
+            }
+            stack.extend(desc.children.iter().rev().map(|child| (child, Some(id))));
+        }
+        graph
+    }
+
+    fn validate(&self) -> Result<()> {
+        for mesh in &self.meshes {
+            mesh.validate()?;
+        }
+        let mut stack: Vec<&NodeDesc> = self.nodes.iter().collect();
+        while let Some(node) = stack.pop() {
+            if let Some(instance) = node.mesh {
+                if instance.mesh >= self.meshes.len() || instance.material >= self.materials.len() {
+                    bail!("node {} uses mesh {} and material {}, there are {} and {}",
+                        node.name, instance.mesh, instance.material, self.meshes.len(), self.materials.len());
+                }
+            }
+            stack.extend(&node.children);
+        }
+        Ok(())
+    }
+}
+
+fn is_ron(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "ron")
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Rotation3;
+
+    use super::*;
+
+    fn assert_matrix_eq(a: Matrix4<f32>, b: Matrix4<f32>) {
+        let (a, b): ([[f32; 4]; 4], [[f32; 4]; 4]) = (a.into(), b.into());
+        for (x, y) in a.iter().flatten().zip(b.iter().flatten()) {
+            assert!((x - y).abs() < 1e-5, "{:?} vs {:?}", a, b);
+        }
+    }
+
+    // a parent turned a quarter around y with a child and grandchild below it
+    fn graph() -> (SceneGraph, NodeId, NodeId, NodeId) {
+        let mut graph = SceneGraph::new();
+        let parent = graph.add("parent", None);
+        let child = graph.add("child", Some(parent));
+        let grandchild = graph.add("grandchild", Some(child));
+        graph.set_transform(parent, Transform {
+            translation: Vector3::new(1.0, 0.0, 0.0),
+            rotation: Quaternion::from_angle_y(cgmath::Deg(90.0)),
+            scale: Vector3::new(2.0, 2.0, 2.0),
+        });
+        graph.set_transform(child, Transform::from_translation(Vector3::new(0.0, 0.0, 1.0)));
+        graph.set_transform(grandchild, Transform::from_translation(Vector3::new(0.0, 1.0, 0.0)));
+        graph.update_world_matrices();
+        (graph, parent, child, grandchild)
+    }
+
+    #[test]
+    fn world_is_parent_times_local() {
+        let (graph, parent, child, grandchild) = graph();
+        let parent_world = graph.node(parent).local().matrix();
+        let child_world = parent_world * graph.node(child).local().matrix();
+        assert_matrix_eq(graph.node(parent).world_matrix(), parent_world);
+        assert_matrix_eq(graph.node(child).world_matrix(), child_world);
+        assert_matrix_eq(graph.node(grandchild).world_matrix(), child_world * graph.node(grandchild).local().matrix());
+        // +z turned a quarter around y is +x, scaled by 2 and moved by 1
+        let position = graph.node(child).world_position();
+        assert!((position - Point3::new(3.0, 0.0, 0.0)).magnitude() < 1e-5, "{:?}", position);
+    }
+
+    #[test]
+    fn moving_a_parent_updates_its_descendants() {
+        let (mut graph, parent, child, grandchild) = graph();
+        assert!(graph.iter().all(|(_, node)| !node.dirty));
+        graph.set_transform(parent, Transform::from_translation(Vector3::new(0.0, 5.0, 0.0)));
+        assert!([parent, child, grandchild].iter().all(|&id| graph.node(id).dirty));
+        graph.update_world_matrices();
+        assert!(graph.iter().all(|(_, node)| !node.dirty));
+        let position = graph.node(grandchild).world_position();
+        assert!((position - Point3::new(0.0, 6.0, 1.0)).magnitude() < 1e-5, "{:?}", position);
+    }
+
+    #[test]
+    fn moving_a_child_leaves_its_parent_clean() {
+        let (mut graph, parent, child, grandchild) = graph();
+        graph.set_transform(child, Transform::default());
+        assert!(!graph.node(parent).dirty);
+        assert!(graph.node(child).dirty && graph.node(grandchild).dirty);
+    }
+
+    #[test]
+    fn set_parent_rejects_descendants() {
+        let (mut graph, parent, child, grandchild) = graph();
+        assert!(graph.set_parent(parent, Some(grandchild)).is_err());
+        assert!(graph.set_parent(parent, Some(parent)).is_err());
+        assert_eq!(graph.node(parent).parent(), None);
+        assert_eq!(graph.roots(), &[parent]);
+
+        graph.set_parent(grandchild, None).unwrap();
+        assert_eq!(graph.roots(), &[parent, grandchild]);
+        assert!(graph.node(child).children().is_empty());
+        graph.update_world_matrices();
+        let position = graph.node(grandchild).world_position();
+        assert!((position - Point3::new(0.0, 1.0, 0.0)).magnitude() < 1e-5, "{:?}", position);
+    }
+
+    #[test]
+    fn remove_drops_the_subtree() {
+        let (mut graph, parent, child, grandchild) = graph();
+        let other = graph.add("other", None);
+        graph.node_mut(grandchild).camera = Some(CameraComponent { fovy: 45.0, znear: 0.1, zfar: 100.0 });
+        graph.active_camera = Some(grandchild);
+        graph.remove(child);
+        assert!(graph.contains(parent) && graph.contains(other));
+        assert!(!graph.contains(child) && !graph.contains(grandchild));
+        assert!(graph.node(parent).children().is_empty());
+        assert_eq!(graph.active_camera, None);
+        assert_eq!(graph.iter().map(|(id, _)| id).collect::<Vec<_>>(), [parent, other]);
+        // ids of removed nodes aren't reused
+        assert_eq!(graph.add("new", None), NodeId(4));
+    }
+
+    // one triangle drawn by one node
+    fn minimal_scene() -> SceneDesc {
+        SceneDesc {
+            clear_color: [0.0, 0.0, 0.0, 1.0],
+            shading: Shading::Unlit,
+            meshes: vec![MeshDesc {
+                name: "triangle".to_string(),
+                positions: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+                tex_coords: vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+                normals: vec![[0.0, 0.0, 1.0]; 3],
+                indices: vec![0, 1, 2],
+            }],
+            materials: vec![SceneMaterial { material: MaterialDesc::default(), unlit_shader: None }],
+            nodes: vec![NodeDesc {
+                name: "node".to_string(),
+                transform: Transform::default(),
+                mesh: Some(MeshInstance { mesh: 0, material: 0 }),
+                light: None,
+                camera: None,
+                children: Vec::new(),
+            }],
+        }
+    }
+
+    fn assert_rejected(desc: &SceneDesc, message: &str) {
+        let error = desc.validate().unwrap_err().to_string();
+        assert!(error.contains(message), "{:?} doesn't mention {:?}", error, message);
+        // the loaders validate too
+        assert!(SceneDesc::from_json(&desc.to_json().unwrap()).is_err());
+        assert!(SceneDesc::from_ron(&desc.to_ron().unwrap()).is_err());
+    }
+
+    #[test]
+    fn demo_scene_round_trips() {
+        let desc = SceneDesc::from_json(include_str!("scenes/demo.json")).unwrap();
+        // saved the way the demo saves it, from the graph it was loaded into
+        let mut lights = Vec::new();
+        let mut graph = desc.graph(&mut lights);
+        graph.update_world_matrices();
+        let saved = SceneDesc { nodes: graph.to_node_descs(&lights), ..desc.clone() };
+        assert_eq!(saved, desc);
+
+        assert_eq!(SceneDesc::from_json(&saved.to_json().unwrap()).unwrap(), saved);
+        assert_eq!(SceneDesc::from_ron(&saved.to_ron().unwrap()).unwrap(), saved);
+        for extension in ["json", "ron"] {
+            let path = std::env::temp_dir().join(format!("learn-wgpu-scene-{}.{}", std::process::id(), extension));
+            saved.save(&path).unwrap();
+            let text = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(text.trim_start().starts_with('{'), extension == "json");
+            let loaded = SceneDesc::load(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.unwrap(), saved);
+        }
+    }
+
+    #[test]
+    fn validate_accepts_a_minimal_scene() {
+        minimal_scene().validate().unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_mismatched_attributes() {
+        let mut desc = minimal_scene();
+        desc.meshes[0].tex_coords.pop();
+        assert_rejected(&desc, "as many tex coords and normals");
+        let mut desc = minimal_scene();
+        desc.meshes[0].normals.push([0.0, 0.0, 1.0]);
+        assert_rejected(&desc, "as many tex coords and normals");
+    }
+
+    #[test]
+    fn validate_rejects_partial_triangles() {
+        let mut desc = minimal_scene();
+        desc.meshes[0].indices.push(0);
+        assert_rejected(&desc, "not whole triangles");
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_indices() {
+        let mut desc = minimal_scene();
+        desc.meshes[0].indices[2] = 3;
+        assert_rejected(&desc, "has index 3 but 3 vertices");
+    }
+
+    #[test]
+    fn validate_rejects_empty_meshes_and_zero_normals() {
+        let mut desc = minimal_scene();
+        desc.meshes[0].indices.clear();
+        assert_rejected(&desc, "no triangles");
+        let mut desc = minimal_scene();
+        desc.meshes[0].normals[1] = [0.0; 3];
+        assert_rejected(&desc, "zero length normal at vertex 1");
+    }
+
+    #[test]
+    fn validate_rejects_missing_meshes_and_materials() {
+        let mut desc = minimal_scene();
+        desc.nodes[0].mesh = Some(MeshInstance { mesh: 1, material: 0 });
+        assert_rejected(&desc, "uses mesh 1 and material 0");
+        // nested nodes are checked too
+        let mut desc = minimal_scene();
+        let mut child = desc.nodes[0].clone();
+        child.mesh = Some(MeshInstance { mesh: 0, material: 1 });
+        desc.nodes[0].children.push(child);
+        assert_rejected(&desc, "uses mesh 0 and material 1");
+    }
+}
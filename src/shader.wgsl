@@ -5,23 +5,53 @@ struct VertexInput {
     @location(1) tex_coords: vec2<f32>,
 };
 
+// the node's world matrix, from the instance buffer
+struct InstanceInput {
+    @location(4) model_0: vec4<f32>,
+    @location(5) model_1: vec4<f32>,
+    @location(6) model_2: vec4<f32>,
+    @location(7) model_3: vec4<f32>,
+};
+
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
+    @location(1) @interpolate(flat) vertex_index: u32,
 };
 
 @vertex
-fn vs_main(model: VertexInput) -> VertexOutput {
+fn vs_main(model: VertexInput, instance: InstanceInput, @builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
-    out.clip_position = vec4<f32>(model.position, 1.0);
+    // the unlit view has no camera, world space is clip space
+    out.clip_position = model_matrix * vec4<f32>(model.position, 1.0);
+    out.vertex_index = vertex_index;
+    return out;
+}
+
+struct BarycentricOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) barycentric: vec3<f32>,
+};
+
+// wireframe fallback when line polygon mode isn't supported,
+// expects un-indexed triangles so each corner gets its own coordinate
+@vertex
+fn vs_barycentric(model: VertexInput, instance: InstanceInput, @builtin(vertex_index) vertex_index: u32) -> BarycentricOutput {
+    let model_matrix = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    var out: BarycentricOutput;
+    out.clip_position = model_matrix * vec4<f32>(model.position, 1.0);
+    let corner = vertex_index % 3u;
+    out.barycentric = vec3<f32>(f32(corner == 0u), f32(corner == 1u), f32(corner == 2u));
     return out;
 }
 
 // Fragment shader
-@group(0) @binding(0) // from 1st parameter in set_bind_group()
+// the base color of the material bind group, the rest of it is unused
+@group(0) @binding(1) // from 1st parameter in set_bind_group()
 var t_diffuse: texture_2d<f32>;
-@group(0) @binding(1) // from binding specified in BindGroupLayout and BindGroup
+@group(0) @binding(2) // from binding specified in BindGroupLayout and BindGroup
 var s_diffuse: sampler;
 
 @fragment
@@ -37,4 +67,35 @@ fn fs_main2(in: VertexOutput) -> @location(0) vec4<f32> {
     return textureSample(t_diffuse, s_diffuse, in.tex_coords);
     // set the color
     // return vec4<f32>(in.clip_position.x, in.clip_position.y, in.clip_position.z, 1.0);
-}
\ No newline at end of file
+}
+
+// Debug view fragment shaders
+@fragment
+fn fs_wireframe(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+}
+
+@fragment
+fn fs_wireframe_barycentric(in: BarycentricOutput) -> @location(0) vec4<f32> {
+    // distance to the nearest edge in pixels
+    let width = fwidth(in.barycentric);
+    let edge = smoothstep(vec3<f32>(0.0), width * 1.5, in.barycentric);
+    let coverage = 1.0 - min(min(edge.x, edge.y), edge.z);
+    if coverage < 0.01 {
+        discard;
+    }
+    return vec4<f32>(0.0, 0.0, 0.0, coverage);
+}
+
+@fragment
+fn fs_uvs(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(in.tex_coords, 0.0, 1.0);
+}
+
+@fragment
+fn fs_vertex_index(in: VertexOutput) -> @location(0) vec4<f32> {
+    // hash the index so neighbouring vertices get unrelated colors
+    let i = in.vertex_index + 1u;
+    let hash = vec3<u32>(i * 2654435761u, i * 2246822519u, i * 3266489917u) >> vec3<u32>(24u);
+    return vec4<f32>(vec3<f32>(hash) / 255.0, 1.0);
+}
@@ -0,0 +1,487 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Vector3, Vector4};
+use wgpu::util::DeviceExt;
+
+use crate::camera::{Camera, OPENGL_TO_WGPU_MATRIX};
+use crate::debug_draw::DebugDraw;
+use crate::lighting::{Light, LightKind};
+use crate::scene::InstanceRaw;
+
+pub const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+// layers of the shadow map array, shared by all shadow casting lights.
+// must match the array size in shadow.wgsl
+pub const MAX_SHADOW_MAPS: usize = 8;
+// must fit the vec4 of split depths in shadow.wgsl
+pub const MAX_CASCADES: u32 = 4;
+
+// the shadow sampling functions, appended to the lit shaders that use them
+pub const SHADER: &str = include_str!("shadow.wgsl");
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShadowSettings {
+    // width and height of every shadow map
+    pub resolution: u32,
+    // depth bias of the depth pass, in depth buffer units and scaled by the slope
+    pub constant_bias: i32,
+    pub slope_bias: f32,
+    // receivers are moved this far along their normal before the lookup
+    pub normal_bias: f32,
+    // pcf samples a (2r + 1)² texel square
+    pub pcf_radius: u32,
+    // cascades of directional lights, up to MAX_CASCADES
+    pub cascade_count: u32,
+    // blend between uniform (0) and logarithmic (1) cascade splits
+    pub split_lambda: f32,
+    // directional shadows end this far from the camera
+    pub max_distance: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            constant_bias: 2,
+            slope_bias: 2.0,
+            normal_bias: 0.01,
+            pcf_radius: 1,
+            cascade_count: 3,
+            split_lambda: 0.6,
+            max_distance: 10.0,
+        }
+    }
+}
+
+// the layers of the shadow map array used by one light
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ShadowLayers {
+    pub first: u32,
+    pub count: u32,
+}
+
+// must match the Shadows struct in shadow.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowsUniform {
+    view_proj: [[[f32; 4]; 4]; MAX_SHADOW_MAPS],
+    // far view depth of each cascade
+    cascade_splits: [f32; 4],
+    camera_position: [f32; 4],
+    camera_forward: [f32; 4],
+    // texel size, pcf radius, normal bias
+    params: [f32; 4],
+}
+
+// a depth texture array rendered from the shadow casting lights, and what
+// the lit shaders need to sample it at group 3
+pub struct ShadowMaps {
+    settings: ShadowSettings,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+    _texture: wgpu::Texture,
+    layer_views: Vec<wgpu::TextureView>,
+    sampler: wgpu::Sampler,
+    uniform_buffer: wgpu::Buffer,
+    // one light matrix per layer for the depth pass, at dynamic offsets
+    pass_buffer: wgpu::Buffer,
+    pass_stride: u32,
+    pass_bind_group: wgpu::BindGroup,
+    pass_bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+    vertex_layout: wgpu::VertexBufferLayout<'static>,
+    view_projs: Vec<Matrix4<f32>>,
+    assignments: Vec<Option<ShadowLayers>>,
+}
+
+impl ShadowMaps {
+    // `vertex_layout` is the one of the meshes casting shadows, with the
+    // position at location 0. their `scene::InstanceRaw` is the second
+    // vertex buffer
+    pub fn new(
+        device: &wgpu::Device,
+        settings: ShadowSettings,
+        vertex_layout: wgpu::VertexBufferLayout<'static>,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("shadow_bind_group_layout"),
+        });
+        let pass_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<[[f32; 4]; 4]>() as u64),
+                },
+                count: None,
+            }],
+            label: Some("shadow_pass_bind_group_layout"),
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadows Buffer"),
+            size: std::mem::size_of::<ShadowsUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let pass_stride = device.limits().min_uniform_buffer_offset_alignment
+            .max(std::mem::size_of::<[[f32; 4]; 4]>() as u32);
+        let pass_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Shadow Pass Buffer"),
+            contents: &vec![0; (pass_stride as usize) * MAX_SHADOW_MAPS],
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let pass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &pass_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &pass_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of::<[[f32; 4]; 4]>() as u64),
+                }),
+            }],
+            label: Some("shadow_pass_bind_group"),
+        });
+        let texture = Self::create_texture(device, settings.resolution);
+        let layer_views = Self::create_layer_views(&texture);
+        let bind_group = Self::create_bind_group(
+            device, &bind_group_layout, &layer_views[MAX_SHADOW_MAPS], &sampler, &uniform_buffer);
+        let pipeline = Self::create_pipeline(device, &pass_bind_group_layout, &settings, vertex_layout.clone());
+        Self {
+            settings,
+            bind_group_layout,
+            bind_group,
+            _texture: texture,
+            layer_views,
+            sampler,
+            uniform_buffer,
+            pass_buffer,
+            pass_stride,
+            pass_bind_group,
+            pass_bind_group_layout,
+            pipeline,
+            vertex_layout,
+            view_projs: Vec::new(),
+            assignments: Vec::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &ShadowSettings {
+        &self.settings
+    }
+
+    // recreates the maps for a new resolution and the pipeline for new biases
+    pub fn set_settings(&mut self, device: &wgpu::Device, settings: ShadowSettings) {
+        if settings.resolution != self.settings.resolution {
+            self._texture = Self::create_texture(device, settings.resolution);
+            self.layer_views = Self::create_layer_views(&self._texture);
+            self.bind_group = Self::create_bind_group(
+                device, &self.bind_group_layout, &self.layer_views[MAX_SHADOW_MAPS], &self.sampler, &self.uniform_buffer);
+        }
+        self.pipeline = Self::create_pipeline(
+            device, &self.pass_bind_group_layout, &settings, self.vertex_layout.clone());
+        self.settings = settings;
+    }
+
+    // the whole array, to declare the dependency between passes in a render graph
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.layer_views[MAX_SHADOW_MAPS]
+    }
+
+    // layers assigned to each light by the last `update`, in the order of the lights
+    pub fn assignments(&self) -> &[Option<ShadowLayers>] {
+        &self.assignments
+    }
+
+    // the number of layers to render, see `begin_pass`
+    pub fn layer_count(&self) -> usize {
+        self.view_projs.len()
+    }
+
+    // assigns layers to the lights with `cast_shadows` set, in order until the
+    // array is full, and computes their light matrices. directional lights get
+    // a layer per cascade, spot lights one. point lights are not supported
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: &Camera, lights: &[Light]) {
+        let cascade_count = self.settings.cascade_count.clamp(1, MAX_CASCADES);
+        let splits = cascade_splits(camera, &self.settings, cascade_count);
+        self.view_projs.clear();
+        self.assignments.clear();
+        for light in lights {
+            let needed = match light.kind {
+                LightKind::Directional => cascade_count as usize,
+                LightKind::Spot => 1,
+                LightKind::Point => 0,
+            };
+            if !light.cast_shadows || needed == 0 || self.view_projs.len() + needed > MAX_SHADOW_MAPS {
+                if light.cast_shadows && needed > 0 {
+                    log::warn!("out of shadow maps, a light casts no shadows");
+                }
+                self.assignments.push(None);
+                continue;
+            }
+            let first = self.view_projs.len() as u32;
+            match light.kind {
+                LightKind::Directional => {
+                    let mut near = camera.znear;
+                    for &far in &splits[..cascade_count as usize] {
+                        let view_proj = self.cascade_view_proj(camera, light.direction, near, far);
+                        self.view_projs.push(view_proj);
+                        near = far;
+                    }
+                }
+                _ => self.view_projs.push(spot_view_proj(light)),
+            }
+            self.assignments.push(Some(ShadowLayers { first, count: needed as u32 }));
+        }
+
+        let mut uniform = ShadowsUniform {
+            view_proj: [Matrix4::identity().into(); MAX_SHADOW_MAPS],
+            cascade_splits: splits,
+            camera_position: camera.eye.to_homogeneous().into(),
+            camera_forward: (camera.target - camera.eye).normalize().extend(0.0).into(),
+            params: [
+                1.0 / self.settings.resolution as f32,
+                self.settings.pcf_radius as f32,
+                self.settings.normal_bias,
+                0.0,
+            ],
+        };
+        for (i, view_proj) in self.view_projs.iter().enumerate() {
+            uniform.view_proj[i] = (*view_proj).into();
+            let matrix: [[f32; 4]; 4] = (*view_proj).into();
+            queue.write_buffer(&self.pass_buffer, i as u64 * self.pass_stride as u64, bytemuck::cast_slice(&matrix));
+        }
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+
+    // a depth only pass into `layer` with the pipeline and light matrix set,
+    // ready for the caster's vertex buffers and draws
+    pub fn begin_pass<'a>(&'a self, encoder: &'a mut wgpu::CommandEncoder, layer: usize) -> wgpu::RenderPass<'a> {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow Pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.layer_views[layer],
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.pass_bind_group, &[layer as u32 * self.pass_stride]);
+        pass
+    }
+
+    // the volume covered by each shadow map
+    pub fn draw_debug(&self, debug_draw: &mut DebugDraw) {
+        for view_proj in self.view_projs.iter() {
+            debug_draw.frustum(*view_proj, [0.6, 0.2, 1.0, 1.0]);
+        }
+    }
+
+    // an orthographic projection around the bounding sphere of the camera
+    // frustum slice, moved in whole texels so the shadow edges don't shimmer
+    fn cascade_view_proj(&self, camera: &Camera, direction: Vector3<f32>, near: f32, far: f32) -> Matrix4<f32> {
+        let direction = if direction.magnitude2() > 0.0 { direction.normalize() } else { -Vector3::unit_y() };
+        let inverse_view = camera.view_matrix().invert().unwrap_or_else(Matrix4::identity);
+        let tan_y = (camera.fovy.to_radians() * 0.5).tan();
+        let tan_x = tan_y * camera.aspect;
+        let mut corners = Vec::with_capacity(8);
+        for depth in [near, far] {
+            for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                let view_corner = Vector4::new(x * tan_x * depth, y * tan_y * depth, -depth, 1.0);
+                corners.push(Point3::from_homogeneous(inverse_view * view_corner));
+            }
+        }
+        let center = Point3::centroid(&corners);
+        let radius = corners.iter()
+            .map(|c| (c - center).magnitude())
+            .fold(0.0f32, f32::max);
+        // quantized so the projection doesn't change size while the camera turns
+        let radius = (radius * 16.0).ceil() / 16.0;
+        // casters up to this far behind the slice still land in the map
+        let caster_distance = radius.max(self.settings.max_distance);
+        let up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+        let eye = center - direction * (radius + caster_distance);
+        let view = Matrix4::look_at_rh(eye, center, up);
+        let mut projection = OPENGL_TO_WGPU_MATRIX
+            * cgmath::ortho(-radius, radius, -radius, radius, 0.0, 2.0 * radius + caster_distance);
+        let half_resolution = self.settings.resolution as f32 * 0.5;
+        let origin = (projection * view).w.truncate() * half_resolution;
+        let offset = (origin.map(f32::round) - origin) / half_resolution;
+        projection.w.x += offset.x;
+        projection.w.y += offset.y;
+        projection * view
+    }
+
+    fn create_texture(device: &wgpu::Device, resolution: u32) -> wgpu::Texture {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Shadow Maps"),
+            size: wgpu::Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: MAX_SHADOW_MAPS as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: SHADOW_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+    }
+
+    // one view per layer to render into, followed by the array view
+    fn create_layer_views(texture: &wgpu::Texture) -> Vec<wgpu::TextureView> {
+        let mut views: Vec<_> = (0..MAX_SHADOW_MAPS as u32)
+            .map(|layer| texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("Shadow Map Layer"),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: std::num::NonZeroU32::new(1),
+                ..Default::default()
+            }))
+            .collect();
+        views.push(texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Shadow Maps"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        }));
+        views
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        view: &wgpu::TextureView,
+        sampler: &wgpu::Sampler,
+        uniform_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("shadow_bind_group"),
+        })
+    }
+
+    // depth only, no fragment stage. both faces are drawn so open meshes
+    // like the demo's still cast shadows
+    fn create_pipeline(
+        device: &wgpu::Device,
+        pass_bind_group_layout: &wgpu::BindGroupLayout,
+        settings: &ShadowSettings,
+        vertex_layout: wgpu::VertexBufferLayout<'static>,
+    ) -> wgpu::RenderPipeline {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Shadow Depth Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shadow_depth.wgsl").into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[pass_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Shadow Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[vertex_layout, InstanceRaw::desc()],
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: SHADOW_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState {
+                    constant: settings.constant_bias,
+                    slope_scale: settings.slope_bias,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+}
+
+// far view depth of each cascade, mixing uniform and logarithmic splits.
+// unused entries repeat the last split
+fn cascade_splits(camera: &Camera, settings: &ShadowSettings, cascade_count: u32) -> [f32; 4] {
+    let near = camera.znear;
+    let far = camera.zfar.min(settings.max_distance).max(near);
+    let mut splits = [far; 4];
+    for (i, split) in splits.iter_mut().enumerate().take(cascade_count as usize) {
+        let t = (i + 1) as f32 / cascade_count as f32;
+        let uniform = near + (far - near) * t;
+        let logarithmic = near * (far / near).powf(t);
+        *split = settings.split_lambda * logarithmic + (1.0 - settings.split_lambda) * uniform;
+    }
+    splits
+}
+
+// a perspective projection covering the spot cone
+fn spot_view_proj(light: &Light) -> Matrix4<f32> {
+    let direction = if light.direction.magnitude2() > 0.0 { light.direction.normalize() } else { -Vector3::unit_y() };
+    let up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+    let view = Matrix4::look_at_rh(light.position, light.position + direction, up);
+    let fovy = cgmath::Rad((2.0 * light.outer_angle).clamp(0.01, 3.0));
+    let far = light.range.max(0.1);
+    OPENGL_TO_WGPU_MATRIX * cgmath::perspective(fovy, 1.0, (far * 0.001).min(0.05), far) * view
+}
@@ -0,0 +1,60 @@
+// Shadow sampling, appended to the lit shaders
+
+struct Shadows {
+    view_proj: array<mat4x4<f32>, 8>,
+    // far view depth of each cascade
+    cascade_splits: vec4<f32>,
+    camera_position: vec4<f32>,
+    camera_forward: vec4<f32>,
+    // x texel size, y pcf radius in texels, z normal bias
+    params: vec4<f32>,
+};
+@group(3) @binding(0)
+var t_shadow: texture_depth_2d_array;
+@group(3) @binding(1)
+var s_shadow: sampler_comparison;
+@group(3) @binding(2)
+var<uniform> shadows: Shadows;
+
+// 1 where the light reaches the surface, 0 in full shadow. `layers` is the
+// light's first layer and count, the first is negative without shadows
+fn shadow_factor(layers: vec2<i32>, world_position: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if layers.x < 0 {
+        return 1.0;
+    }
+    var layer = layers.x;
+    if layers.y > 1 {
+        // directional lights pick the cascade by view depth
+        let view_depth = dot(world_position - shadows.camera_position.xyz, shadows.camera_forward.xyz);
+        var cascade = -1;
+        for (var i = 0; i < layers.y; i += 1) {
+            if view_depth < shadows.cascade_splits[i] {
+                cascade = i;
+                break;
+            }
+        }
+        if cascade < 0 {
+            return 1.0;
+        }
+        layer += cascade;
+    }
+    // pushing the lookup off the surface fights acne where the bias isn't enough
+    let position = world_position + normal * shadows.params.z;
+    let clip = shadows.view_proj[layer] * vec4<f32>(position, 1.0);
+    let ndc = clip.xyz / clip.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    if any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0 {
+        return 1.0;
+    }
+    // percentage closer filtering over a square of texels
+    let radius = i32(shadows.params.y);
+    var lit = 0.0;
+    for (var y = -radius; y <= radius; y += 1) {
+        for (var x = -radius; x <= radius; x += 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * shadows.params.x;
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, uv + offset, layer, ndc.z);
+        }
+    }
+    let samples = f32((2 * radius + 1) * (2 * radius + 1));
+    return lit / samples;
+}
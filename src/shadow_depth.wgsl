@@ -0,0 +1,18 @@
+// Vertex shader
+
+// the light matrix of the layer being rendered
+@group(0) @binding(0)
+var<uniform> light_view_proj: mat4x4<f32>;
+
+@vertex
+fn vs_main(
+    @location(0) position: vec3<f32>,
+    // the caster's world matrix, from the instance buffer
+    @location(4) model_0: vec4<f32>,
+    @location(5) model_1: vec4<f32>,
+    @location(6) model_2: vec4<f32>,
+    @location(7) model_3: vec4<f32>,
+) -> @builtin(position) vec4<f32> {
+    let model = mat4x4<f32>(model_0, model_1, model_2, model_3);
+    return light_view_proj * model * vec4<f32>(position, 1.0);
+}
@@ -0,0 +1,40 @@
+// Vertex shader
+
+struct Camera {
+    view_proj: mat4x4<f32>,
+    view_position: vec4<f32>,
+    inverse_view_proj: mat4x4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> camera: Camera;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+// a fullscreen triangle on the far plane
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let x = f32(i32(index & 1u) * 4 - 1);
+    let y = f32(i32(index >> 1u) * 4 - 1);
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 1.0, 1.0);
+    out.ndc = vec2<f32>(x, y);
+    return out;
+}
+
+// Fragment shader
+
+@group(0) @binding(0)
+var t_environment: texture_cube<f32>;
+@group(0) @binding(1)
+var s_environment: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // the view ray through this pixel, from the camera to the far plane
+    let far = camera.inverse_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let dir = normalize(far.xyz / far.w - camera.view_position.xyz);
+    return vec4<f32>(textureSampleLevel(t_environment, s_environment, dir, 0.0).rgb, 1.0);
+}
@@ -0,0 +1,346 @@
+use wgpu::util::DeviceExt;
+
+use crate::atlas::UvRect;
+use crate::buffer::DynamicBuffer;
+use crate::texture;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SpriteVertex {
+    pub position: [f32; 3],
+    pub tex_coords: [f32; 2],
+    pub color: [f32; 4],
+}
+
+impl SpriteVertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 3] =
+        wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x4];
+
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<SpriteVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+// index of a texture registered with `SpriteBatch::add_texture`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpriteTextureId(usize);
+
+#[derive(Copy, Clone, Debug)]
+pub struct Sprite {
+    pub texture: SpriteTextureId,
+    // in pixels, y pointing down
+    pub position: [f32; 2],
+    // radians, clockwise on screen
+    pub rotation: f32,
+    pub scale: [f32; 2],
+    // unscaled size in pixels
+    pub size: [f32; 2],
+    // pivot for rotation and scale, 0..1 across the sprite
+    pub origin: [f32; 2],
+    pub uv: UvRect,
+    pub tint: [f32; 4],
+    // lower layers are drawn first
+    pub layer: i32,
+}
+
+impl Sprite {
+    pub fn new(texture: SpriteTextureId, size: [f32; 2]) -> Self {
+        Self {
+            texture,
+            position: [0.0, 0.0],
+            rotation: 0.0,
+            scale: [1.0, 1.0],
+            size,
+            origin: [0.0, 0.0],
+            uv: UvRect::FULL,
+            tint: [1.0, 1.0, 1.0, 1.0],
+            layer: 0,
+        }
+    }
+
+    fn vertices(&self) -> [SpriteVertex; 4] {
+        let (sin, cos) = self.rotation.sin_cos();
+        let w = self.size[0] * self.scale[0];
+        let h = self.size[1] * self.scale[1];
+        let corners = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+        corners.map(|[u, v]| {
+            let x = (u - self.origin[0]) * w;
+            let y = (v - self.origin[1]) * h;
+            SpriteVertex {
+                position: [
+                    self.position[0] + x * cos - y * sin,
+                    self.position[1] + x * sin + y * cos,
+                    0.0,
+                ],
+                tex_coords: self.uv.remap([u, v]),
+                color: self.tint,
+            }
+        })
+    }
+}
+
+// a run of consecutive sprites sharing a texture, drawn with one call
+#[derive(Clone, Debug, PartialEq)]
+struct DrawBatch {
+    texture: SpriteTextureId,
+    indices: std::ops::Range<u32>,
+}
+
+// sorts `sprites` into draw order and appends a batch per run of one
+// texture, returning their vertices, four per sprite in the sorted order
+fn batch_sprites(sprites: &mut [Sprite], batches: &mut Vec<DrawBatch>) -> Vec<SpriteVertex> {
+    // stable, so submission order is kept within a layer and texture
+    sprites.sort_by_key(|s| (s.layer, s.texture));
+    let mut vertices = Vec::with_capacity(sprites.len() * 4);
+    for (i, sprite) in sprites.iter().enumerate() {
+        vertices.extend_from_slice(&sprite.vertices());
+        let start = i as u32 * 6;
+        match batches.last_mut() {
+            Some(batch) if batch.texture == sprite.texture => batch.indices.end = start + 6,
+            _ => batches.push(DrawBatch {
+                texture: sprite.texture,
+                indices: start..start + 6,
+            }),
+        }
+    }
+    vertices
+}
+
+pub struct SpriteBatch {
+    pipeline: wgpu::RenderPipeline,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    textures: Vec<wgpu::BindGroup>,
+    camera_buffer: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+    vertex_buffer: DynamicBuffer,
+    index_buffer: DynamicBuffer,
+    // sprites covered by the index buffer
+    capacity: usize,
+    sprites: Vec<Sprite>,
+    batches: Vec<DrawBatch>,
+}
+
+impl SpriteBatch {
+    const INITIAL_CAPACITY: usize = 256;
+
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let texture_bind_group_layout = texture::Texture::create_bind_group_layout(device);
+        let camera_bind_group_layout = device.create_bind_group_layout(
+            &wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+                label: Some("sprite_camera_bind_group_layout"),
+            }
+        );
+        let camera_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Sprite Camera Buffer"),
+                contents: bytemuck::cast_slice(&Self::ortho(width, height)),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &camera_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+            label: Some("sprite_camera_bind_group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Sprite Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("sprite.wgsl").into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Sprite Pipeline Layout"),
+            bind_group_layouts: &[&texture_bind_group_layout, &camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Sprite Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[SpriteVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            // sprites can be mirrored with a negative scale, so no culling
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+            multiview: None,
+        });
+
+        let capacity = Self::INITIAL_CAPACITY;
+        let vertex_buffer = DynamicBuffer::new(
+            device,
+            "Sprite Vertex Buffer",
+            wgpu::BufferUsages::VERTEX,
+            (capacity * 4 * std::mem::size_of::<SpriteVertex>()) as wgpu::BufferAddress,
+        );
+        let index_buffer = DynamicBuffer::with_contents(
+            device,
+            "Sprite Index Buffer",
+            wgpu::BufferUsages::INDEX,
+            bytemuck::cast_slice(&Self::quad_indices(capacity)),
+        );
+        Self {
+            pipeline,
+            texture_bind_group_layout,
+            textures: Vec::new(),
+            camera_buffer,
+            camera_bind_group,
+            vertex_buffer,
+            index_buffer,
+            capacity,
+            sprites: Vec::new(),
+            batches: Vec::new(),
+        }
+    }
+
+    pub fn add_texture(&mut self, device: &wgpu::Device, texture: &texture::Texture) -> SpriteTextureId {
+        self.textures.push(texture.create_bind_group(device, &self.texture_bind_group_layout));
+        SpriteTextureId(self.textures.len() - 1)
+    }
+
+    pub fn resize(&mut self, queue: &wgpu::Queue, width: u32, height: u32) {
+        queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&Self::ortho(width, height)));
+    }
+
+    pub fn submit(&mut self, sprite: Sprite) {
+        self.sprites.push(sprite);
+    }
+
+    // number of draw calls recorded by the last `prepare`
+    pub fn draw_calls(&self) -> usize {
+        self.batches.len()
+    }
+
+    // sort the submitted sprites, upload them and build the draw list,
+    // clearing the queue for the next frame
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.batches.clear();
+        if self.sprites.is_empty() {
+            return;
+        }
+        let vertices = batch_sprites(&mut self.sprites, &mut self.batches);
+        // the quad indices never change, so they are only rewritten on growth
+        if self.sprites.len() > self.capacity {
+            self.capacity = self.sprites.len().next_power_of_two();
+            self.index_buffer.write_slice(device, queue, &Self::quad_indices(self.capacity));
+        }
+        self.vertex_buffer.write_slice(device, queue, &vertices);
+        self.sprites.clear();
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if self.batches.is_empty() {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice());
+        render_pass.set_index_buffer(self.index_buffer.slice(), wgpu::IndexFormat::Uint32);
+        for batch in self.batches.iter() {
+            render_pass.set_bind_group(0, &self.textures[batch.texture.0], &[]);
+            render_pass.draw_indexed(batch.indices.clone(), 0, 0..1);
+        }
+    }
+
+    fn quad_indices(capacity: usize) -> Vec<u32> {
+        (0..capacity as u32)
+            .flat_map(|i| {
+                let base = i * 4;
+                [base, base + 1, base + 2, base, base + 2, base + 3]
+            })
+            .collect()
+    }
+
+    // pixel space with the origin at the top left
+    fn ortho(width: u32, height: u32) -> [[f32; 4]; 4] {
+        let w = width.max(1) as f32;
+        let h = height.max(1) as f32;
+        [
+            [2.0 / w, 0.0, 0.0, 0.0],
+            [0.0, -2.0 / h, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [-1.0, 1.0, 0.0, 1.0],
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sprite(texture: usize, layer: i32, x: f32) -> Sprite {
+        Sprite {
+            position: [x, 0.0],
+            layer,
+            ..Sprite::new(SpriteTextureId(texture), [1.0, 1.0])
+        }
+    }
+
+    #[test]
+    fn batches_follow_layers_then_textures() {
+        let mut sprites = vec![
+            sprite(1, 1, 0.0),
+            sprite(0, 0, 1.0),
+            sprite(1, 0, 2.0),
+            sprite(0, 1, 3.0),
+            sprite(0, 0, 4.0),
+            sprite(1, 1, 5.0),
+        ];
+        let mut batches = Vec::new();
+        let vertices = batch_sprites(&mut sprites, &mut batches);
+        let batch = |texture, indices| DrawBatch { texture: SpriteTextureId(texture), indices };
+        assert_eq!(batches, [batch(0, 0..12), batch(1, 12..18), batch(0, 18..24), batch(1, 24..36)]);
+        // submission order within a layer and texture
+        let xs: Vec<f32> = vertices.chunks(4).map(|quad| quad[0].position[0]).collect();
+        assert_eq!(xs, [1.0, 4.0, 2.0, 3.0, 0.0, 5.0]);
+    }
+
+    #[test]
+    fn one_texture_across_layers_is_one_batch() {
+        let mut sprites = vec![sprite(2, 0, 0.0), sprite(2, 3, 1.0), sprite(2, -1, 2.0)];
+        let mut batches = Vec::new();
+        batch_sprites(&mut sprites, &mut batches);
+        // layers only order the sprites, a texture run spans them
+        assert_eq!(batches, [DrawBatch { texture: SpriteTextureId(2), indices: 0..18 }]);
+    }
+}
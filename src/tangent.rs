@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector3};
+
+// the geometry with tangents for normal mapping, generated with mikktspace
+// so they match the tangent space normal maps are baked in. w holds the
+// handedness so the shader can rebuild the bitangent as cross(n, t) * w
+pub struct Tangents {
+    // one per output vertex, the input vertex it copies. the first ones are
+    // the input vertices in order, vertices whose triangles disagree on the
+    // tangent (across uv seams or mirrored uvs) get copies appended
+    pub sources: Vec<u32>,
+    pub tangents: Vec<[f32; 4]>,
+    // the input triangles, over the output vertices
+    pub indices: Vec<u32>,
+}
+
+pub fn compute_tangents(
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+    tex_coords: &[[f32; 2]],
+    indices: &[u32],
+) -> Tangents {
+    let mut geometry = Geometry {
+        positions,
+        normals,
+        tex_coords,
+        indices,
+        corners: vec![None; indices.len() - indices.len() % 3],
+    };
+    // false when there are no triangles, every corner keeps its fallback
+    bevy_mikktspace::generate_tangents(&mut geometry);
+
+    let mut result = Tangents {
+        sources: (0..positions.len() as u32).collect(),
+        tangents: normals.iter().map(|&n| fallback_tangent(n)).collect(),
+        indices: Vec::with_capacity(geometry.corners.len()),
+    };
+    let mut assigned = vec![false; positions.len()];
+    let mut copies: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (corner, tangent) in geometry.corners.iter().enumerate() {
+        let vertex = indices[corner];
+        let tangent = tangent.filter(is_usable).unwrap_or_else(|| fallback_tangent(normals[vertex as usize]));
+        let index = if !assigned[vertex as usize] {
+            assigned[vertex as usize] = true;
+            result.tangents[vertex as usize] = tangent;
+            vertex
+        } else if same_tangent(&result.tangents[vertex as usize], &tangent) {
+            vertex
+        } else {
+            let copies = copies.entry(vertex).or_default();
+            match copies.iter().find(|&&c| same_tangent(&result.tangents[c as usize], &tangent)) {
+                Some(&copy) => copy,
+                None => {
+                    let copy = result.sources.len() as u32;
+                    result.sources.push(vertex);
+                    result.tangents.push(tangent);
+                    copies.push(copy);
+                    copy
+                }
+            }
+        };
+        result.indices.push(index);
+    }
+    result
+}
+
+struct Geometry<'a> {
+    positions: &'a [[f32; 3]],
+    normals: &'a [[f32; 3]],
+    tex_coords: &'a [[f32; 2]],
+    indices: &'a [u32],
+    // the generated tangent of each triangle corner
+    corners: Vec<Option<[f32; 4]>>,
+}
+
+impl Geometry<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> usize {
+        self.indices[face * 3 + vert] as usize
+    }
+}
+
+impl bevy_mikktspace::Geometry for Geometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.corners.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.positions[self.vertex(face, vert)]
+    }
+
+    // mikktspace expects unit normals
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        let normal = Vector3::from(self.normals[self.vertex(face, vert)]);
+        if normal.magnitude2() > 1e-12 { normal.normalize().into() } else { normal.into() }
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.tex_coords[self.vertex(face, vert)]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.corners[face * 3 + vert] = Some(tangent);
+    }
+}
+
+// degenerate uvs and zero normals can leave a corner without a direction
+fn is_usable(tangent: &[f32; 4]) -> bool {
+    let length2 = Vector3::new(tangent[0], tangent[1], tangent[2]).magnitude2();
+    length2.is_finite() && length2 > 1e-12
+}
+
+// corners of a shared vertex get the same tangent unless mikktspace split them
+fn same_tangent(a: &[f32; 4], b: &[f32; 4]) -> bool {
+    a[3] == b[3] && (0..3).all(|i| (a[i] - b[i]).abs() <= 1e-5)
+}
+
+// any unit vector perpendicular to the normal, or to +z for zero normals
+fn fallback_tangent(normal: [f32; 3]) -> [f32; 4] {
+    let normal = Vector3::from(normal);
+    let normal = if normal.magnitude2() > 1e-12 { normal.normalize() } else { Vector3::unit_z() };
+    let axis = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+    let tangent = (axis - normal * normal.dot(axis)).normalize();
+    [tangent.x, tangent.y, tangent.z, 1.0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a quad in the xy plane facing +z, split down its diagonal
+    const POSITIONS: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+    const NORMALS: [[f32; 3]; 4] = [[0.0, 0.0, 1.0]; 4];
+    const INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+    fn assert_unit(tangent: &[f32; 4]) {
+        let length = Vector3::new(tangent[0], tangent[1], tangent[2]).magnitude();
+        assert!((length - 1.0).abs() < 1e-4, "{:?}", tangent);
+    }
+
+    #[test]
+    fn planar_quad_follows_u() {
+        // v increasing down, as in wgpu, makes the bitangent -y
+        let tex_coords = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]];
+        let result = compute_tangents(&POSITIONS, &NORMALS, &tex_coords, &INDICES);
+        assert_eq!(result.sources, [0, 1, 2, 3]);
+        assert_eq!(result.indices, INDICES);
+        for tangent in &result.tangents {
+            assert!((tangent[0] - 1.0).abs() < 1e-4 && tangent[1].abs() < 1e-4 && tangent[2].abs() < 1e-4, "{:?}", tangent);
+            assert_eq!(tangent[3], -1.0);
+        }
+    }
+
+    #[test]
+    fn mirrored_uvs_split_shared_vertices() {
+        // the second triangle has its u flipped, so the diagonal is a seam
+        let tex_coords = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [2.0, 0.0]];
+        let result = compute_tangents(&POSITIONS, &NORMALS, &tex_coords, &INDICES);
+        assert_eq!(result.sources.len(), 6);
+        assert_eq!(&result.sources[4..], [0, 2]);
+        assert_eq!(&result.indices[..3], [0, 1, 2]);
+        assert_eq!(&result.indices[3..], [4, 5, 3]);
+        assert_eq!(result.tangents[0][3], -result.tangents[4][3]);
+        result.tangents.iter().for_each(assert_unit);
+    }
+
+    #[test]
+    fn degenerate_input_stays_finite() {
+        // every uv the same, and a zero normal
+        let tex_coords = [[0.5, 0.5]; 4];
+        let mut normals = NORMALS;
+        normals[2] = [0.0, 0.0, 0.0];
+        let result = compute_tangents(&POSITIONS, &normals, &tex_coords, &INDICES);
+        assert_eq!(result.indices.len(), 6);
+        result.tangents.iter().for_each(assert_unit);
+    }
+}
@@ -0,0 +1,212 @@
+use std::time::{Duration, Instant};
+
+// weight of the newest frame in the smoothed fps
+const FPS_SMOOTHING: f32 = 0.1;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameTime {
+    // seconds since the previous frame, or the fixed step
+    pub delta: f32,
+    // seconds since the first frame
+    pub elapsed: f64,
+    // counts from 0
+    pub frame: u64,
+    // frames per second, averaged over the last several frames
+    pub fps: f32,
+}
+
+// measures the time between frames
+#[derive(Clone, Debug)]
+pub struct Clock {
+    last: Option<Instant>,
+    started: bool,
+    time: FrameTime,
+    smoothed_delta: f32,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            last: None,
+            started: false,
+            time: FrameTime::default(),
+            smoothed_delta: 0.0,
+        }
+    }
+
+    // the time of a new frame, starting now. the first frame has no delta
+    pub fn tick(&mut self) -> FrameTime {
+        let delta = self.measure();
+        self.advance(delta)
+    }
+
+    // the wall clock time since the previous call, zero the first time.
+    // `tick` is `measure` then `advance`
+    pub fn measure(&mut self) -> Duration {
+        let now = Instant::now();
+        let delta = self.last.map_or(Duration::ZERO, |last| now - last);
+        self.last = Some(now);
+        delta
+    }
+
+    // the time of a new frame `delta` after the previous one, for time
+    // that doesn't come from the wall clock
+    pub fn advance(&mut self, delta: Duration) -> FrameTime {
+        let seconds = delta.as_secs_f32();
+        if self.started {
+            self.time.frame += 1;
+        }
+        self.started = true;
+        if self.smoothed_delta > 0.0 {
+            self.smoothed_delta += (seconds - self.smoothed_delta) * FPS_SMOOTHING;
+        } else {
+            self.smoothed_delta = seconds;
+        }
+        self.time.delta = seconds;
+        self.time.elapsed += delta.as_secs_f64();
+        self.time.fps = if self.smoothed_delta > 0.0 { 1.0 / self.smoothed_delta } else { 0.0 };
+        self.time
+    }
+
+    // the most recent frame
+    pub fn time(&self) -> FrameTime {
+        self.time
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// runs updates at a constant rate however fast frames are drawn. frames
+// render in between two updates, `alpha` of the way to the latest one
+#[derive(Clone, Debug)]
+pub struct FixedTimestep {
+    // seconds per update
+    pub step: f32,
+    // updates per frame at most, time beyond that is dropped so a slow
+    // frame can't cause ever more updates
+    pub max_steps: u32,
+    accumulator: f32,
+    time: FrameTime,
+}
+
+impl FixedTimestep {
+    pub fn new(step: f32, max_steps: u32) -> Self {
+        assert!(step > 0.0 && step.is_finite(), "fixed timestep needs a positive step, got {}", step);
+        Self {
+            step,
+            max_steps,
+            accumulator: 0.0,
+            time: FrameTime::default(),
+        }
+    }
+
+    pub fn from_rate(updates_per_second: f32, max_steps: u32) -> Self {
+        assert!(updates_per_second > 0.0 && updates_per_second.is_finite(),
+            "fixed timestep needs a positive rate, got {}", updates_per_second);
+        Self::new(1.0 / updates_per_second, max_steps)
+    }
+
+    // adds a frame's time, returning the updates it is due. each one gets
+    // its time from `next_update`
+    pub fn advance(&mut self, frame: &FrameTime) -> u32 {
+        self.accumulator += frame.delta;
+        let due = (self.accumulator / self.step).floor() as u32;
+        let steps = due.min(self.max_steps);
+        self.accumulator -= steps as f32 * self.step;
+        if due > steps {
+            // skip what couldn't be caught up on, keeping the progress into the next step
+            self.accumulator = self.accumulator.rem_euclid(self.step);
+        }
+        self.time.fps = frame.fps;
+        steps
+    }
+
+    // the time of the update about to run
+    pub fn next_update(&mut self) -> FrameTime {
+        let time = FrameTime {
+            delta: self.step,
+            ..self.time
+        };
+        self.time.frame += 1;
+        self.time.elapsed += self.step as f64;
+        time
+    }
+
+    // how far the frame is between the previous and the latest update
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.step).clamp(0.0, 1.0)
+    }
+}
+
+// how `run` drives `State::update`
+#[derive(Clone, Debug)]
+pub enum LoopMode {
+    // one update per frame with the frame's delta
+    Variable,
+    Fixed(FixedTimestep),
+}
+
+impl Default for LoopMode {
+    // 60 updates per second, catching up on at most 5 per frame
+    fn default() -> Self {
+        LoopMode::Fixed(FixedTimestep::from_rate(60.0, 5))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(delta: f32) -> FrameTime {
+        FrameTime { delta, ..FrameTime::default() }
+    }
+
+    #[test]
+    fn catches_up_over_several_steps() {
+        let mut fixed = FixedTimestep::new(0.25, 10);
+        assert_eq!(fixed.advance(&frame(0.1)), 0);
+        assert_eq!(fixed.advance(&frame(0.9)), 4);
+        let updates: Vec<FrameTime> = (0..4).map(|_| fixed.next_update()).collect();
+        assert_eq!(updates.iter().map(|u| u.frame).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        assert_eq!(updates.iter().map(|u| u.elapsed).collect::<Vec<_>>(), [0.0, 0.25, 0.5, 0.75]);
+        assert!(updates.iter().all(|u| u.delta == 0.25));
+        assert_eq!(fixed.alpha(), 0.0);
+    }
+
+    #[test]
+    fn drops_time_past_max_steps() {
+        let mut fixed = FixedTimestep::new(0.25, 2);
+        // 6.5 steps due, 2 run and the half step is kept
+        assert_eq!(fixed.advance(&frame(1.625)), 2);
+        assert_eq!(fixed.alpha(), 0.5);
+        assert_eq!(fixed.advance(&frame(0.125)), 1);
+        assert_eq!(fixed.alpha(), 0.0);
+    }
+
+    #[test]
+    fn alpha_stays_below_one() {
+        let mut fixed = FixedTimestep::from_rate(60.0, 5);
+        // frame times that don't divide the step evenly
+        for i in 0..1000 {
+            fixed.advance(&frame(0.001 + (i % 37) as f32 * 0.0013));
+            let alpha = fixed.alpha();
+            assert!((0.0..1.0).contains(&alpha), "alpha {} after frame {}", alpha, i);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "positive step")]
+    fn rejects_a_zero_step() {
+        FixedTimestep::new(0.0, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "positive rate")]
+    fn rejects_a_zero_rate() {
+        FixedTimestep::from_rate(0.0, 5);
+    }
+}
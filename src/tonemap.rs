@@ -0,0 +1,63 @@
+// curves mapping the unbounded hdr scene into displayable range,
+// the index is what post/tonemap.wgsl switches on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ToneMapOperator {
+    Reinhard = 0,
+    Aces = 1,
+    AgX = 2,
+    Uncharted2 = 3,
+}
+
+impl ToneMapOperator {
+    pub fn next(self) -> Self {
+        match self {
+            ToneMapOperator::Reinhard => ToneMapOperator::Aces,
+            ToneMapOperator::Aces => ToneMapOperator::AgX,
+            ToneMapOperator::AgX => ToneMapOperator::Uncharted2,
+            ToneMapOperator::Uncharted2 => ToneMapOperator::Reinhard,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    // linear multiplier applied before the curve
+    pub exposure: f32,
+    // the curve's white is scaled to this, 1.0 for sdr output
+    pub peak: f32,
+}
+
+impl ToneMapping {
+    // on extended range (scRGB) surfaces 1.0 is 80 nits, so this puts
+    // the curve's white at about 320 nits
+    pub const HDR_PEAK: f32 = 4.0;
+
+    pub fn new(hdr_output: bool) -> Self {
+        Self {
+            operator: ToneMapOperator::Aces,
+            exposure: 1.0,
+            peak: if hdr_output { Self::HDR_PEAK } else { 1.0 },
+        }
+    }
+
+    // the layout expected by post/tonemap.wgsl
+    pub fn params(&self) -> [f32; 4] {
+        [self.exposure, self.operator as u32 as f32, self.peak, 0.0]
+    }
+}
+
+// prefer an extended range float format when `prefer_hdr` is set and the
+// surface offers one, otherwise the first srgb format. returns the format
+// and whether it is hdr
+pub fn select_surface_format(formats: &[wgpu::TextureFormat], prefer_hdr: bool) -> (wgpu::TextureFormat, bool) {
+    let hdr = wgpu::TextureFormat::Rgba16Float;
+    if prefer_hdr && formats.contains(&hdr) {
+        return (hdr, true);
+    }
+    let format = formats.iter()
+        .copied()
+        .find(|f| f.describe().srgb)
+        .unwrap_or(formats[0]);
+    (format, false)
+}